@@ -0,0 +1,75 @@
+use std::fmt;
+
+// Why a packet could not be parsed. `DnsPacket::parse` returns this so that callers can tell a
+// packet that simply got cut short from one that is malformed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DnsError {
+    // The packet ended before everything announced by the header could be read
+    Truncated,
+    // A compression pointer points outside of the packet
+    BadPointer,
+    // A domain name is made up of labels that do not line up with their length octets
+    BadLabel,
+    // An address record whose data is not the size of an address (4 octets for A, 16 for AAAA)
+    BadRdataLength,
+    // The packet was parsed completely but this many bytes were left over
+    TrailingBytes(usize),
+    // The packet ended after fewer entries of a section than the header claims
+    CountMismatch {
+        section: Section,
+        declared: u16,
+        parsed: u16,
+    },
+    // Any other failure reported by the parser
+    Nom(String),
+}
+
+impl fmt::Display for DnsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DnsError::Truncated => write!(f, "Parsing Error: Packet is truncated"),
+            DnsError::BadPointer => write!(f, "Parsing Error: Invalid compression pointer"),
+            DnsError::BadLabel => write!(f, "Parsing Error: Invalid label in domain name"),
+            DnsError::BadRdataLength => {
+                write!(f, "Parsing Error: Record data does not match its type's length")
+            }
+            DnsError::TrailingBytes(count) => write!(
+                f,
+                "Parsing Error: Unable to parse the whole dns packet, {} bytes remaining",
+                count
+            ),
+            DnsError::CountMismatch {
+                section,
+                declared,
+                parsed,
+            } => write!(
+                f,
+                "Parsing Error: Header declares {} entries in the {} section but only {} are present",
+                declared, section, parsed
+            ),
+            DnsError::Nom(error) => write!(f, "Parsing Error: {}", error),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Section {
+    Question,
+    Answer,
+    Authority,
+    Additional,
+}
+
+impl fmt::Display for Section {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Section::Question => "question",
+            Section::Answer => "answer",
+            Section::Authority => "authority",
+            Section::Additional => "additional",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl std::error::Error for DnsError {}
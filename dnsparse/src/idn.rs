@@ -0,0 +1,49 @@
+// Internationalized domain names (RFC 5890). On the wire every label is plain ASCII, labels with
+// other characters are written in their punycode form instead (A-labels, e.g. xn--mnchen-3ya for
+// münchen).
+
+// The A-label form of `name`, as it gets written into packets
+pub fn to_ascii(name: &str) -> anyhow::Result<String> {
+    idna::domain_to_ascii(name)
+        .map_err(|error| anyhow::anyhow!("Invalid domain name {}: {}", name, error))
+}
+
+// Turns the A-labels of a name (as it comes out of the parser) back into Unicode for display.
+// Labels that are not valid punycode are left alone.
+pub fn to_unicode(name: &str) -> String {
+    let (unicode, _result) = idna::domain_to_unicode(name);
+
+    unicode
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{write_packet_vec, DnsPacket, QueryType};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn to_ascii_works() {
+        assert_eq!(to_ascii("münchen.de").unwrap(), "xn--mnchen-3ya.de");
+        assert_eq!(to_ascii("example.com").unwrap(), "example.com");
+    }
+
+    #[test]
+    fn to_unicode_works() {
+        assert_eq!(to_unicode("xn--mnchen-3ya.de"), "münchen.de");
+        assert_eq!(to_unicode("example.com"), "example.com");
+    }
+
+    #[test]
+    fn unicode_names_round_trip_through_punycode() {
+        let query = DnsPacket::query(1, "münchen.de", QueryType::A);
+
+        let bytes = write_packet_vec(&query).unwrap();
+        let parsed = DnsPacket::parse(&bytes).unwrap();
+        let qname = parsed.qname().unwrap();
+
+        assert!(bytes.is_ascii());
+        assert_eq!(qname, "xn--mnchen-3ya.de");
+        assert_eq!(to_unicode(&qname), "münchen.de");
+    }
+}
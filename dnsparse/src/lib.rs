@@ -1,9 +1,27 @@
+mod error;
+#[cfg(feature = "idna")]
+pub mod idn;
+mod name;
 mod parser;
+#[cfg(feature = "serde")]
+mod serde_impls;
 mod types;
 mod utils;
 mod writer;
 
-pub use types::{DnsHeader, DnsPacket, DnsQuestion, QueryType, ResponseCode};
+pub use error::{DnsError, Section};
+pub use name::validate_qname;
+pub use types::{
+    loc_precision_to_cm, loc_to_degrees, DelegationInfo, DnsClass, DnsHeader, DnsPacket,
+    DnsQuestion, DnsRecord, QueryFlags, QueryType, ResponseCode, EDNS_DO_FLAG,
+};
 
 pub use parser::packet as dns_packet_parser;
+pub use parser::read_tcp_message;
 pub use writer::write as write_packet;
+pub use writer::write_io as write_packet_io;
+pub use writer::write_tcp as write_packet_tcp;
+pub use writer::write_tcp_vec as write_packet_tcp_vec;
+pub use writer::write_vec as write_packet_vec;
+pub use writer::write_with_options as write_packet_with_options;
+pub use writer::WriteOptions;
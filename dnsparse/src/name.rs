@@ -0,0 +1,121 @@
+// Including the length octets and the terminating zero (RFC 1035 section 2.3.4)
+pub(crate) const MAX_NAME_LENGTH: usize = 255;
+const MAX_LABEL_LENGTH: usize = 63;
+
+// Checks that a name someone typed in (or got from somewhere else) can be asked about, so that a
+// bad one fails with a message saying what is wrong with it instead of ending up in a malformed
+// packet. A single trailing dot is fine, names are taken to be fully qualified either way, and "."
+// on its own is the root.
+//
+// Internationalized names are let through, see the idna feature for how they are written.
+pub fn validate_qname(name: &str) -> Result<(), String> {
+    if let Some(c) = name.chars().find(|c| c.is_control() || c.is_whitespace()) {
+        return Err(format!("Domain name {:?} contains {:?}", name, c));
+    }
+
+    // The root, e.g. to ask for the root servers
+    if name == "." {
+        return Ok(());
+    }
+
+    let labels = name.strip_suffix('.').unwrap_or(name);
+    if labels.is_empty() {
+        return Err("Domain name is empty".to_string());
+    }
+
+    for label in labels.split('.') {
+        if label.is_empty() {
+            return Err(format!("Domain name {:?} contains an empty label", name));
+        }
+        if label.len() > MAX_LABEL_LENGTH {
+            return Err(format!(
+                "Label {:?} exceeds {} characters",
+                label, MAX_LABEL_LENGTH
+            ));
+        }
+    }
+
+    // Every label is preceded by its length, and the root (a zero) ends the name
+    if labels.len() + 2 > MAX_NAME_LENGTH {
+        return Err(format!(
+            "Domain name {:?} exceeds {} octets",
+            name, MAX_NAME_LENGTH
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn validate_qname_accepts_valid_names() {
+        assert_eq!(validate_qname("www.example.com"), Ok(()));
+        assert_eq!(validate_qname("www.example.com."), Ok(()));
+        assert_eq!(validate_qname("_dmarc.example-1.com"), Ok(()));
+        assert_eq!(validate_qname("münchen.de"), Ok(()));
+        assert_eq!(validate_qname(&"a".repeat(63)), Ok(()));
+        assert_eq!(validate_qname("."), Ok(()));
+    }
+
+    #[test]
+    fn validate_qname_rejects_empty_names() {
+        assert_eq!(validate_qname(""), Err("Domain name is empty".to_string()));
+    }
+
+    #[test]
+    fn validate_qname_rejects_junk_around_labels() {
+        assert_eq!(
+            validate_qname(".example.com"),
+            Err("Domain name \".example.com\" contains an empty label".to_string())
+        );
+        assert_eq!(
+            validate_qname("example..com"),
+            Err("Domain name \"example..com\" contains an empty label".to_string())
+        );
+        assert_eq!(
+            validate_qname("example.com.."),
+            Err("Domain name \"example.com..\" contains an empty label".to_string())
+        );
+        assert_eq!(
+            validate_qname(" example.com"),
+            Err("Domain name \" example.com\" contains ' '".to_string())
+        );
+    }
+
+    #[test]
+    fn validate_qname_rejects_control_characters() {
+        assert_eq!(
+            validate_qname("example\u{0}.com"),
+            Err("Domain name \"example\\0.com\" contains '\\0'".to_string())
+        );
+        assert!(validate_qname("example.com\n").is_err());
+    }
+
+    #[test]
+    fn validate_qname_rejects_long_labels() {
+        let label = "a".repeat(64);
+
+        assert_eq!(
+            validate_qname(&format!("{}.com", label)),
+            Err(format!("Label {:?} exceeds 63 characters", label))
+        );
+    }
+
+    #[test]
+    fn validate_qname_rejects_long_names() {
+        // Takes up 255 octets on the wire with the length octets and the root, one more character
+        // tips it over
+        let name = format!("{}.{}", vec!["a".repeat(63); 3].join("."), "b".repeat(61));
+        let too_long = format!("{}b", name);
+
+        assert_eq!(validate_qname(&name), Ok(()));
+        assert_eq!(
+            validate_qname(&too_long),
+            Err(format!("Domain name {:?} exceeds 255 octets", too_long))
+        );
+    }
+}
@@ -1,17 +1,18 @@
 use std::{
-    cmp::min_by_key,
     convert::TryFrom,
+    io::Read,
     net::{Ipv4Addr, Ipv6Addr},
 };
 
 use crate::{
-    types::{DnsHeader, DnsPacket, DnsQuestion, DnsRecord, QueryType, ResponseCode},
+    error::{DnsError, Section},
+    types::{DnsClass, DnsHeader, DnsPacket, DnsQuestion, DnsRecord, QueryType, ResponseCode},
     utils::isperse,
 };
 use log::trace;
 use nom::{
-    bytes::{complete::take as take_bytes, complete::take_while},
-    error::ParseError,
+    bytes::complete::take as take_bytes,
+    error::{ErrorKind, ParseError},
     multi::{count, many0},
     number::complete::{be_u16, be_u32, be_u8},
     IResult,
@@ -20,6 +21,12 @@ use nom::{
 const JUMP_REQUIRED_FLAG: u8 = 0xc0;
 const NULL_BYTE: u8 = 0x00;
 
+// nom has no notion of our failure modes, so they are reported using error kinds that none of the
+// combinators we use produce. `DnsPacket::parse` maps them back.
+const BAD_POINTER: ErrorKind = ErrorKind::Verify;
+const BAD_LABEL: ErrorKind = ErrorKind::Tag;
+const BAD_RDATA_LENGTH: ErrorKind = ErrorKind::LengthValue;
+
 fn ipv4<'a, E>() -> impl FnMut(&'a [u8]) -> IResult<&'a [u8], Ipv4Addr, E>
 where
     E: ParseError<&'a [u8]>,
@@ -69,52 +76,71 @@ where
     }
 }
 
-// WARN: This does not consider infinite loop attack here. Someone can easily create an input and
-// this function will just loop infinitely. Will error out with stack overflow given the recursive
-// nature of how parser combinators work.
+// A <character-string> is a single length octet followed by that number of characters.
+fn character_string<'a, E>() -> impl FnMut(&'a [u8]) -> IResult<&'a [u8], String, E>
+where
+    E: ParseError<&'a [u8]>,
+{
+    |input| {
+        let (rest, size) = be_u8(input)?;
+        let (rest, value) = take_bytes(size as usize)(rest)?;
+
+        Ok((rest, String::from_utf8_lossy(value).to_string()))
+    }
+}
+
+// Where `input` starts within `original`, if it is a part of it at all
+fn offset_in(original: &[u8], input: &[u8]) -> Option<usize> {
+    (input.as_ptr() as usize)
+        .checked_sub(original.as_ptr() as usize)
+        .filter(|&offset| offset <= original.len())
+}
+
+// Compression pointers are followed in a loop rather than by recursing, and each one has to point
+// before the labels that led to it (RFC 1035 section 4.1.4 only allows pointing to a prior
+// occurrence of a name). Every jump therefore goes further back in the packet, so a name that
+// points to itself, directly or through other names, is rejected instead of followed forever.
 fn domain_name<'a, E>(original: &'a [u8]) -> impl FnMut(&'a [u8]) -> IResult<&'a [u8], String, E>
 where
     E: ParseError<&'a [u8]>,
 {
     move |input| {
-        // Parse upto shorter
-        // (need a parser combinator which terminates at parser which finishes first)
-        let o0xc0 = take_while(|c| c != JUMP_REQUIRED_FLAG)(input)?;
-        let o0x00 = take_while(|c| c != NULL_BYTE)(input)?;
-        let (rest, domain_name_bytes) = min_by_key(o0x00, o0xc0, |(_rest, parsed)| parsed.len());
-
-        trace!("0x00 {:x?}", o0x00);
-        trace!("0xc0 {:x?}", o0xc0);
-        trace!("seclected {:x?}", (rest, domain_name_bytes));
-        // trace!();
-
-        let (_, mut fragments) = many0(domain_fragment())(domain_name_bytes)?;
-        trace!("fragments: {:x?}", fragments);
-
-        let (rest, next) = be_u8(rest)?;
-
-        if next == JUMP_REQUIRED_FLAG {
-            trace!("JUMPING");
-            let (rest, jump_location) = be_u8(rest)?;
-
-            let new_input = &original[(jump_location as usize)..];
-            let (_ignore_rest, recursive_domain_str) = domain_name(original)(new_input)?;
-            fragments.push(recursive_domain_str);
-
-            let domain = isperse(fragments);
-            trace!("Result (after jump): {}, Remaining: {:x?}", domain, rest);
-
-            Ok((rest, domain))
-        } else if next == NULL_BYTE {
-            let domain = isperse(fragments);
-            trace!("Result (no-jump): {}", domain);
-
-            Ok((rest, domain))
-        } else {
-            panic!(
-                "Impossible state reached, expecting {} or {}",
-                NULL_BYTE, JUMP_REQUIRED_FLAG
-            )
+        let mut fragments = vec![];
+        let mut rest = input;
+        // Where the labels being read start, and where the name ends once we have jumped
+        let mut start = offset_in(original, input).unwrap_or(original.len());
+        let mut end = None;
+        loop {
+            let (after_length, length) = be_u8(rest)?;
+
+            if length & JUMP_REQUIRED_FLAG == JUMP_REQUIRED_FLAG {
+                // The pointer is the remaining 6 bits of this octet followed by the next octet,
+                // so it can reach anywhere in the first 16K of the packet
+                let (after_pointer, low) = be_u8(after_length)?;
+                let jump_location = ((length & !JUMP_REQUIRED_FLAG) as usize) << 8 | low as usize;
+
+                if jump_location >= start {
+                    return Err(nom::Err::Error(E::from_error_kind(rest, BAD_POINTER)));
+                }
+                trace!("Jumping to {}", jump_location);
+                end.get_or_insert(after_pointer);
+                start = jump_location;
+                rest = &original[jump_location..];
+                continue;
+            } else if length == NULL_BYTE {
+                let domain = isperse(fragments);
+                let rest = end.unwrap_or(after_length);
+                trace!("Result: {}, Remaining: {:x?}", domain, rest);
+
+                return Ok((rest, domain));
+            } else if length & JUMP_REQUIRED_FLAG != 0 {
+                // 0x40 and 0x80 are reserved label types
+                return Err(nom::Err::Error(E::from_error_kind(rest, BAD_LABEL)));
+            }
+
+            let (after_label, fragment) = domain_fragment()(rest)?;
+            fragments.push(fragment);
+            rest = after_label;
         }
     }
 }
@@ -136,7 +162,7 @@ where
         let authoritative_answer = (a & (1 << 2)) > 0;
         let opcode = (a >> 3) & 0x0F;
         let response = (a & (1 << 7)) > 0;
-        let rescode = ResponseCode::from_num(b & 0x0F);
+        let rescode = ResponseCode::from_num((b & 0x0F).into());
         let checking_disabled = (b & (1 << 4)) > 0;
         let authed_data = (b & (1 << 5)) > 0;
         let z = (b & (1 << 6)) > 0;
@@ -179,17 +205,33 @@ where
     move |input| {
         let (rest, domain) = domain_name(original)(input)?;
         let (rest, qtype) = be_u16(rest)?;
-        let (rest, _qclass) = be_u16(rest)?;
+        let (rest, qclass) = be_u16(rest)?;
 
-        let question = DnsQuestion {
-            name: domain,
-            qtype: QueryType::from_num(qtype),
-        };
+        let question = DnsQuestion::builder()
+            .name(domain)
+            .qtype(QueryType::from_num(qtype))
+            .class(DnsClass::from_num(qclass))
+            .build();
 
         Ok((rest, question))
     }
 }
 
+// Addresses take up exactly as many bytes as they should, anything else is not an address
+fn expect_length<'a, E>(record_bytes: &'a [u8], length: usize) -> Result<(), nom::Err<E>>
+where
+    E: ParseError<&'a [u8]>,
+{
+    if record_bytes.len() != length {
+        return Err(nom::Err::Error(E::from_error_kind(
+            record_bytes,
+            BAD_RDATA_LENGTH,
+        )));
+    }
+
+    Ok(())
+}
+
 fn answer<'a, E>(original: &'a [u8]) -> impl FnMut(&'a [u8]) -> IResult<&'a [u8], DnsRecord, E>
 where
     E: ParseError<&'a [u8]>,
@@ -197,7 +239,7 @@ where
     move |input| {
         let (rest, domain) = domain_name(original)(input)?;
         let (rest, qnum) = be_u16(rest)?;
-        let (rest, _qclass) = be_u16(rest)?;
+        let (rest, qclass) = be_u16(rest)?;
         let (rest, ttl) = be_u32(rest)?;
         let (rest, data_len) = be_u16(rest)?;
 
@@ -205,13 +247,21 @@ where
         let (rest, record_bytes) = take_bytes(data_len as usize)(rest)?;
 
         let record = match qtype {
-            QueryType::UNKNOWN(_) => DnsRecord::UNKNOWN {
+            // ANY is only valid in questions, a record claiming it is kept as is. So are DNSSEC
+            // records, which we pass on without validating them.
+            QueryType::UNKNOWN(_)
+            | QueryType::ANY
+            | QueryType::DS
+            | QueryType::RRSIG
+            | QueryType::NSEC
+            | QueryType::NSEC3 => DnsRecord::UNKNOWN {
                 domain,
                 qtype: qnum,
-                data_len,
+                data: record_bytes.to_vec(),
                 ttl,
             },
             QueryType::A => {
+                expect_length(record_bytes, 4)?;
                 let (_rest, addr) = ipv4()(record_bytes)?;
                 DnsRecord::A { domain, addr, ttl }
             }
@@ -234,17 +284,166 @@ where
                 }
             }
             QueryType::AAAA => {
+                expect_length(record_bytes, 16)?;
                 let (_rest, addr) = ipv6()(record_bytes)?;
                 DnsRecord::AAAA { domain, addr, ttl }
             }
+            QueryType::SRV => {
+                let (rest, priority) = be_u16(record_bytes)?;
+                let (rest, weight) = be_u16(rest)?;
+                let (rest, port) = be_u16(rest)?;
+                let (_rest, target) = domain_name(original)(rest)?;
+                DnsRecord::SRV {
+                    domain,
+                    priority,
+                    weight,
+                    port,
+                    target,
+                    ttl,
+                }
+            }
+            QueryType::PTR => {
+                let (_rest, host) = domain_name(original)(record_bytes)?;
+                DnsRecord::PTR { domain, host, ttl }
+            }
+            QueryType::CAA => {
+                let (rest, flags) = be_u8(record_bytes)?;
+                let (rest, tag) = character_string()(rest)?;
+                let value = String::from_utf8_lossy(rest).to_string();
+                DnsRecord::CAA {
+                    domain,
+                    flags,
+                    tag,
+                    value,
+                    ttl,
+                }
+            }
+            QueryType::TXT => {
+                let (_rest, data) = many0(character_string())(record_bytes)?;
+                DnsRecord::TXT { domain, data, ttl }
+            }
+            QueryType::DNAME => {
+                let (_rest, target) = domain_name(original)(record_bytes)?;
+                DnsRecord::DNAME {
+                    domain,
+                    target,
+                    ttl,
+                }
+            }
+            QueryType::HINFO => {
+                let (rest, cpu) = character_string()(record_bytes)?;
+                let (_rest, os) = character_string()(rest)?;
+                DnsRecord::HINFO {
+                    domain,
+                    cpu,
+                    os,
+                    ttl,
+                }
+            }
+            QueryType::SOA => {
+                let (rest, m_name) = domain_name(original)(record_bytes)?;
+                let (rest, r_name) = domain_name(original)(rest)?;
+                let (rest, serial) = be_u32(rest)?;
+                let (rest, refresh) = be_u32(rest)?;
+                let (rest, retry) = be_u32(rest)?;
+                let (rest, expire) = be_u32(rest)?;
+                let (_rest, minimum) = be_u32(rest)?;
+                DnsRecord::SOA {
+                    domain,
+                    m_name,
+                    r_name,
+                    serial,
+                    refresh,
+                    retry,
+                    expire,
+                    minimum,
+                    ttl,
+                }
+            }
+            QueryType::LOC => {
+                let (rest, version) = be_u8(record_bytes)?;
+                let (rest, size) = be_u8(rest)?;
+                let (rest, horiz_pre) = be_u8(rest)?;
+                let (rest, vert_pre) = be_u8(rest)?;
+                let (rest, latitude) = be_u32(rest)?;
+                let (rest, longitude) = be_u32(rest)?;
+                let (_rest, altitude) = be_u32(rest)?;
+                DnsRecord::LOC {
+                    domain,
+                    version,
+                    size,
+                    horiz_pre,
+                    vert_pre,
+                    latitude,
+                    longitude,
+                    altitude,
+                    ttl,
+                }
+            }
+            QueryType::NAPTR => {
+                let (rest, order) = be_u16(record_bytes)?;
+                let (rest, preference) = be_u16(rest)?;
+                let (rest, flags) = character_string()(rest)?;
+                let (rest, services) = character_string()(rest)?;
+                let (rest, regexp) = character_string()(rest)?;
+                let (_rest, replacement) = domain_name(original)(rest)?;
+                DnsRecord::NAPTR {
+                    domain,
+                    order,
+                    preference,
+                    flags,
+                    services,
+                    regexp,
+                    replacement,
+                    ttl,
+                }
+            }
+            QueryType::DNSKEY => {
+                let (rest, flags) = be_u16(record_bytes)?;
+                let (rest, protocol) = be_u8(rest)?;
+                let (rest, algorithm) = be_u8(rest)?;
+                DnsRecord::DNSKEY {
+                    domain,
+                    flags,
+                    protocol,
+                    algorithm,
+                    public_key: rest.to_vec(),
+                    ttl,
+                }
+            }
+            QueryType::OPT => DnsRecord::OPT {
+                udp_payload_size: qclass,
+                ext_rcode: (ttl >> 24) as u8,
+                version: ((ttl >> 16) & 0xFF) as u8,
+                flags: (ttl & 0xFFFF) as u16,
+                data: record_bytes.to_vec(),
+            },
         };
 
         Ok((rest, record))
     }
 }
 
+// The smallest possible question is the root name (a single zero byte) followed by type and class
+const MIN_QUESTION_SIZE: usize = 1 + 2 + 2;
+// The smallest possible record is the root name followed by type, class, ttl and an empty rdata
+const MIN_RECORD_SIZE: usize = 1 + 2 + 2 + 4 + 2;
+
 pub fn packet<'a>(input: &'a [u8], original: &'a [u8]) -> IResult<&'a [u8], DnsPacket> {
     let (rest, header) = header()(input)?;
+
+    // Don't trust the counts in the header blindly, a tiny packet claiming to carry thousands of
+    // records would otherwise have us allocate for all of them before finding out it is lying.
+    let records = header.answers as usize
+        + header.authoritative_entries as usize
+        + header.resource_entries as usize;
+    let min_size = header.questions as usize * MIN_QUESTION_SIZE + records * MIN_RECORD_SIZE;
+    if min_size > rest.len() {
+        return Err(nom::Err::Error(nom::error::make_error(
+            rest,
+            ErrorKind::Eof,
+        )));
+    }
     let (rest, questions) = count(question(original), header.questions as usize)(rest)?;
     let (rest, answers) = count(answer(original), header.answers as usize)(rest)?;
     let (rest, authorities) = count(answer(original), header.authoritative_entries as usize)(rest)?;
@@ -261,32 +460,118 @@ pub fn packet<'a>(input: &'a [u8], original: &'a [u8]) -> IResult<&'a [u8], DnsP
     Ok((rest, dns_packet))
 }
 
+impl DnsPacket {
+    // Unlike `packet`, the sections are parsed one entry at a time, so that a header claiming more
+    // entries than the packet holds can be told apart from an entry that got cut short
+    pub fn parse(value: &[u8]) -> Result<DnsPacket, DnsError> {
+        let (mut rest, header) = header()(value).map_err(dns_error)?;
+
+        let questions = section(
+            &mut rest,
+            Section::Question,
+            header.questions,
+            question(value),
+        )?;
+        let answers = section(&mut rest, Section::Answer, header.answers, answer(value))?;
+        let authorities = section(
+            &mut rest,
+            Section::Authority,
+            header.authoritative_entries,
+            answer(value),
+        )?;
+        let resources = section(
+            &mut rest,
+            Section::Additional,
+            header.resource_entries,
+            answer(value),
+        )?;
+
+        if !rest.is_empty() {
+            return Err(DnsError::TrailingBytes(rest.len()));
+        }
+
+        Ok(DnsPacket {
+            header,
+            questions,
+            answers,
+            authorities,
+            resources,
+        })
+    }
+}
+
+fn section<'a, T, F>(
+    input: &mut &'a [u8],
+    section: Section,
+    declared: u16,
+    mut entry: F,
+) -> Result<Vec<T>, DnsError>
+where
+    F: FnMut(&'a [u8]) -> IResult<&'a [u8], T>,
+{
+    let mut entries = vec![];
+    while entries.len() < declared as usize {
+        if input.is_empty() {
+            return Err(DnsError::CountMismatch {
+                section,
+                declared,
+                parsed: entries.len() as u16,
+            });
+        }
+
+        let (rest, parsed) = entry(input).map_err(dns_error)?;
+        entries.push(parsed);
+        *input = rest;
+    }
+
+    Ok(entries)
+}
+
+fn dns_error(error: nom::Err<nom::error::Error<&[u8]>>) -> DnsError {
+    match error {
+        nom::Err::Incomplete(_) => DnsError::Truncated,
+        nom::Err::Error(e) | nom::Err::Failure(e) => match e.code {
+            ErrorKind::Eof => DnsError::Truncated,
+            BAD_POINTER => DnsError::BadPointer,
+            BAD_LABEL => DnsError::BadLabel,
+            BAD_RDATA_LENGTH => DnsError::BadRdataLength,
+            code => DnsError::Nom(format!("{:?}", code)),
+        },
+    }
+}
+
 impl<'a> TryFrom<&'a [u8]> for DnsPacket {
     type Error = String;
 
     fn try_from(value: &'a [u8]) -> Result<Self, Self::Error> {
-        match packet(&value, &value) {
-            Ok(([], cl)) => Ok(cl),
-            Ok((s, _)) => Err(format!(
-                "Parsing Error: Unable to parse the whole dns packet\nRemaining Tokens: {:?}",
-                s
-            )),
-            Err(e) => Err(format!("Parsing Error: {:?}", e)),
-        }
+        DnsPacket::parse(value).map_err(|e| e.to_string())
     }
 }
 
+// Reads a message the way it is sent over TCP, preceded by its length in two octets (RFC 1035
+// section 4.2.2), the counterpart of `write_tcp`. Nothing past the message is read.
+pub fn read_tcp_message<R: Read>(reader: &mut R) -> anyhow::Result<DnsPacket> {
+    let mut length = [0u8; 2];
+    reader.read_exact(&mut length)?;
+
+    let mut buf = vec![0u8; u16::from_be_bytes(length) as usize];
+    reader.read_exact(&mut buf)?;
+
+    Ok(DnsPacket::parse(&buf)?)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
     use pretty_assertions::assert_eq;
+    use std::net::IpAddr;
 
     fn header(input: &[u8]) -> IResult<&[u8], DnsHeader> {
         super::header()(input)
     }
 
     fn question(input: &[u8]) -> IResult<&[u8], DnsQuestion> {
-        super::question(&(*input))(input)
+        super::question(input)(input)
     }
 
     fn answer<'a>(input: &'a [u8], original: &'a [u8]) -> IResult<&'a [u8], DnsRecord> {
@@ -301,6 +586,98 @@ mod test {
         super::domain_name(input)(input)
     }
 
+    // How many pointers reading a single name may follow, far more than any real packet needs
+    const MAX_JUMPS: usize = 64;
+
+    // Reads the name at `offset` without going through the parser, returning it along with where
+    // whatever comes after it starts
+    fn walk_name(bytes: &[u8], mut offset: usize) -> (String, usize) {
+        let start = offset;
+        let (mut labels, mut jumps, mut end, mut length) = (vec![], 0, None, 1);
+        loop {
+            let octet = bytes[offset];
+            if octet & 0xc0 == 0xc0 {
+                let target = ((octet & 0x3f) as usize) << 8 | bytes[offset + 1] as usize;
+                jumps += 1;
+                assert!(
+                    target < bytes.len(),
+                    "pointer at {} leaves the packet",
+                    offset
+                );
+                assert!(jumps <= MAX_JUMPS, "name at {} never ends", start);
+                end.get_or_insert(offset + 2);
+                offset = target;
+            } else if octet == 0 {
+                let name = labels.join(".");
+                return (name, end.unwrap_or(offset + 1));
+            } else {
+                let label = &bytes[offset + 1..offset + 1 + octet as usize];
+                assert!(octet <= 63, "label at {} is {} octets long", offset, octet);
+                length += 1 + label.len();
+                assert!(length <= 255, "name at {} is over 255 octets long", start);
+                labels.push(String::from_utf8_lossy(label).to_string());
+                offset += 1 + label.len();
+            }
+        }
+    }
+
+    // Walks every name in the packet (those in the data of records included) pointer by pointer,
+    // without going through the parser, and checks the parser reads each of them the same. The
+    // walk comes first so that a name that never ends is caught here rather than by the parser.
+    fn assert_no_compression_errors(bytes: &[u8]) {
+        let number = |at: usize| u16::from_be_bytes([bytes[at], bytes[at + 1]]);
+
+        let mut walked = vec![];
+        let mut offset = 12;
+        for _ in 0..number(4) {
+            let (name, next) = walk_name(bytes, offset);
+            walked.push(name);
+            offset = next + 4;
+        }
+        for _ in 0..number(6) + number(8) + number(10) {
+            let (name, next) = walk_name(bytes, offset);
+            walked.push(name);
+            let data = next + 10;
+
+            let name_offsets = match QueryType::from_num(number(next)) {
+                QueryType::NS | QueryType::CNAME | QueryType::PTR | QueryType::DNAME => vec![data],
+                QueryType::MX => vec![data + 2],
+                QueryType::SRV => vec![data + 6],
+                QueryType::SOA => vec![data, walk_name(bytes, data).1],
+                _ => vec![],
+            };
+            for name_offset in name_offsets {
+                walked.push(walk_name(bytes, name_offset).0);
+            }
+
+            offset = data + number(next + 8) as usize;
+        }
+        assert_eq!(offset, bytes.len());
+
+        let packet = DnsPacket::parse(bytes).unwrap();
+        let mut parsed: Vec<&str> = packet.questions.iter().map(|q| q.name.as_str()).collect();
+        let records = packet
+            .answers
+            .iter()
+            .chain(&packet.authorities)
+            .chain(&packet.resources);
+        for record in records {
+            parsed.push(record.domain());
+            match record {
+                DnsRecord::NS { host, .. }
+                | DnsRecord::CNAME { host, .. }
+                | DnsRecord::PTR { host, .. }
+                | DnsRecord::MX { host, .. } => parsed.push(host),
+                DnsRecord::DNAME { target, .. } | DnsRecord::SRV { target, .. } => {
+                    parsed.push(target)
+                }
+                DnsRecord::SOA { m_name, r_name, .. } => parsed.extend(&[m_name.as_str(), r_name]),
+                _ => {}
+            }
+        }
+        assert_eq!(walked, parsed);
+    }
+
     #[rustfmt::skip]
     fn google_query() -> [u8; 28] {
         [
@@ -406,10 +783,10 @@ mod test {
 
     #[test]
     fn dns_question_parsing_works() {
-        let expected_question = DnsQuestion {
-            name: "google.com".to_string(),
-            qtype: QueryType::A,
-        };
+        let expected_question = DnsQuestion::builder()
+            .name("google.com".to_string())
+            .qtype(QueryType::A)
+            .build();
         let (_, result) = question(&google_query()[12..]).unwrap();
 
         assert_eq!(result, expected_question);
@@ -431,10 +808,10 @@ mod test {
     #[test]
     fn dns_packet_parsing_works_on_answer() {
         let header = google_header_answer();
-        let expected_question = DnsQuestion {
-            name: "google.com".to_string(),
-            qtype: QueryType::A,
-        };
+        let expected_question = DnsQuestion::builder()
+            .name("google.com".to_string())
+            .qtype(QueryType::A)
+            .build();
         let record = DnsRecord::A {
             domain: "google.com".to_string(),
             addr: Ipv4Addr::new(216, 58, 211, 142),
@@ -457,10 +834,10 @@ mod test {
     #[test]
     fn dns_packet_parsing_works_on_question() {
         let header = google_header_question();
-        let expected_question = DnsQuestion {
-            name: "google.com".to_string(),
-            qtype: QueryType::A,
-        };
+        let expected_question = DnsQuestion::builder()
+            .name("google.com".to_string())
+            .qtype(QueryType::A)
+            .build();
 
         let dns_packet = DnsPacket {
             header,
@@ -523,10 +900,10 @@ mod test {
             authoritative_entries: 0,
             resource_entries: 0,
         };
-        let question = DnsQuestion {
-            name: "www.yahoo.com".to_string(),
-            qtype: QueryType::A,
-        };
+        let question = DnsQuestion::builder()
+            .name("www.yahoo.com".to_string())
+            .qtype(QueryType::A)
+            .build();
         let records = vec![
             DnsRecord::CNAME {
                 domain: "www.yahoo.com".to_string(),
@@ -557,10 +934,20 @@ mod test {
     }
 
     #[test]
-    fn dns_authorities_parsing_works() {
-        env_logger::init();
+    fn records_of_type_works() {
+        let yahoo = DnsPacket::parse(&yahoo_packet()).unwrap();
+        let google = DnsPacket::parse(&google_answer()).unwrap();
+
+        assert_eq!(yahoo.all_records().count(), 3);
+        assert_eq!(yahoo.records_of_type(QueryType::A).count(), 2);
+        assert_eq!(yahoo.records_of_type(QueryType::CNAME).count(), 1);
+        assert_eq!(google.all_records().count(), 1);
+        assert_eq!(google.records_of_type(QueryType::A).count(), 1);
+        assert_eq!(google.records_of_type(QueryType::NS).count(), 0);
+    }
 
-        let pack_buf = [
+    fn gtld_referral_packet() -> [u8; 488] {
+        [
             0x4, 0xd1, 0x81, 0x0, 0x0, 0x1, 0x0, 0x0, 0x0, 0xd, 0x0, 0xe, // header
             0x6, 0x67, 0x6f, 0x6f, 0x67, 0x6c, 0x65, 0x3, 0x63, 0x6f, 0x6d, 0x0, 0x0, 0x1, 0x0,
             0x1, // question
@@ -596,7 +983,14 @@ mod test {
             0xc0, 0x37, 0x53, 0x1e, 0xc0, 0x28, 0x0, 0x1c, 0x0, 0x1, 0x0, 0x2, 0xa3, 0x0, 0x0,
             0x10, 0x20, 0x1, 0x5, 0x3, 0xa8, 0x3e, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x2, 0x0,
             0x30,
-        ];
+        ]
+    }
+
+    #[test]
+    fn dns_authorities_parsing_works() {
+        env_logger::init();
+
+        let pack_buf = gtld_referral_packet();
 
         let header = DnsHeader {
             id: 1233,
@@ -615,10 +1009,10 @@ mod test {
             authoritative_entries: 13,
             resource_entries: 14,
         };
-        let question = DnsQuestion {
-            name: "google.com".to_string(),
-            qtype: QueryType::A,
-        };
+        let question = DnsQuestion::builder()
+            .name("google.com".to_string())
+            .qtype(QueryType::A)
+            .build();
         let authoritative_records = vec![
             DnsRecord::NS {
                 domain: "com".to_string(),
@@ -771,4 +1165,890 @@ mod test {
 
         assert_eq!(pack, result);
     }
+
+    #[test]
+    fn nameservers_works() {
+        let pack = DnsPacket::parse(&gtld_referral_packet()).unwrap();
+
+        let nameservers = pack.nameservers().collect::<Vec<_>>();
+
+        assert_eq!(nameservers.len(), 13);
+        assert_eq!(nameservers[0], ("com", "a.gtld-servers.net"));
+        assert_eq!(nameservers[12], ("com", "m.gtld-servers.net"));
+        assert_eq!(pack.delegations_for("www.GOOGLE.com").count(), 13);
+        assert_eq!(pack.delegations_for("example.org").count(), 0);
+    }
+
+    #[test]
+    fn delegation_info_works() {
+        let pack = DnsPacket::parse(&gtld_referral_packet()).unwrap();
+
+        let info = pack.delegation_info("google.com").unwrap();
+
+        assert_eq!(info.zone, "com");
+        assert_eq!(info.nameservers.len(), 13);
+        assert_eq!(info.nameservers[12], "m.gtld-servers.net");
+        // Every one of them came with an IPv4 address, a.gtld-servers.net with an IPv6 one as well
+        assert_eq!(info.glued.len(), 14);
+        assert_eq!(
+            info.glued[..2],
+            [
+                (
+                    "a.gtld-servers.net".to_string(),
+                    IpAddr::from(Ipv4Addr::new(192, 5, 6, 30))
+                ),
+                (
+                    "a.gtld-servers.net".to_string(),
+                    IpAddr::from(Ipv6Addr::new(0x2001, 0x503, 0xa83e, 0, 0, 0, 0x2, 0x30))
+                ),
+            ]
+        );
+        assert_eq!(pack.delegation_info("example.org"), None);
+    }
+
+    #[test]
+    fn round_trip_keeps_record_order() {
+        let parsed = DnsPacket::parse(&gtld_referral_packet()).unwrap();
+
+        let written = crate::writer::write_vec(&parsed).unwrap();
+        let reparsed = DnsPacket::parse(&written).unwrap();
+
+        assert_eq!(reparsed.header, parsed.header);
+        assert_eq!(reparsed.questions, parsed.questions);
+        assert_eq!(reparsed.answers, parsed.answers);
+        assert_eq!(reparsed.authorities, parsed.authorities);
+        assert_eq!(reparsed.resources, parsed.resources);
+    }
+
+    #[rustfmt::skip]
+    fn sip_srv_packet() -> [u8; 69] {
+        [
+            0x5c, 0x1a, 0x81, 0x80, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, // header
+            0x04, 0x5f, 0x73, 0x69, 0x70, // _sip
+            0x04, 0x5f, 0x74, 0x63, 0x70, // _tcp
+            0x07, 0x65, 0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65, 0x03, 0x63, 0x6f, 0x6d, 0x00, // example.com
+            0x00, 0x21, 0x00, 0x01, // query type and query class
+            // answer
+            0xc0, 0x0c, // jump location
+            0x00, 0x21, 0x00, 0x01, // query type and query class
+            0x00, 0x01, 0x51, 0x80, // ttl
+            0x00, 0x12, // len
+            0x00, 0x0a, // priority
+            0x00, 0x3c, // weight
+            0x13, 0xc4, // port
+            // sipserver (notice the jump at the end to example.com location)
+            0x09, 0x73, 0x69, 0x70, 0x73, 0x65, 0x72, 0x76, 0x65, 0x72, 0xc0, 0x16,
+        ]
+    }
+
+    #[test]
+    fn srv_record_round_trip_works() {
+        let record = DnsRecord::SRV {
+            domain: "_sip._tcp.example.com".to_string(),
+            priority: 10,
+            weight: 60,
+            port: 5060,
+            target: "sipserver.example.com".to_string(),
+            ttl: 86400,
+        };
+
+        let parsed = DnsPacket::try_from(&sip_srv_packet()[..]).unwrap();
+        assert_eq!(parsed.answers, vec![record]);
+
+        let mut buf = vec![0u8; 512];
+        let size = crate::writer::write(&mut buf, &parsed).unwrap();
+        let reparsed = DnsPacket::try_from(&buf[..size]).unwrap();
+
+        assert_eq!(reparsed, parsed);
+    }
+
+    #[rustfmt::skip]
+    fn reverse_google_packet() -> [u8; 62] {
+        [
+            0x2f, 0x91, 0x81, 0x80, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, // header
+            0x01, 0x38, 0x01, 0x38, 0x01, 0x38, 0x01, 0x38, // 8.8.8.8
+            0x07, 0x69, 0x6e, 0x2d, 0x61, 0x64, 0x64, 0x72, 0x04, 0x61, 0x72, 0x70, 0x61, 0x00, // in-addr.arpa
+            0x00, 0x0c, 0x00, 0x01, // query type and query class
+            // answer
+            0xc0, 0x0c, // jump location
+            0x00, 0x0c, 0x00, 0x01, // query type and query class
+            0x00, 0x00, 0x54, 0x60, // ttl
+            0x00, 0x0c, // len
+            0x03, 0x64, 0x6e, 0x73, 0x06, 0x67, 0x6f, 0x6f, 0x67, 0x6c, 0x65, 0x00, // dns.google
+        ]
+    }
+
+    #[test]
+    fn ptr_record_round_trip_works() {
+        let record = DnsRecord::PTR {
+            domain: "8.8.8.8.in-addr.arpa".to_string(),
+            host: "dns.google".to_string(),
+            ttl: 21600,
+        };
+
+        let parsed = DnsPacket::try_from(&reverse_google_packet()[..]).unwrap();
+        assert_eq!(parsed.answers, vec![record]);
+
+        let mut buf = vec![0u8; 512];
+        let size = crate::writer::write(&mut buf, &parsed).unwrap();
+        let reparsed = DnsPacket::try_from(&buf[..size]).unwrap();
+
+        assert_eq!(reparsed, parsed);
+    }
+
+    #[rustfmt::skip]
+    fn letsencrypt_caa_packet() -> [u8; 63] {
+        [
+            0x1d, 0x6e, 0x81, 0x80, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, // header
+            0x07, 0x65, 0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65, 0x03, 0x63, 0x6f, 0x6d, 0x00, // example.com
+            0x01, 0x01, 0x00, 0x01, // query type and query class
+            // answer
+            0xc0, 0x0c, // jump location
+            0x01, 0x01, 0x00, 0x01, // query type and query class
+            0x00, 0x00, 0x0e, 0x10, // ttl
+            0x00, 0x16, // len
+            0x00, // flags
+            0x05, 0x69, 0x73, 0x73, 0x75, 0x65, // issue
+            // letsencrypt.org
+            0x6c, 0x65, 0x74, 0x73, 0x65, 0x6e, 0x63, 0x72, 0x79, 0x70, 0x74, 0x2e, 0x6f, 0x72, 0x67,
+        ]
+    }
+
+    #[test]
+    fn caa_record_round_trip_works() {
+        let record = DnsRecord::CAA {
+            domain: "example.com".to_string(),
+            flags: 0,
+            tag: "issue".to_string(),
+            value: "letsencrypt.org".to_string(),
+            ttl: 3600,
+        };
+
+        let parsed = DnsPacket::try_from(&letsencrypt_caa_packet()[..]).unwrap();
+        assert_eq!(parsed.answers, vec![record]);
+
+        let mut buf = vec![0u8; 512];
+        let size = crate::writer::write(&mut buf, &parsed).unwrap();
+        let reparsed = DnsPacket::try_from(&buf[..size]).unwrap();
+
+        assert_eq!(reparsed, parsed);
+    }
+
+    #[rustfmt::skip]
+    fn sip_naptr_packet() -> [u8; 79] {
+        [
+            0x3a, 0x4b, 0x81, 0x80, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, // header
+            0x07, 0x65, 0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65, 0x03, 0x63, 0x6f, 0x6d, 0x00, // example.com
+            0x00, 0x23, 0x00, 0x01, // query type and query class
+            // answer
+            0xc0, 0x0c, // jump location
+            0x00, 0x23, 0x00, 0x01, // query type and query class
+            0x00, 0x00, 0x0e, 0x10, // ttl
+            0x00, 0x26, // len
+            0x00, 0x64, // order
+            0x00, 0x0a, // preference
+            0x01, 0x53, // S
+            0x07, 0x53, 0x49, 0x50, 0x2b, 0x44, 0x32, 0x55, // SIP+D2U
+            0x00, // empty regexp
+            // _sip._udp.example.com (never compressed, RFC 3403 section 4.1)
+            0x04, 0x5f, 0x73, 0x69, 0x70,
+            0x04, 0x5f, 0x75, 0x64, 0x70,
+            0x07, 0x65, 0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65, 0x03, 0x63, 0x6f, 0x6d, 0x00,
+        ]
+    }
+
+    #[test]
+    fn naptr_record_round_trip_works() {
+        let record = DnsRecord::NAPTR {
+            domain: "example.com".to_string(),
+            order: 100,
+            preference: 10,
+            flags: "S".to_string(),
+            services: "SIP+D2U".to_string(),
+            regexp: "".to_string(),
+            replacement: "_sip._udp.example.com".to_string(),
+            ttl: 3600,
+        };
+
+        let parsed = DnsPacket::try_from(&sip_naptr_packet()[..]).unwrap();
+        assert_eq!(parsed.answers, vec![record]);
+
+        let mut buf = vec![0u8; 512];
+        let size = crate::writer::write(&mut buf, &parsed).unwrap();
+        let reparsed = DnsPacket::try_from(&buf[..size]).unwrap();
+
+        assert_eq!(reparsed, parsed);
+    }
+
+    #[rustfmt::skip]
+    fn cloudflare_dnskey_packet() -> [u8; 112] {
+        [
+            0x3a, 0x51, 0x81, 0x80, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, // header
+            0x0a, 0x63, 0x6c, 0x6f, 0x75, 0x64, 0x66, 0x6c, 0x61, 0x72, 0x65, // cloudflare
+            0x03, 0x63, 0x6f, 0x6d, 0x00, // com
+            0x00, 0x30, 0x00, 0x01, // query type and query class
+            // answer
+            0xc0, 0x0c, // jump location
+            0x00, 0x30, 0x00, 0x01, // query type and query class
+            0x00, 0x00, 0x0e, 0x10, // ttl
+            0x00, 0x44, // len
+            0x01, 0x00, // flags (zone key)
+            0x03, // protocol
+            0x0d, // algorithm (ECDSA P-256 with SHA-256)
+            // public key
+            0xa0, 0x93, 0x11, 0x11, 0x2c, 0xf9, 0x13, 0x88, 0x18, 0xcd, 0x2f, 0xea, 0xe9, 0x70, 0xeb, 0xbd,
+            0x4d, 0x6a, 0x30, 0xf6, 0x08, 0x8c, 0x25, 0xb3, 0x25, 0xa3, 0x9a, 0xbb, 0xc5, 0xcd, 0x11, 0x97,
+            0xaa, 0x09, 0x82, 0x83, 0xe5, 0xaa, 0xf4, 0x21, 0x17, 0x7c, 0x2a, 0xa5, 0xd7, 0x14, 0x99, 0x2a,
+            0x99, 0x57, 0xd1, 0xbc, 0xc1, 0x8f, 0x98, 0xcd, 0x71, 0xf1, 0xf1, 0x80, 0x6b, 0x65, 0xe1, 0x48,
+        ]
+    }
+
+    #[test]
+    fn dnskey_record_round_trip_works() {
+        let parsed = DnsPacket::try_from(&cloudflare_dnskey_packet()[..]).unwrap();
+        let record = &parsed.answers[0];
+
+        assert_eq!(
+            record.to_string(),
+            "cloudflare.com.\t3600\tIN\tDNSKEY\t256 3 13 \
+             oJMRESz5E4gYzS/q6XDrvU1qMPYIjCWzJaOau8XNEZeqCYKD5ar0IRd8KqXXFJkqmVfRvMGPmM1x8fGAa2XhSA=="
+        );
+
+        let buf = crate::writer::write_vec(&parsed).unwrap();
+
+        assert_eq!(buf, cloudflare_dnskey_packet().to_vec());
+    }
+
+    #[rustfmt::skip]
+    fn google_query_with_opt() -> [u8; 39] {
+        [
+            0xa8, 0x4f, // identifier
+            0x01, 0x20, // flags
+            0x00, 0x01, // question count
+            0x00, 0x00, // answer count
+            0x00, 0x00, // authority count
+            0x00, 0x01, // additional count
+            0x06, 0x67, 0x6f, 0x6f, 0x67, 0x6c, 0x65, 0x03, 0x63, 0x6f, 0x6d, 0x00, // google.com
+            0x00, 0x01, // query type
+            0x00, 0x01, // query question
+            0x00, // root
+            0x00, 0x29, // query type
+            0x10, 0x00, // udp payload size
+            0x00, 0x00, 0x80, 0x00, // extended rcode, version and flags
+            0x00, 0x00, // len
+        ]
+    }
+
+    #[test]
+    fn opt_record_parsing_works() {
+        let record = DnsRecord::OPT {
+            udp_payload_size: 4096,
+            ext_rcode: 0,
+            version: 0,
+            flags: 0x8000,
+            data: vec![],
+        };
+
+        let result = DnsPacket::try_from(&google_query_with_opt()[..]).unwrap();
+
+        assert_eq!(result.resources, vec![record]);
+        assert_eq!(result.udp_payload_size(), 4096);
+    }
+
+    #[rustfmt::skip]
+    fn spf_txt_packet() -> [u8; 59] {
+        [
+            0x6b, 0x03, 0x81, 0x80, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, // header
+            0x07, 0x65, 0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65, 0x03, 0x63, 0x6f, 0x6d, 0x00, // example.com
+            0x00, 0x10, 0x00, 0x01, // query type and query class
+            // answer
+            0xc0, 0x0c, // jump location
+            0x00, 0x10, 0x00, 0x01, // query type and query class
+            0x00, 0x00, 0x0e, 0x10, // ttl
+            0x00, 0x12, // len
+            0x0b, 0x76, 0x3d, 0x73, 0x70, 0x66, 0x31, 0x20, 0x2d, 0x61, 0x6c, 0x6c, // v=spf1 -all
+            0x05, 0x68, 0x65, 0x6c, 0x6c, 0x6f, // hello
+        ]
+    }
+
+    #[test]
+    fn txt_record_round_trip_works() {
+        let record = DnsRecord::TXT {
+            domain: "example.com".to_string(),
+            data: vec!["v=spf1 -all".to_string(), "hello".to_string()],
+            ttl: 3600,
+        };
+
+        let parsed = DnsPacket::try_from(&spf_txt_packet()[..]).unwrap();
+        assert_eq!(parsed.answers, vec![record]);
+
+        let mut buf = vec![0u8; 512];
+        let size = crate::writer::write(&mut buf, &parsed).unwrap();
+        let reparsed = DnsPacket::try_from(&buf[..size]).unwrap();
+
+        assert_eq!(reparsed, parsed);
+    }
+
+    #[test]
+    fn read_tcp_message_works() {
+        let first = DnsPacket::query(1, "example.com", QueryType::A);
+        let second = DnsPacket::query(2, "example.org", QueryType::AAAA);
+        let mut bytes = crate::writer::write_tcp_vec(&first).unwrap();
+        bytes.extend(crate::writer::write_tcp_vec(&second).unwrap());
+        let mut cursor = std::io::Cursor::new(&bytes[..bytes.len() - 1]);
+
+        assert_eq!(read_tcp_message(&mut cursor).unwrap(), first);
+        // The second message is missing its last byte
+        let error = read_tcp_message(&mut cursor).unwrap_err();
+        let error = error.downcast_ref::<std::io::Error>().unwrap();
+        assert_eq!(error.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn parse_reports_truncated_packets() {
+        let result = DnsPacket::parse(&google_query()[..20]);
+
+        assert_eq!(result, Err(DnsError::Truncated));
+    }
+
+    #[test]
+    fn parse_reports_bad_pointers() {
+        let mut buf = google_answer();
+        buf[29] = 0xff; // answer name now points past the end of the packet
+
+        let result = DnsPacket::parse(&buf);
+
+        assert_eq!(result, Err(DnsError::BadPointer));
+    }
+
+    #[test]
+    fn pointers_above_one_octet_work() {
+        // "example.com" at 0x3ff0, followed by "www" pointing back to it
+        let mut buf = vec![0u8; 0x3ff0];
+        buf.extend_from_slice(b"\x07example\x03com\x00");
+        let start = buf.len();
+        buf.extend_from_slice(b"\x03www\xff\xf0");
+
+        let (rest, result) = super::domain_name::<()>(&buf)(&buf[start..]).unwrap();
+
+        assert_eq!(result, "www.example.com");
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn pointers_use_the_high_bits_of_their_first_octet() {
+        // "example.org" at 0x00 and "example.com" at 0x100, a pointer that only looked at its
+        // second octet would end up at the wrong one
+        let mut buf = b"\x07example\x03org\x00".to_vec();
+        buf.resize(0x100, 0);
+        buf.extend_from_slice(b"\x07example\x03com\x00");
+        let start = buf.len();
+        buf.extend_from_slice(b"\x04mail\xc1\x00");
+
+        let (_, result) = super::domain_name::<()>(&buf)(&buf[start..]).unwrap();
+
+        assert_eq!(result, "mail.example.com");
+    }
+
+    #[test]
+    fn names_pointing_to_themselves_are_rejected() {
+        #[rustfmt::skip]
+        let buf = [
+            // Header: a query with a single question
+            0x00, 0x01, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            // Question: a name that is nothing but a pointer to itself, A, IN
+            0xc0, 0x0c, 0x00, 0x01, 0x00, 0x01,
+        ];
+
+        assert_eq!(DnsPacket::parse(&buf), Err(DnsError::BadPointer));
+    }
+
+    #[test]
+    fn names_pointing_to_each_other_are_rejected() {
+        #[rustfmt::skip]
+        let buf = [
+            // Header: a response with a question and an answer
+            0x00, 0x01, 0x81, 0x80, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00,
+            // Question: "a" followed by a pointer to the answer's name, A, IN
+            0x01, b'a', 0xc0, 0x14, 0x00, 0x01, 0x00, 0x01,
+            // Answer: a pointer back to the question's name, A, IN, TTL 60, 1.2.3.4
+            0xc0, 0x0c, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x3c, 0x00, 0x04,
+            0x01, 0x02, 0x03, 0x04,
+        ];
+
+        assert_eq!(DnsPacket::parse(&buf), Err(DnsError::BadPointer));
+        // Starting from the answer, the first jump is a fine one, it is the way back that is not
+        let result = super::domain_name::<()>(&buf)(&buf[20..]);
+        assert_eq!(result, Err(nom::Err::Error(())));
+    }
+
+    #[test]
+    fn pointers_into_earlier_sections_work() {
+        #[rustfmt::skip]
+        let buf = [
+            // Header: a response with a question, an NS record and its glue
+            0x00, 0x01, 0x80, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x00, 0x01,
+            // 12: example.com A IN
+            0x07, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0x03, b'c', b'o', b'm', 0x00,
+            0x00, 0x01, 0x00, 0x01,
+            // 29: example.com NS ns1.example.com, whose name starts at 41
+            0xc0, 0x0c, 0x00, 0x02, 0x00, 0x01, 0x00, 0x02, 0xa3, 0x00, 0x00, 0x06,
+            0x03, b'n', b's', b'1', 0xc0, 0x0c,
+            // 47: ns1.example.com A 192.0.2.53, named by pointing back into the NS record
+            0xc0, 0x29, 0x00, 0x01, 0x00, 0x01, 0x00, 0x02, 0xa3, 0x00, 0x00, 0x04,
+            0xc0, 0x00, 0x02, 0x35,
+        ];
+
+        let packet = DnsPacket::parse(&buf).unwrap();
+
+        assert_eq!(
+            packet.authorities,
+            vec![DnsRecord::NS {
+                domain: "example.com".to_string(),
+                host: "ns1.example.com".to_string(),
+                ttl: 172800,
+            }]
+        );
+        assert_eq!(
+            packet.resources,
+            vec![DnsRecord::A {
+                domain: "ns1.example.com".to_string(),
+                addr: Ipv4Addr::new(192, 0, 2, 53),
+                ttl: 172800,
+            }]
+        );
+    }
+
+    #[test]
+    fn large_compressed_packet_round_trip_works() {
+        let mut pack = DnsPacket::query(42, "example.com", QueryType::TXT);
+        pack.header.response = true;
+        // Every pair of records shares a zone, so pointers are spread over the whole packet, up to
+        // the highest offset a pointer can reach
+        pack.answers = (0..100)
+            .map(|i| DnsRecord::TXT {
+                domain: format!("host{}.zone{}.example.com", i, i / 2),
+                data: vec!["x".repeat(200)],
+                ttl: 300,
+            })
+            .collect();
+        pack.header.answers = pack.answers.len() as u16;
+
+        let written = crate::writer::write_tcp_vec(&pack).unwrap();
+        let parsed = DnsPacket::parse(&written[2..]).unwrap();
+
+        assert!(written.len() > 0x3fff);
+        assert_eq!(parsed, pack);
+        assert_no_compression_errors(&written[2..]);
+    }
+
+    #[test]
+    fn parse_reports_bad_labels() {
+        let mut buf = google_query();
+        buf[12] = 0x08; // "google" claims to be longer than it is
+
+        let result = DnsPacket::parse(&buf);
+
+        assert_eq!(result, Err(DnsError::BadLabel));
+    }
+
+    // A response with a single answer for example.com, whose data is `data`
+    fn address_response(qtype: QueryType, data: &[u8]) -> Vec<u8> {
+        let mut buf = vec![
+            0x00, 0x01, 0x80, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00,
+        ];
+        buf.extend_from_slice(b"\x07example\x03com\x00");
+        buf.extend_from_slice(&qtype.to_num().to_be_bytes());
+        buf.extend_from_slice(&[0x00, 0x01, 0x00, 0x00, 0x01, 0x2c]);
+        buf.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        buf.extend_from_slice(data);
+        buf
+    }
+
+    #[test]
+    fn parse_reports_bad_a_data_lengths() {
+        let short = DnsPacket::parse(&address_response(QueryType::A, &[1, 2]));
+        let long = DnsPacket::parse(&address_response(QueryType::A, &[1, 2, 3, 4, 5, 6]));
+        let exact = DnsPacket::parse(&address_response(QueryType::A, &[1, 2, 3, 4])).unwrap();
+
+        assert_eq!(short, Err(DnsError::BadRdataLength));
+        assert_eq!(long, Err(DnsError::BadRdataLength));
+        assert_eq!(exact.get_random_a(), Some(Ipv4Addr::new(1, 2, 3, 4)));
+    }
+
+    #[test]
+    fn parse_reports_bad_aaaa_data_lengths() {
+        let short = DnsPacket::parse(&address_response(QueryType::AAAA, &[0; 4]));
+        let long = DnsPacket::parse(&address_response(QueryType::AAAA, &[0; 20]));
+        let exact = DnsPacket::parse(&address_response(QueryType::AAAA, &[0; 16])).unwrap();
+
+        assert_eq!(short, Err(DnsError::BadRdataLength));
+        assert_eq!(long, Err(DnsError::BadRdataLength));
+        assert_eq!(exact.answers.len(), 1);
+    }
+
+    #[test]
+    fn parse_reports_trailing_bytes() {
+        let mut buf = google_query().to_vec();
+        buf.extend_from_slice(&[0xde, 0xad]);
+
+        let result = DnsPacket::parse(&buf);
+
+        assert_eq!(result, Err(DnsError::TrailingBytes(2)));
+    }
+
+    #[test]
+    fn try_from_keeps_string_errors() {
+        let result = DnsPacket::try_from(&google_query()[..20]);
+
+        assert_eq!(
+            result,
+            Err("Parsing Error: Packet is truncated".to_string())
+        );
+    }
+
+    #[rustfmt::skip]
+    fn dname_packet() -> [u8; 51] {
+        [
+            0x7a, 0x02, 0x81, 0x80, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, // header
+            0x03, 0x6f, 0x6c, 0x64, // old
+            0x07, 0x65, 0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65, 0x03, 0x63, 0x6f, 0x6d, 0x00, // example.com
+            0x00, 0x27, 0x00, 0x01, // query type and query class
+            // answer
+            0xc0, 0x0c, // jump location
+            0x00, 0x27, 0x00, 0x01, // query type and query class
+            0x00, 0x00, 0x0e, 0x10, // ttl
+            0x00, 0x06, // len
+            0x03, 0x6e, 0x65, 0x77, 0xc0, 0x10, // new (followed by a jump to example.com)
+        ]
+    }
+
+    #[test]
+    fn dname_record_round_trip_works() {
+        let record = DnsRecord::DNAME {
+            domain: "old.example.com".to_string(),
+            target: "new.example.com".to_string(),
+            ttl: 3600,
+        };
+
+        let parsed = DnsPacket::try_from(&dname_packet()[..]).unwrap();
+        assert_eq!(parsed.answers, vec![record]);
+
+        let mut buf = vec![0u8; 512];
+        let size = crate::writer::write(&mut buf, &parsed).unwrap();
+        let reparsed = DnsPacket::try_from(&buf[..size]).unwrap();
+
+        assert_eq!(reparsed, parsed);
+    }
+
+    // What servers implementing RFC 8482 send back instead of every record they have for an ANY query
+    #[rustfmt::skip]
+    fn rfc8482_hinfo_packet() -> [u8; 50] {
+        [
+            0x1f, 0x5e, 0x85, 0x00, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, // header
+            0x07, 0x65, 0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65, 0x03, 0x63, 0x6f, 0x6d, 0x00, // example.com
+            0x00, 0xff, 0x00, 0x01, // query type (ANY) and query class
+            // answer
+            0xc0, 0x0c, // jump location
+            0x00, 0x0d, 0x00, 0x01, // query type and query class
+            0x00, 0x00, 0x0e, 0x10, // ttl
+            0x00, 0x09, // len
+            0x07, 0x52, 0x46, 0x43, 0x38, 0x34, 0x38, 0x32, // RFC8482
+            0x00, // empty os
+        ]
+    }
+
+    #[test]
+    fn hinfo_record_round_trip_works() {
+        let record = DnsRecord::HINFO {
+            domain: "example.com".to_string(),
+            cpu: "RFC8482".to_string(),
+            os: "".to_string(),
+            ttl: 3600,
+        };
+
+        let parsed = DnsPacket::try_from(&rfc8482_hinfo_packet()[..]).unwrap();
+        assert_eq!(parsed.answers, vec![record]);
+
+        let mut buf = vec![0u8; 512];
+        let size = crate::writer::write(&mut buf, &parsed).unwrap();
+        let reparsed = DnsPacket::try_from(&buf[..size]).unwrap();
+
+        assert_eq!(reparsed, parsed);
+    }
+
+    // NODATA for an AAAA query, the name exists but has no records of that type
+    #[rustfmt::skip]
+    fn soa_nodata_packet() -> [u8; 85] {
+        [
+            0x3a, 0x7c, 0x81, 0x80, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, // header
+            0x07, 0x65, 0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65, 0x03, 0x63, 0x6f, 0x6d, 0x00, // example.com
+            0x00, 0x1c, 0x00, 0x01, // query type (AAAA) and query class
+            // authority
+            0xc0, 0x0c, // jump location
+            0x00, 0x06, 0x00, 0x01, // query type and query class
+            0x00, 0x00, 0x0e, 0x10, // ttl
+            0x00, 0x2c, // len
+            0x02, 0x6e, 0x73, 0x05, 0x69, 0x63, 0x61, 0x6e, 0x6e, 0x03, 0x6f, 0x72, 0x67, 0x00, // ns.icann.org
+            0x03, 0x6e, 0x6f, 0x63, 0x03, 0x64, 0x6e, 0x73, 0xc0, 0x2c, // noc.dns (followed by a jump to icann.org)
+            0x78, 0xa5, 0x07, 0xf8, // serial
+            0x00, 0x00, 0x1c, 0x20, // refresh
+            0x00, 0x00, 0x0e, 0x10, // retry
+            0x00, 0x12, 0x75, 0x00, // expire
+            0x00, 0x00, 0x0e, 0x10, // minimum
+        ]
+    }
+
+    #[test]
+    fn soa_record_round_trip_works() {
+        let record = DnsRecord::SOA {
+            domain: "example.com".to_string(),
+            m_name: "ns.icann.org".to_string(),
+            r_name: "noc.dns.icann.org".to_string(),
+            serial: 2024081400,
+            refresh: 7200,
+            retry: 3600,
+            expire: 1209600,
+            minimum: 3600,
+            ttl: 3600,
+        };
+
+        let parsed = DnsPacket::try_from(&soa_nodata_packet()[..]).unwrap();
+        assert_eq!(parsed.authorities, vec![record]);
+
+        let mut buf = vec![0u8; 512];
+        let size = crate::writer::write(&mut buf, &parsed).unwrap();
+        let reparsed = DnsPacket::try_from(&buf[..size]).unwrap();
+
+        assert_eq!(reparsed, parsed);
+    }
+
+    #[rustfmt::skip]
+    fn loc_packet() -> [u8; 57] {
+        [
+            0x4c, 0x4f, 0x81, 0x80, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, // header
+            0x07, 0x65, 0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65, 0x03, 0x63, 0x6f, 0x6d, 0x00, // example.com
+            0x00, 0x1d, 0x00, 0x01, // query type (LOC) and query class
+            // answer
+            0xc0, 0x0c, // jump location
+            0x00, 0x1d, 0x00, 0x01, // query type and query class
+            0x00, 0x00, 0x0e, 0x10, // ttl
+            0x00, 0x10, // len
+            0x00, // version
+            0x33, 0x16, 0x13, // size (30m), horizontal (10000m) and vertical (10m) precision
+            0x89, 0x17, 0x2d, 0xd0, // latitude (42 21 54 N)
+            0x70, 0xbe, 0x15, 0xf0, // longitude (71 06 18 W)
+            0x00, 0x98, 0x8d, 0x20, // altitude (-24m)
+        ]
+    }
+
+    #[test]
+    fn loc_record_round_trip_works() {
+        let record = DnsRecord::LOC {
+            domain: "example.com".to_string(),
+            version: 0,
+            size: 0x33,
+            horiz_pre: 0x16,
+            vert_pre: 0x13,
+            latitude: 2299997648,
+            longitude: 1891505648,
+            altitude: 9997600,
+            ttl: 3600,
+        };
+
+        let parsed = DnsPacket::try_from(&loc_packet()[..]).unwrap();
+        assert_eq!(parsed.answers, vec![record]);
+
+        let mut buf = vec![0u8; 512];
+        let size = crate::writer::write(&mut buf, &parsed).unwrap();
+        let reparsed = DnsPacket::try_from(&buf[..size]).unwrap();
+
+        assert_eq!(reparsed, parsed);
+    }
+
+    #[rustfmt::skip]
+    fn rrsig_packet() -> [u8; 80] {
+        [
+            0x5d, 0x1e, 0x81, 0xa0, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, // header
+            0x07, 0x65, 0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65, 0x03, 0x63, 0x6f, 0x6d, 0x00, // example.com
+            0x00, 0x01, 0x00, 0x01, // query type (A) and query class
+            // answer
+            0xc0, 0x0c, // jump location
+            0x00, 0x2e, 0x00, 0x01, // query type (RRSIG) and query class
+            0x00, 0x00, 0x0e, 0x10, // ttl
+            0x00, 0x27, // len
+            0x00, 0x01, 0x0d, 0x02, // type covered (A), algorithm (13) and labels
+            0x00, 0x00, 0x0e, 0x10, // original ttl
+            0x66, 0x00, 0x00, 0x00, 0x65, 0x00, 0x00, 0x00, // signature expiration and inception
+            0x12, 0x34, // key tag
+            0x07, 0x65, 0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65, 0x03, 0x63, 0x6f, 0x6d, 0x00, // signer
+            0xde, 0xad, 0xbe, 0xef, 0xde, 0xad, 0xbe, 0xef, // signature
+        ]
+    }
+
+    #[test]
+    fn rrsig_record_round_trip_works() {
+        let packet = rrsig_packet();
+
+        let parsed = DnsPacket::try_from(&packet[..]).unwrap();
+        assert_eq!(parsed.answers[0].query_type(), QueryType::RRSIG);
+        assert_eq!(
+            parsed.answers,
+            vec![DnsRecord::UNKNOWN {
+                domain: "example.com".to_string(),
+                qtype: 46,
+                data: packet[41..].to_vec(),
+                ttl: 3600,
+            }]
+        );
+
+        let mut buf = vec![0u8; 512];
+        let size = crate::writer::write(&mut buf, &parsed).unwrap();
+        let reparsed = DnsPacket::try_from(&buf[..size]).unwrap();
+
+        assert_eq!(reparsed, parsed);
+    }
+
+    #[rustfmt::skip]
+    fn https_packet() -> [u8; 51] {
+        [
+            0x2c, 0x41, 0x81, 0x80, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, // header
+            0x07, 0x65, 0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65, 0x03, 0x63, 0x6f, 0x6d, 0x00, // example.com
+            0x00, 0x41, 0x00, 0x01, // query type (HTTPS) and query class
+            // answer
+            0xc0, 0x0c, // jump location
+            0x00, 0x41, 0x00, 0x01, // query type and query class
+            0x00, 0x00, 0x0e, 0x10, // ttl
+            0x00, 0x0a, // len
+            0x00, 0x01, // priority
+            0x00, // target (root, i.e. the owner name)
+            0x00, 0x01, 0x00, 0x03, 0x02, 0x68, 0x32, // alpn=h2
+        ]
+    }
+
+    #[test]
+    fn unknown_record_round_trip_works() {
+        let record = DnsRecord::UNKNOWN {
+            domain: "example.com".to_string(),
+            qtype: 65,
+            data: vec![0x00, 0x01, 0x00, 0x00, 0x01, 0x00, 0x03, 0x02, 0x68, 0x32],
+            ttl: 3600,
+        };
+
+        let parsed = DnsPacket::try_from(&https_packet()[..]).unwrap();
+        assert_eq!(parsed.answers, vec![record]);
+
+        let mut buf = vec![0u8; 512];
+        let size = crate::writer::write(&mut buf, &parsed).unwrap();
+        let reparsed = DnsPacket::try_from(&buf[..size]).unwrap();
+
+        assert_eq!(reparsed, parsed);
+    }
+
+    #[test]
+    fn inflated_section_counts_are_rejected() {
+        let mut buf = google_query();
+        buf[6] = 0xff; // answer count
+        buf[7] = 0xff;
+
+        let result = packet(&buf, &buf);
+
+        // Right after the header, before even the question is read
+        assert_eq!(
+            result,
+            Err(nom::Err::Error(nom::error::Error::new(
+                &buf[12..],
+                ErrorKind::Eof
+            )))
+        );
+    }
+
+    #[test]
+    fn parse_reports_inflated_section_counts() {
+        let mut buf = google_query();
+        buf[6] = 0xff; // answer count
+        buf[7] = 0xff;
+
+        let result = DnsPacket::parse(&buf);
+
+        assert_eq!(
+            result,
+            Err(DnsError::CountMismatch {
+                section: Section::Answer,
+                declared: 0xffff,
+                parsed: 0,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_reports_count_mismatches() {
+        let mut buf = yahoo_packet();
+        buf[7] = 0x05; // 5 answers, there are only 3
+
+        let result = DnsPacket::parse(&buf);
+
+        assert_eq!(
+            result,
+            Err(DnsError::CountMismatch {
+                section: Section::Answer,
+                declared: 5,
+                parsed: 3,
+            })
+        );
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Parsing Error: Header declares 5 entries in the answer section but only 3 are present"
+        );
+    }
+
+    #[test]
+    fn parse_reports_missing_questions() {
+        let result = DnsPacket::parse(&google_query()[..12]);
+
+        assert_eq!(
+            result,
+            Err(DnsError::CountMismatch {
+                section: Section::Question,
+                declared: 1,
+                parsed: 0,
+            })
+        );
+    }
+
+    #[test]
+    fn fixtures_have_no_compression_errors() {
+        let fixtures: Vec<Vec<u8>> = vec![
+            google_query().to_vec(),
+            google_answer().to_vec(),
+            yahoo_packet().to_vec(),
+            gtld_referral_packet().to_vec(),
+            sip_srv_packet().to_vec(),
+            reverse_google_packet().to_vec(),
+            letsencrypt_caa_packet().to_vec(),
+            sip_naptr_packet().to_vec(),
+            cloudflare_dnskey_packet().to_vec(),
+            google_query_with_opt().to_vec(),
+            spf_txt_packet().to_vec(),
+            dname_packet().to_vec(),
+            rfc8482_hinfo_packet().to_vec(),
+            soa_nodata_packet().to_vec(),
+            loc_packet().to_vec(),
+            rrsig_packet().to_vec(),
+            https_packet().to_vec(),
+        ];
+
+        for fixture in fixtures {
+            assert_no_compression_errors(&fixture);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "never ends")]
+    fn compression_loops_are_caught_before_parsing() {
+        let mut fixture = google_answer().to_vec();
+        fixture[29] = 0x1c; // answer name now points to itself
+
+        assert_no_compression_errors(&fixture);
+    }
 }
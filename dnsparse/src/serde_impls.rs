@@ -0,0 +1,100 @@
+use std::fmt::Display;
+use std::str::FromStr;
+
+use serde::{de, Deserialize, Deserializer, Serializer};
+
+use crate::types::{DnsClass, QueryType, ResponseCode};
+
+// Query types, classes and response codes are written by their mnemonic (e.g. "AAAA", "IN",
+// "NXDOMAIN") rather than as the variants of the enums, which is what people reading the JSON will
+// recognize
+
+fn serialize_str<T: Display, S: Serializer>(value: &T, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(value)
+}
+
+fn deserialize_str<'de, T, D>(deserializer: D) -> Result<T, D::Error>
+where
+    T: FromStr<Err = String>,
+    D: Deserializer<'de>,
+{
+    String::deserialize(deserializer)?
+        .parse()
+        .map_err(de::Error::custom)
+}
+
+impl serde::Serialize for QueryType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_str(self, serializer)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for QueryType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize_str(deserializer)
+    }
+}
+
+impl serde::Serialize for DnsClass {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_str(self, serializer)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for DnsClass {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize_str(deserializer)
+    }
+}
+
+impl serde::Serialize for ResponseCode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_str(self, serializer)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for ResponseCode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize_str(deserializer)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+    use std::net::Ipv4Addr;
+
+    use crate::types::{DnsHeader, DnsPacket, DnsQuestion, DnsRecord, QueryType, ResponseCode};
+
+    #[test]
+    fn json_round_trip_works() {
+        let header = DnsHeader::builder()
+            .id(818)
+            .response(true)
+            .rescode(ResponseCode::NXDOMAIN)
+            .questions(1)
+            .answers(1)
+            .build();
+        let question = DnsQuestion::builder()
+            .name("example.com".to_string())
+            .qtype(QueryType::UNKNOWN(65))
+            .build();
+        let record = DnsRecord::A {
+            domain: "example.com".to_string(),
+            addr: Ipv4Addr::new(93, 184, 216, 34),
+            ttl: 300,
+        };
+        let packet = DnsPacket::builder()
+            .header(header)
+            .questions(vec![question])
+            .answers(vec![record])
+            .build();
+
+        let json = serde_json::to_value(&packet).unwrap();
+        let parsed: DnsPacket = serde_json::from_value(json.clone()).unwrap();
+
+        assert_eq!(json["header"]["rescode"], "NXDOMAIN");
+        assert_eq!(json["questions"][0]["qtype"], "TYPE65");
+        assert_eq!(parsed, packet);
+    }
+}
@@ -1,6 +1,18 @@
-use std::net::{Ipv4Addr, Ipv6Addr};
+use rand::seq::SliceRandom;
+use std::{
+    collections::HashSet,
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
 use typed_builder::TypedBuilder;
 
+// Without EDNS a UDP message is limited to 512 bytes (not counting the IP or UDP headers).
+pub(crate) const DEFAULT_UDP_PAYLOAD_SIZE: usize = 512;
+const DNS_HEADER_SIZE: usize = 12;
+// The DNSSEC OK bit in the flags of the OPT record (RFC 3225), asking for DNSSEC records
+pub const EDNS_DO_FLAG: u16 = 0x8000;
+
 // All communications inside of the domain protocol are carried in a single
 // format called a message.  The top level format of message is divided
 // into 5 sections (some of which are empty in certain cases) shown below:
@@ -21,7 +33,11 @@ use typed_builder::TypedBuilder;
 // specify which of the remaining sections are present, and also specify
 // whether the message is a query or a response, a standard query or some
 // other opcode, etc.
+//
+// The records of every section are kept in the order they appear in on the wire, and written out
+// in that same order, so that parsing and writing a packet leaves the order alone.
 #[derive(Clone, Debug, PartialEq, Eq, TypedBuilder)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DnsPacket {
     pub header: DnsHeader,
 
@@ -52,6 +68,7 @@ pub struct DnsPacket {
 //  |                    ARCOUNT                    |
 //  +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
 #[derive(Clone, Debug, PartialEq, Eq, TypedBuilder)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DnsHeader {
     // A random identifier is assigned to query packets. Response packets must reply with the same
     // id. This is needed to differentiate responses due to the stateless nature of UDP.
@@ -113,7 +130,8 @@ pub struct DnsHeader {
 
 impl DnsHeader {
     pub fn flags(&self) -> u16 {
-        (self.rescode as u16)
+        // Only the lower 4 bits of the code fit in the header
+        (self.rescode.to_num() & 0x0F)
             | ((self.checking_disabled as u16) << 4)
             | ((self.authed_data as u16) << 5)
             | ((self.z as u16) << 6)
@@ -126,43 +144,125 @@ impl DnsHeader {
     }
 }
 
+// The DNSSEC related bits a query can be sent with (RFC 4035 section 3.2), see
+// `DnsPacket::query_with_flags`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueryFlags {
+    // Ask for records even if they fail validation upstream, e.g. to debug a broken signature
+    pub checking_disabled: bool,
+    // Ask whether the records were validated, without having to ask for the signatures (DO)
+    pub authed_data: bool,
+}
+
 //  Response code - this 4 bit field is set as part of responses.  The values have the following
-//  interpretation:
+//  interpretation (see the IANA "DNS RCODEs" registry, RFC 6895):
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum ResponseCode {
     // No error condition
-    NOERROR = 0,
+    NOERROR,
     // Format error - The name server was unable to interpret the query.
-    FORMERR = 1,
+    FORMERR,
     // Server failure - The name server was unable to process this query due to a problem with the
     // name server.
-    SERVFAIL = 2,
+    SERVFAIL,
     // Name Error - Meaningful only for responses from an authoritative name server, this code
     // signifies that the domain name referenced in the query does not exist.
-    NXDOMAIN = 3,
+    NXDOMAIN,
     // Not Implemented - The name server does not support the requested kind of query.
-    NOTIMP = 4,
+    NOTIMP,
     // Refused - The name server refuses to perform the specified operation for policy reasons.
     // For example, a name server may not wish to provide the information to the particular
     // requester, or a name server may not wish to perform a particular operation (e.g., zone
     // transfer) for particular data.
-    REFUSED = 5,
-    //  6-15  Reserved for future use.
+    REFUSED,
+    // Name Exists when it should not (RFC 2136)
+    YXDOMAIN,
+    // RR Set Exists when it should not (RFC 2136)
+    YXRRSET,
+    // RR Set that should exist does not (RFC 2136)
+    NXRRSET,
+    // Server Not Authoritative for zone (RFC 2136) or Not Authorized (RFC 8945)
+    NOTAUTH,
+    // Name not contained in zone (RFC 2136)
+    NOTZONE,
+    // 11-15 are unassigned.
+    //
+    // Codes from 16 onwards do not fit the 4 bits of the header. Their upper 8 bits are kept in
+    // the OPT record (`ext_rcode`), see `DnsPacket::rescode`.
+    //
+    // Bad OPT Version - The server does not implement the EDNS version of the request (RFC 6891)
+    BADVERS,
+    // Any other code, including the ones we do not know about. Those are never a success.
+    UNKNOWN(u16),
 }
 
 impl ResponseCode {
-    pub fn from_num(num: u8) -> ResponseCode {
+    pub fn to_num(self) -> u16 {
+        match self {
+            ResponseCode::NOERROR => 0,
+            ResponseCode::FORMERR => 1,
+            ResponseCode::SERVFAIL => 2,
+            ResponseCode::NXDOMAIN => 3,
+            ResponseCode::NOTIMP => 4,
+            ResponseCode::REFUSED => 5,
+            ResponseCode::YXDOMAIN => 6,
+            ResponseCode::YXRRSET => 7,
+            ResponseCode::NXRRSET => 8,
+            ResponseCode::NOTAUTH => 9,
+            ResponseCode::NOTZONE => 10,
+            ResponseCode::BADVERS => 16,
+            ResponseCode::UNKNOWN(x) => x,
+        }
+    }
+
+    pub fn from_num(num: u16) -> ResponseCode {
         match num {
+            0 => ResponseCode::NOERROR,
             1 => ResponseCode::FORMERR,
             2 => ResponseCode::SERVFAIL,
             3 => ResponseCode::NXDOMAIN,
             4 => ResponseCode::NOTIMP,
             5 => ResponseCode::REFUSED,
-            _ => ResponseCode::NOERROR,
+            6 => ResponseCode::YXDOMAIN,
+            7 => ResponseCode::YXRRSET,
+            8 => ResponseCode::NXRRSET,
+            9 => ResponseCode::NOTAUTH,
+            10 => ResponseCode::NOTZONE,
+            16 => ResponseCode::BADVERS,
+            _ => ResponseCode::UNKNOWN(num),
         }
     }
 }
 
+// The name of the code, codes without one are written as RCODE<number>
+impl fmt::Display for ResponseCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResponseCode::UNKNOWN(num) => write!(f, "RCODE{}", num),
+            code => write!(f, "{:?}", code),
+        }
+    }
+}
+
+impl FromStr for ResponseCode {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        if let Some(num) = name.strip_prefix("RCODE") {
+            return num
+                .parse()
+                .map(ResponseCode::from_num)
+                .map_err(|_| format!("Invalid response code {}", name));
+        }
+
+        (0..=16)
+            .map(ResponseCode::from_num)
+            .find(|code| code.to_string() == name)
+            .ok_or_else(|| format!("Unknown response code {}", name))
+    }
+}
+
 // The question section is used to carry the "question" in most queries, i.e., the parameters that
 // define what is being asked.  The section contains QDCOUNT (usually 1) entries, each of the
 // following format:
@@ -177,13 +277,91 @@ impl ResponseCode {
 //  +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
 //  |                     QCLASS                    |
 //  +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, TypedBuilder)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DnsQuestion {
     // The domain name, encoded as a sequence of labels as described below
     pub name: String,
     // The Record Type
     pub qtype: QueryType,
-    // The class is almost always set to 1, so we will not represent it here
+    // Almost always IN, CH is what `version.bind` and friends are asked in
+    #[builder(default = DnsClass::IN)]
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub class: DnsClass,
+}
+
+// The classes of RFC 1035 section 3.2.4 plus the QCLASS values of section 3.2.5 and RFC 2136
+#[derive(PartialEq, Eq, Debug, Clone, Hash, Copy, Default)]
+pub enum DnsClass {
+    UNKNOWN(u16),
+    // The Internet
+    #[default]
+    IN,
+    // Chaos
+    CH,
+    // Hesiod
+    HS,
+    NONE,
+    ANY,
+}
+
+impl DnsClass {
+    pub fn to_num(self) -> u16 {
+        match self {
+            DnsClass::UNKNOWN(x) => x,
+            DnsClass::IN => 1,
+            DnsClass::CH => 3,
+            DnsClass::HS => 4,
+            DnsClass::NONE => 254,
+            DnsClass::ANY => 255,
+        }
+    }
+
+    pub fn from_num(num: u16) -> DnsClass {
+        match num {
+            1 => DnsClass::IN,
+            3 => DnsClass::CH,
+            4 => DnsClass::HS,
+            254 => DnsClass::NONE,
+            255 => DnsClass::ANY,
+            _ => DnsClass::UNKNOWN(num),
+        }
+    }
+}
+
+// The mnemonic of the class, classes we do not know about are written as CLASS<number> (RFC 3597)
+impl fmt::Display for DnsClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DnsClass::UNKNOWN(num) => write!(f, "CLASS{}", num),
+            DnsClass::IN => write!(f, "IN"),
+            DnsClass::CH => write!(f, "CH"),
+            DnsClass::HS => write!(f, "HS"),
+            DnsClass::NONE => write!(f, "NONE"),
+            DnsClass::ANY => write!(f, "ANY"),
+        }
+    }
+}
+
+impl FromStr for DnsClass {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        let class = match name {
+            "IN" => DnsClass::IN,
+            "CH" => DnsClass::CH,
+            "HS" => DnsClass::HS,
+            "NONE" => DnsClass::NONE,
+            "ANY" => DnsClass::ANY,
+            _ => name
+                .strip_prefix("CLASS")
+                .and_then(|num| num.parse().ok())
+                .map(DnsClass::from_num)
+                .ok_or_else(|| format!("Unknown class {}", name))?,
+        };
+
+        Ok(class)
+    }
 }
 
 #[derive(PartialEq, Eq, Debug, Clone, Hash, Copy)]
@@ -194,6 +372,24 @@ pub enum QueryType {
     NS,
     MX,
     AAAA,
+    SRV,
+    PTR,
+    CAA,
+    OPT,
+    TXT,
+    DNAME,
+    HINFO,
+    SOA,
+    LOC,
+    NAPTR,
+    // Only ever asked for, it stands for every record the name has (RFC 1035 section 3.2.3)
+    ANY,
+    // DNSSEC (RFC 4034, RFC 5155), the records of these types are passed on as they are
+    DS,
+    RRSIG,
+    NSEC,
+    DNSKEY,
+    NSEC3,
 }
 
 impl QueryType {
@@ -205,6 +401,22 @@ impl QueryType {
             QueryType::NS => 2,
             QueryType::MX => 15,
             QueryType::AAAA => 28,
+            QueryType::SRV => 33,
+            QueryType::PTR => 12,
+            QueryType::CAA => 257,
+            QueryType::OPT => 41,
+            QueryType::TXT => 16,
+            QueryType::DNAME => 39,
+            QueryType::HINFO => 13,
+            QueryType::SOA => 6,
+            QueryType::LOC => 29,
+            QueryType::NAPTR => 35,
+            QueryType::ANY => 255,
+            QueryType::DS => 43,
+            QueryType::RRSIG => 46,
+            QueryType::NSEC => 47,
+            QueryType::DNSKEY => 48,
+            QueryType::NSEC3 => 50,
         }
     }
 
@@ -213,11 +425,118 @@ impl QueryType {
             1 => QueryType::A,
             2 => QueryType::NS,
             5 => QueryType::CNAME,
+            6 => QueryType::SOA,
+            12 => QueryType::PTR,
+            13 => QueryType::HINFO,
             15 => QueryType::MX,
+            16 => QueryType::TXT,
             28 => QueryType::AAAA,
+            29 => QueryType::LOC,
+            33 => QueryType::SRV,
+            35 => QueryType::NAPTR,
+            39 => QueryType::DNAME,
+            41 => QueryType::OPT,
+            43 => QueryType::DS,
+            46 => QueryType::RRSIG,
+            47 => QueryType::NSEC,
+            48 => QueryType::DNSKEY,
+            50 => QueryType::NSEC3,
+            255 => QueryType::ANY,
+            257 => QueryType::CAA,
             _ => QueryType::UNKNOWN(num),
         }
     }
+
+    // Records of these types hold the addresses of their name
+    pub fn is_address(self) -> bool {
+        matches!(self, QueryType::A | QueryType::AAAA)
+    }
+
+    // Records of these types point to another name, whose addresses may well be wanted next
+    pub fn is_name_data(self) -> bool {
+        matches!(
+            self,
+            QueryType::NS | QueryType::CNAME | QueryType::MX | QueryType::PTR
+        )
+    }
+
+    pub fn is_dnssec(self) -> bool {
+        matches!(
+            self,
+            QueryType::DS
+                | QueryType::RRSIG
+                | QueryType::NSEC
+                | QueryType::DNSKEY
+                | QueryType::NSEC3
+        )
+    }
+}
+
+// The mnemonic of the type, types we do not know about are written as TYPE<number> (RFC 3597)
+impl fmt::Display for QueryType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueryType::UNKNOWN(num) => write!(f, "TYPE{}", num),
+            QueryType::A => write!(f, "A"),
+            QueryType::CNAME => write!(f, "CNAME"),
+            QueryType::NS => write!(f, "NS"),
+            QueryType::MX => write!(f, "MX"),
+            QueryType::AAAA => write!(f, "AAAA"),
+            QueryType::SRV => write!(f, "SRV"),
+            QueryType::PTR => write!(f, "PTR"),
+            QueryType::CAA => write!(f, "CAA"),
+            QueryType::OPT => write!(f, "OPT"),
+            QueryType::TXT => write!(f, "TXT"),
+            QueryType::DNAME => write!(f, "DNAME"),
+            QueryType::HINFO => write!(f, "HINFO"),
+            QueryType::SOA => write!(f, "SOA"),
+            QueryType::LOC => write!(f, "LOC"),
+            QueryType::NAPTR => write!(f, "NAPTR"),
+            QueryType::ANY => write!(f, "ANY"),
+            QueryType::DS => write!(f, "DS"),
+            QueryType::RRSIG => write!(f, "RRSIG"),
+            QueryType::NSEC => write!(f, "NSEC"),
+            QueryType::DNSKEY => write!(f, "DNSKEY"),
+            QueryType::NSEC3 => write!(f, "NSEC3"),
+        }
+    }
+}
+
+impl FromStr for QueryType {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        let qtype = match name {
+            "A" => QueryType::A,
+            "CNAME" => QueryType::CNAME,
+            "NS" => QueryType::NS,
+            "MX" => QueryType::MX,
+            "AAAA" => QueryType::AAAA,
+            "SRV" => QueryType::SRV,
+            "PTR" => QueryType::PTR,
+            "CAA" => QueryType::CAA,
+            "OPT" => QueryType::OPT,
+            "TXT" => QueryType::TXT,
+            "DNAME" => QueryType::DNAME,
+            "HINFO" => QueryType::HINFO,
+            "SOA" => QueryType::SOA,
+            "LOC" => QueryType::LOC,
+            "NAPTR" => QueryType::NAPTR,
+            "ANY" => QueryType::ANY,
+            "DS" => QueryType::DS,
+            "RRSIG" => QueryType::RRSIG,
+            "NSEC" => QueryType::NSEC,
+            "DNSKEY" => QueryType::DNSKEY,
+            "NSEC3" => QueryType::NSEC3,
+            _ => name
+                .strip_prefix("TYPE")
+                .and_then(|num| num.parse().ok())
+                .map(QueryType::from_num)
+                .ok_or_else(|| format!("Unknown query type {}", name))?,
+        };
+
+        Ok(qtype)
+    }
 }
 
 // The answer, authority, and additional sections all share the same format: a variable number of
@@ -249,9 +568,40 @@ impl QueryType {
 // | 1  | A     | Alias - Mapping names to IP addresses    | Preamble + Four bytes for IPv4 adress            |
 // | 2  | NS    | Name Server - The DNS server address     | Preamble + Label Sequence                        |
 // | 5  | CNAME | Canonical Name - Maps names to names     | Preamble + Label Sequence                        |
+// | 6  | SOA   | Start of Authority - Zone parameters     | Preamble + 2 Label Sequences (mname, rname) +    |
+// |    |       |                                          | 5 * 4-bytes (serial, refresh, retry, expire, min)|
+// | 12 | PTR   | Pointer - Maps addresses back to names   | Preamble + Label Sequence                        |
+// | 13 | HINFO | Host Information - CPU and OS of a host  | Preamble + Two Character Strings (cpu, os)       |
 // | 15 | MX    | Mail eXchange - mail server for a domain | Preamble + 2-bytes for priority + Label Sequence |
+// | 16 | TXT   | Text strings                             | Preamble + One or more Character Strings         |
 // | 28 | AAAA  | IPv6 alias                               | Premable + Sixteen bytes for IPv6 adress         |
+// | 29 | LOC   | Location - Geographic position of a host | Preamble + 4 * 1-byte (version, size, horiz pre, |
+// |    |       |                                          | vert pre) + 3 * 4-bytes (lat, long, altitude)    |
+// | 33 | SRV   | Service locator - host/port of a service | Preamble + 3 * 2-bytes (prio, weight, port) +    |
+// |    |       |                                          | Label Sequence                                   |
+// | 35 | NAPTR | Naming Authority Pointer - Rewrite rules | Preamble + 2 * 2-bytes (order, preference) +     |
+// |    |       |                                          | 3 Character Strings + Label Sequence             |
+// | 48 | DNSKEY| Public key a zone is signed with         | Preamble + 2-bytes flags + 1-byte protocol +     |
+// |    |       |                                          | 1-byte algorithm + remaining bytes (public key)  |
+// | 39 | DNAME | Delegation Name - Maps a whole subtree    | Preamble + Label Sequence                        |
+// | 41 | OPT   | EDNS0 pseudo-record (see below)          | Root name + payload size in CLASS + packed TTL   |
+// |    |       |                                          | + Options                                        |
+// | 257| CAA   | Certification Authority Authorization    | Preamble + 1-byte flags + Character String (tag) |
+// |    |       |                                          | + remaining bytes (value)                        |
+//
+// The OPT pseudo-record (EDNS0, RFC 6891) lives in the additional section and reuses the fixed
+// fields of the record layout for other purposes. NAME is always the root, CLASS holds the UDP
+// payload size the sender can receive and TTL is split up as follows:
+//                                     1  1  1  1  1  1
+//       0  1  2  3  4  5  6  7  8  9  0  1  2  3  4  5
+//     +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
+//     |     EXTENDED-RCODE    |        VERSION        |
+//     +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
+//     |DO|                    Z                       |
+//     +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum DnsRecord {
     A {
         domain: String,
@@ -279,75 +629,885 @@ pub enum DnsRecord {
         addr: Ipv6Addr,
         ttl: u32,
     },
+    SRV {
+        domain: String,
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: String,
+        ttl: u32,
+    },
+    PTR {
+        domain: String,
+        host: String,
+        ttl: u32,
+    },
+    CAA {
+        domain: String,
+        flags: u8,
+        tag: String,
+        value: String,
+        ttl: u32,
+    },
+    TXT {
+        domain: String,
+        data: Vec<String>,
+        ttl: u32,
+    },
+    DNAME {
+        domain: String,
+        target: String,
+        ttl: u32,
+    },
+    HINFO {
+        domain: String,
+        cpu: String,
+        os: String,
+        ttl: u32,
+    },
+    SOA {
+        domain: String,
+        // The primary name server of the zone
+        m_name: String,
+        // The mailbox of whoever is responsible for the zone, with the @ turned into a dot
+        r_name: String,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        // How long negative answers may be cached for (RFC 2308)
+        minimum: u32,
+        ttl: u32,
+    },
+    // RFC 1876, kept in its packed form (see `loc_to_degrees` and `loc_precision_to_cm`)
+    LOC {
+        domain: String,
+        version: u8,
+        // Diameter of a sphere enclosing the entity and the precision of the position
+        size: u8,
+        horiz_pre: u8,
+        vert_pre: u8,
+        // In thousandths of an arc second, 2^31 being the equator and the prime meridian
+        latitude: u32,
+        longitude: u32,
+        // In centimeters, from 100,000m below the WGS 84 reference spheroid
+        altitude: u32,
+        ttl: u32,
+    },
+    // RFC 3403, rewrite rules for turning e.g. a phone number (ENUM) into a SIP URI
+    NAPTR {
+        domain: String,
+        // Rules are applied lowest order first, preference breaking ties between equal orders
+        order: u16,
+        preference: u16,
+        flags: String,
+        services: String,
+        regexp: String,
+        // The name to look up next, the root when `regexp` is used instead
+        replacement: String,
+        ttl: u32,
+    },
+    // RFC 4034 section 2, passed on without validating anything signed with it
+    DNSKEY {
+        domain: String,
+        // 256 for a zone signing key, 257 when it is also a key signing key (SEP bit)
+        flags: u16,
+        // Always 3
+        protocol: u8,
+        algorithm: u8,
+        public_key: Vec<u8>,
+        ttl: u32,
+    },
+    OPT {
+        udp_payload_size: u16,
+        ext_rcode: u8,
+        version: u8,
+        flags: u16,
+        data: Vec<u8>,
+    },
+    // Record types we do not know about keep their RDATA as is. Compression is not allowed within
+    // the RDATA of those (RFC 3597), so the bytes can be written back out into any other packet.
     UNKNOWN {
         domain: String,
         qtype: u16,
-        data_len: u16,
+        data: Vec<u8>,
         ttl: u32,
     },
 }
 
+impl DnsRecord {
+    pub fn domain(&self) -> &str {
+        match self {
+            DnsRecord::A { domain, .. }
+            | DnsRecord::NS { domain, .. }
+            | DnsRecord::CNAME { domain, .. }
+            | DnsRecord::MX { domain, .. }
+            | DnsRecord::AAAA { domain, .. }
+            | DnsRecord::SRV { domain, .. }
+            | DnsRecord::PTR { domain, .. }
+            | DnsRecord::CAA { domain, .. }
+            | DnsRecord::TXT { domain, .. }
+            | DnsRecord::DNAME { domain, .. }
+            | DnsRecord::HINFO { domain, .. }
+            | DnsRecord::SOA { domain, .. }
+            | DnsRecord::LOC { domain, .. }
+            | DnsRecord::NAPTR { domain, .. }
+            | DnsRecord::DNSKEY { domain, .. }
+            | DnsRecord::UNKNOWN { domain, .. } => domain,
+            // OPT is always owned by the root
+            DnsRecord::OPT { .. } => "",
+        }
+    }
+
+    // OPT has no TTL of its own, the field is used to carry the extended rcode, version and flags
+    pub fn ttl(&self) -> u32 {
+        match self {
+            DnsRecord::A { ttl, .. }
+            | DnsRecord::NS { ttl, .. }
+            | DnsRecord::CNAME { ttl, .. }
+            | DnsRecord::MX { ttl, .. }
+            | DnsRecord::AAAA { ttl, .. }
+            | DnsRecord::SRV { ttl, .. }
+            | DnsRecord::PTR { ttl, .. }
+            | DnsRecord::CAA { ttl, .. }
+            | DnsRecord::TXT { ttl, .. }
+            | DnsRecord::DNAME { ttl, .. }
+            | DnsRecord::HINFO { ttl, .. }
+            | DnsRecord::SOA { ttl, .. }
+            | DnsRecord::LOC { ttl, .. }
+            | DnsRecord::NAPTR { ttl, .. }
+            | DnsRecord::DNSKEY { ttl, .. }
+            | DnsRecord::UNKNOWN { ttl, .. } => *ttl,
+            DnsRecord::OPT { .. } => 0,
+        }
+    }
+
+    // Setting the TTL of an OPT record does nothing, see `ttl`
+    pub fn set_ttl(&mut self, new_ttl: u32) {
+        match self {
+            DnsRecord::A { ttl, .. }
+            | DnsRecord::NS { ttl, .. }
+            | DnsRecord::CNAME { ttl, .. }
+            | DnsRecord::MX { ttl, .. }
+            | DnsRecord::AAAA { ttl, .. }
+            | DnsRecord::SRV { ttl, .. }
+            | DnsRecord::PTR { ttl, .. }
+            | DnsRecord::CAA { ttl, .. }
+            | DnsRecord::TXT { ttl, .. }
+            | DnsRecord::DNAME { ttl, .. }
+            | DnsRecord::HINFO { ttl, .. }
+            | DnsRecord::SOA { ttl, .. }
+            | DnsRecord::LOC { ttl, .. }
+            | DnsRecord::NAPTR { ttl, .. }
+            | DnsRecord::DNSKEY { ttl, .. }
+            | DnsRecord::UNKNOWN { ttl, .. } => *ttl = new_ttl,
+            DnsRecord::OPT { .. } => {}
+        }
+    }
+
+    pub fn query_type(&self) -> QueryType {
+        match self {
+            DnsRecord::A { .. } => QueryType::A,
+            DnsRecord::NS { .. } => QueryType::NS,
+            DnsRecord::CNAME { .. } => QueryType::CNAME,
+            DnsRecord::MX { .. } => QueryType::MX,
+            DnsRecord::AAAA { .. } => QueryType::AAAA,
+            DnsRecord::SRV { .. } => QueryType::SRV,
+            DnsRecord::PTR { .. } => QueryType::PTR,
+            DnsRecord::CAA { .. } => QueryType::CAA,
+            DnsRecord::TXT { .. } => QueryType::TXT,
+            DnsRecord::DNAME { .. } => QueryType::DNAME,
+            DnsRecord::HINFO { .. } => QueryType::HINFO,
+            DnsRecord::SOA { .. } => QueryType::SOA,
+            DnsRecord::LOC { .. } => QueryType::LOC,
+            DnsRecord::NAPTR { .. } => QueryType::NAPTR,
+            DnsRecord::DNSKEY { .. } => QueryType::DNSKEY,
+            DnsRecord::OPT { .. } => QueryType::OPT,
+            // Also holds the records of types we only know by name (e.g. DNSSEC ones)
+            DnsRecord::UNKNOWN { qtype, .. } => QueryType::from_num(*qtype),
+        }
+    }
+}
+
+impl DnsRecord {
+    // How many bytes the record takes up once written, see `DnsPacket::estimated_size`
+    pub fn estimated_size(&self) -> usize {
+        let rdata = match self {
+            DnsRecord::A { .. } => 4,
+            DnsRecord::AAAA { .. } => 16,
+            DnsRecord::NS { host, .. }
+            | DnsRecord::CNAME { host, .. }
+            | DnsRecord::PTR { host, .. } => name_size(host),
+            DnsRecord::DNAME { target, .. } => name_size(target),
+            DnsRecord::MX { host, .. } => 2 + name_size(host),
+            DnsRecord::SRV { target, .. } => 6 + name_size(target),
+            DnsRecord::CAA { tag, value, .. } => 2 + tag.len() + value.len(),
+            DnsRecord::TXT { data, .. } => data.iter().map(|text| 1 + text.len()).sum(),
+            DnsRecord::HINFO { cpu, os, .. } => 2 + cpu.len() + os.len(),
+            DnsRecord::SOA { m_name, r_name, .. } => name_size(m_name) + name_size(r_name) + 20,
+            DnsRecord::LOC { .. } => 16,
+            DnsRecord::NAPTR {
+                flags,
+                services,
+                regexp,
+                replacement,
+                ..
+            } => 7 + flags.len() + services.len() + regexp.len() + name_size(replacement),
+            DnsRecord::DNSKEY { public_key, .. } => 4 + public_key.len(),
+            DnsRecord::OPT { data, .. } | DnsRecord::UNKNOWN { data, .. } => data.len(),
+        };
+
+        // Type, class, TTL and RDATA length
+        name_size(self.domain()) + 10 + rdata
+    }
+}
+
+// The length octets of the labels plus the terminating zero, which is what the writer puts out
+// without compression. Internationalized names are taken by the length of their punycode form,
+// which is what gets written with the idna feature (and nothing does without it).
+fn name_size(name: &str) -> usize {
+    #[cfg(feature = "idna")]
+    if !name.is_ascii() {
+        if let Ok(ascii) = crate::idn::to_ascii(name) {
+            return ascii.len() + 2;
+        }
+    }
+
+    name.len() + 2
+}
+
+// A line of a zone file, the way dig prints records:
+//
+//     example.com.    300    IN    MX    10 mail.example.com.
+impl fmt::Display for DnsRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // OPT is not a real record, dig shows it as a pseudo section
+        if let DnsRecord::OPT {
+            udp_payload_size,
+            version,
+            flags,
+            ..
+        } = self
+        {
+            return write!(
+                f,
+                "; EDNS: version: {}, flags: {:#06x}; udp: {}",
+                version, flags, udp_payload_size
+            );
+        }
+
+        write!(
+            f,
+            "{}\t{}\tIN\t{}\t",
+            fqdn(self.domain()),
+            self.ttl(),
+            self.query_type()
+        )?;
+
+        match self {
+            DnsRecord::A { addr, .. } => write!(f, "{}", addr),
+            DnsRecord::AAAA { addr, .. } => write!(f, "{}", addr),
+            DnsRecord::NS { host, .. }
+            | DnsRecord::CNAME { host, .. }
+            | DnsRecord::PTR { host, .. } => write!(f, "{}", fqdn(host)),
+            DnsRecord::DNAME { target, .. } => write!(f, "{}", fqdn(target)),
+            DnsRecord::MX { priority, host, .. } => write!(f, "{} {}", priority, fqdn(host)),
+            DnsRecord::SRV {
+                priority,
+                weight,
+                port,
+                target,
+                ..
+            } => write!(f, "{} {} {} {}", priority, weight, port, fqdn(target)),
+            DnsRecord::CAA {
+                flags, tag, value, ..
+            } => write!(f, "{} {} {:?}", flags, tag, value),
+            DnsRecord::TXT { data, .. } => {
+                let quoted = data.iter().map(|text| format!("{:?}", text));
+                write!(f, "{}", quoted.collect::<Vec<_>>().join(" "))
+            }
+            DnsRecord::HINFO { cpu, os, .. } => write!(f, "{:?} {:?}", cpu, os),
+            DnsRecord::SOA {
+                m_name,
+                r_name,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                ..
+            } => write!(
+                f,
+                "{} {} {} {} {} {} {}",
+                fqdn(m_name),
+                fqdn(r_name),
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum
+            ),
+            DnsRecord::LOC {
+                size,
+                horiz_pre,
+                vert_pre,
+                latitude,
+                longitude,
+                altitude,
+                ..
+            } => {
+                let altitude = *altitude as i64 - LOC_ALTITUDE_BASE;
+                write!(
+                    f,
+                    "{} {} {}{}.{:02}m {} {} {}",
+                    loc_dms(*latitude, 'N', 'S'),
+                    loc_dms(*longitude, 'E', 'W'),
+                    if altitude < 0 { "-" } else { "" },
+                    altitude.abs() / 100,
+                    altitude.abs() % 100,
+                    loc_meters(*size),
+                    loc_meters(*horiz_pre),
+                    loc_meters(*vert_pre)
+                )
+            }
+            DnsRecord::NAPTR {
+                order,
+                preference,
+                flags,
+                services,
+                regexp,
+                replacement,
+                ..
+            } => write!(
+                f,
+                "{} {} {:?} {:?} {:?} {}",
+                order,
+                preference,
+                flags,
+                services,
+                regexp,
+                fqdn(replacement)
+            ),
+            DnsRecord::DNSKEY {
+                flags,
+                protocol,
+                algorithm,
+                public_key,
+                ..
+            } => write!(
+                f,
+                "{} {} {} {}",
+                flags,
+                protocol,
+                algorithm,
+                base64(public_key)
+            ),
+            // The generic encoding of RFC 3597
+            DnsRecord::UNKNOWN { data, .. } => {
+                write!(f, "\\# {}", data.len())?;
+                if !data.is_empty() {
+                    write!(f, " ")?;
+                }
+                data.iter().try_for_each(|b| write!(f, "{:02x}", b))
+            }
+            // Taken care of above
+            DnsRecord::OPT { .. } => Ok(()),
+        }
+    }
+}
+
+// Names are kept without the trailing dot of the root
+fn fqdn(name: &str) -> String {
+    format!("{}.", name)
+}
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+// Keys are shown in Base64 (RFC 4648 section 4) in the master file format, padding included
+fn base64(data: &[u8]) -> String {
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let bits = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                let index = (bits >> (18 - 6 * i)) & 0x3F;
+                encoded.push(BASE64_ALPHABET[index as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+
+    encoded
+}
+
+const LOC_EQUATOR: i64 = 1 << 31;
+const LOC_ALTITUDE_BASE: i64 = 10_000_000;
+
+// Latitude or longitude of a LOC record in decimal degrees, negative to the south and west
+pub fn loc_to_degrees(packed: u32) -> f64 {
+    (packed as i64 - LOC_EQUATOR) as f64 / 3_600_000.0
+}
+
+// Size and precisions of a LOC record are packed as a mantissa (high nibble) and a power of ten
+// (low nibble), e.g. 0x13 is 1 * 10^3cm = 10m
+pub fn loc_precision_to_cm(packed: u8) -> u64 {
+    (packed >> 4) as u64 * 10u64.pow((packed & 0x0F).min(9) as u32)
+}
+
+// The degrees, minutes and seconds of the master file format (RFC 1876 section 3)
+fn loc_dms(packed: u32, positive: char, negative: char) -> String {
+    let offset = packed as i64 - LOC_EQUATOR;
+    let hemisphere = if offset < 0 { negative } else { positive };
+    let millis = offset.abs();
+
+    format!(
+        "{} {} {}.{:03} {}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000,
+        hemisphere
+    )
+}
+
+fn loc_meters(packed: u8) -> String {
+    let cm = loc_precision_to_cm(packed);
+    match cm % 100 {
+        0 => format!("{}m", cm / 100),
+        rest => format!("{}.{:02}m", cm / 100, rest),
+    }
+}
+
 impl DnsPacket {
+    // A standard query for a single question, asking the server to recurse on our behalf
+    pub fn query(id: u16, qname: &str, qtype: QueryType) -> DnsPacket {
+        DnsPacket::query_with_flags(id, qname, qtype, QueryFlags::default())
+    }
+
+    // Same as `query` but with the CD and AD bits set as given
+    pub fn query_with_flags(
+        id: u16,
+        qname: &str,
+        qtype: QueryType,
+        flags: QueryFlags,
+    ) -> DnsPacket {
+        let header = DnsHeader::builder()
+            .id(id)
+            .questions(1)
+            .recursion_desired(true)
+            .checking_disabled(flags.checking_disabled)
+            .authed_data(flags.authed_data)
+            .build();
+
+        let question = DnsQuestion::builder()
+            .name(qname.to_string())
+            .qtype(qtype)
+            .build();
+
+        DnsPacket::builder()
+            .header(header)
+            .questions(vec![question])
+            .build()
+    }
+
+    // The name asked about in `request` does not exist
+    pub fn nxdomain(request: &DnsPacket) -> DnsPacket {
+        DnsPacket::error_response(request, ResponseCode::NXDOMAIN)
+    }
+
+    // The server was unable to come up with an answer to `request`
+    pub fn servfail(request: &DnsPacket) -> DnsPacket {
+        DnsPacket::error_response(request, ResponseCode::SERVFAIL)
+    }
+
+    // The server was unable to make sense of `request`
+    pub fn formerr(request: &DnsPacket) -> DnsPacket {
+        DnsPacket::error_response(request, ResponseCode::FORMERR)
+    }
+
+    // The server does not speak the EDNS version of `request`. The code only fits into the header
+    // together with an OPT record (see `DnsPacket::rescode`), which it is up to the caller to add.
+    pub fn badvers(request: &DnsPacket) -> DnsPacket {
+        DnsPacket::error_response(request, ResponseCode::BADVERS)
+    }
+
+    // A response without any records, echoing the id, opcode, RD bit and questions of the request
+    // (RFC 1035 section 4.1.1)
+    fn error_response(request: &DnsPacket, rescode: ResponseCode) -> DnsPacket {
+        let header = DnsHeader::builder()
+            .id(request.header.id)
+            .response(true)
+            .opcode(request.header.opcode)
+            .recursion_desired(request.header.recursion_desired)
+            .rescode(rescode)
+            .questions(request.questions.len() as u16)
+            .build();
+
+        DnsPacket::builder()
+            .header(header)
+            .questions(request.questions.clone())
+            .build()
+    }
+
     pub fn first_question(&self) -> Option<&DnsQuestion> {
-        self.questions.get(0)
+        self.questions.first()
+    }
+
+    pub fn is_successful(&self) -> bool {
+        self.rescode() == ResponseCode::NOERROR
     }
 
-    pub fn is_valid(&self) -> bool {
-        self.header.rescode != ResponseCode::NOERROR
+    pub fn is_response(&self) -> bool {
+        self.header.response
+    }
+
+    pub fn is_query(&self) -> bool {
+        !self.header.response
     }
 
     pub fn has_answers(&self) -> bool {
         !self.answers.is_empty()
     }
 
+    // The code of the header along with the upper bits kept in the OPT record (if any), so that
+    // e.g. a BADVERS (16) does not pass for a NOERROR (0)
     pub fn rescode(&self) -> ResponseCode {
-        self.header.rescode
+        let ext_rcode = self.resources.iter().find_map(|r| match r {
+            DnsRecord::OPT { ext_rcode, .. } => Some(*ext_rcode),
+            _ => None,
+        });
+
+        match ext_rcode {
+            Some(ext_rcode) if ext_rcode > 0 => ResponseCode::from_num(
+                ((ext_rcode as u16) << 4) | (self.header.rescode.to_num() & 0x0F),
+            ),
+            _ => self.header.rescode,
+        }
+    }
+
+    // The name server answered the question (possibly with a CNAME to follow)
+    pub fn is_answer(&self) -> bool {
+        self.is_successful() && self.has_answers()
+    }
+
+    // The name server does not know the answer but tells us which ones to ask next, the name
+    // servers of a zone closer to the name (RFC 1034 section 4.3.2)
+    pub fn is_referral(&self) -> bool {
+        self.is_successful()
+            && !self.has_answers()
+            && !self.is_nodata()
+            && self
+                .authorities
+                .iter()
+                .any(|record| record.query_type() == QueryType::NS)
+    }
+
+    // The name does not exist at all, whatever the type (RFC 2308 section 2.1)
+    pub fn is_nxdomain(&self) -> bool {
+        self.rescode() == ResponseCode::NXDOMAIN
+    }
+
+    // The name exists but has no records of the asked for type (RFC 2308 section 2.2), which
+    // is told apart from a referral by the SOA record in the authority section
+    pub fn is_nodata(&self) -> bool {
+        self.is_successful()
+            && !self.has_answers()
+            && self
+                .authorities
+                .iter()
+                .any(|record| record.query_type() == QueryType::SOA)
     }
 
     pub fn qtype(&self) -> Option<QueryType> {
         self.first_question().map(|q| q.qtype)
     }
 
+    // The name and type of every question, unlike `qname` and `qtype` which only look at the first
+    pub fn questions_iter(&self) -> impl Iterator<Item = (&str, QueryType)> {
+        self.questions
+            .iter()
+            .map(|question| (question.name.as_str(), question.qtype))
+    }
+
     pub fn qname(&self) -> Option<String> {
         self.first_question().map(|q| q.name.clone())
     }
 
-    pub fn get_random_a(&self) -> Option<Ipv4Addr> {
-        self.answers.iter().find_map(|r| match r {
-            DnsRecord::A { addr, .. } => Some(*addr),
+    // The EDNS version the sender speaks, None when it does not speak EDNS at all
+    pub fn edns_version(&self) -> Option<u8> {
+        self.resources.iter().find_map(|r| match r {
+            DnsRecord::OPT { version, .. } => Some(*version),
             _ => None,
         })
     }
 
-    fn get_ns(&self) -> impl Iterator<Item = (&str, &str)> {
+    // Whether the sender wants DNSSEC records, signalled with the DO bit of its OPT record
+    pub fn dnssec_ok(&self) -> bool {
+        self.resources.iter().any(|r| match r {
+            DnsRecord::OPT { flags, .. } => flags & EDNS_DO_FLAG != 0,
+            _ => false,
+        })
+    }
+
+    // The size of the packet once written, without writing it. Never less than what is actually
+    // written, as names are counted uncompressed.
+    pub fn estimated_size(&self) -> usize {
+        let questions = self
+            .questions
+            .iter()
+            .map(|question| name_size(&question.name) + 4)
+            .sum::<usize>();
+        let records = self
+            .all_records()
+            .map(DnsRecord::estimated_size)
+            .sum::<usize>();
+
+        DNS_HEADER_SIZE + questions + records
+    }
+
+    // How long the packet as a whole can be kept for, which is as long as its shortest lived
+    // record. OPT records have no TTL of their own and are left out.
+    pub fn min_ttl(&self) -> Option<u32> {
+        self.all_records()
+            .filter(|record| record.query_type() != QueryType::OPT)
+            .map(DnsRecord::ttl)
+            .min()
+    }
+
+    pub fn udp_payload_size(&self) -> usize {
+        self.resources
+            .iter()
+            .find_map(|r| match r {
+                DnsRecord::OPT {
+                    udp_payload_size, ..
+                } => Some(*udp_payload_size as usize),
+                _ => None,
+            })
+            .map_or(DEFAULT_UDP_PAYLOAD_SIZE, |size| {
+                size.max(DEFAULT_UDP_PAYLOAD_SIZE)
+            })
+    }
+
+    // Records of every section, in the order they appear in the packet
+    pub fn all_records(&self) -> impl Iterator<Item = &DnsRecord> {
+        self.answers
+            .iter()
+            .chain(self.authorities.iter())
+            .chain(self.resources.iter())
+    }
+
+    pub fn records_of_type(&self, qtype: QueryType) -> impl Iterator<Item = &DnsRecord> {
+        self.all_records()
+            .filter(move |record| record.query_type() == qtype)
+    }
+
+    // Following CNAME chains or stitching together cached responses tends to pile up the same
+    // records more than once. Drops the repeats of each section, keeping the first occurrence
+    // where it was.
+    pub fn dedup_records(&mut self) {
+        dedup_section(&mut self.answers);
+        dedup_section(&mut self.authorities);
+        dedup_section(&mut self.resources);
+
+        self.header.answers = self.answers.len() as u16;
+        self.header.authoritative_entries = self.authorities.len() as u16;
+        self.header.resource_entries = self.resources.len() as u16;
+    }
+
+    // Adding records one at a time keeps the header counts in line, unlike pushing onto the
+    // sections directly
+    pub fn push_answer(&mut self, record: DnsRecord) {
+        self.answers.push(record);
+        self.header.answers += 1;
+    }
+
+    pub fn push_authority(&mut self, record: DnsRecord) {
+        self.authorities.push(record);
+        self.header.authoritative_entries += 1;
+    }
+
+    pub fn push_resource(&mut self, record: DnsRecord) {
+        self.resources.push(record);
+        self.header.resource_entries += 1;
+    }
+
+    // Adds the records of `other` to those of the same section, e.g. the answers for the target of
+    // a CNAME to the CNAME itself, leaving out those already there. The OPT record of `other` is
+    // not carried over, a packet has at most one.
+    pub fn merge_answers(&mut self, other: &DnsPacket) {
+        self.answers.extend(other.answers.iter().cloned());
+        self.authorities.extend(other.authorities.iter().cloned());
+        self.resources.extend(
+            other
+                .resources
+                .iter()
+                .filter(|record| record.query_type() != QueryType::OPT)
+                .cloned(),
+        );
+
+        self.dedup_records();
+    }
+
+    pub fn get_all_a(&self) -> Vec<Ipv4Addr> {
+        self.answers
+            .iter()
+            .filter_map(|r| match r {
+                DnsRecord::A { addr, .. } => Some(*addr),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn get_all_aaaa(&self) -> Vec<Ipv6Addr> {
+        self.answers
+            .iter()
+            .filter_map(|r| match r {
+                DnsRecord::AAAA { addr, .. } => Some(*addr),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // Picking one at random spreads the load over all the addresses of a name, and keeps us from
+    // always running into the same one should it be down
+    pub fn get_random_a(&self) -> Option<Ipv4Addr> {
+        self.get_all_a().choose(&mut rand::thread_rng()).copied()
+    }
+
+    pub fn get_random_aaaa(&self) -> Option<Ipv6Addr> {
+        self.get_all_aaaa().choose(&mut rand::thread_rng()).copied()
+    }
+
+    // The (zone, name server) pairs of the NS records in the authority section, i.e. where a
+    // referral points to
+    pub fn nameservers(&self) -> impl Iterator<Item = (&str, &str)> {
         self.authorities.iter().filter_map(|record| match record {
             DnsRecord::NS { domain, host, .. } => Some((domain.as_str(), host.as_str())),
             _ => None,
         })
     }
 
-    fn get_ns_for<'a>(&'a self, qname: &'a str) -> impl Iterator<Item = (&'a str, &'a str)> {
-        self.get_ns()
-            .filter(move |(domain, _)| qname.ends_with(*domain))
+    // The `nameservers` of zones `qname` is in. Names are compared case-insensitively (RFC 4343),
+    // some servers mix up the case of labels.
+    pub fn delegations_for<'a>(
+        &'a self,
+        qname: &'a str,
+    ) -> impl Iterator<Item = (&'a str, &'a str)> {
+        let qname = qname.to_ascii_lowercase();
+        self.nameservers()
+            .filter(move |(domain, _)| qname.ends_with(&domain.to_ascii_lowercase()))
     }
 
-    pub fn get_resolved_ns(&self, qname: &str) -> Option<Ipv4Addr> {
-        self.get_ns_for(qname)
-            .flat_map(|(_, host)| {
-                self.resources
-                    .iter()
-                    .filter_map(move |record| match record {
-                        DnsRecord::A { domain, addr, .. } if domain == host => Some(addr),
-                        _ => None,
-                    })
+    // The addresses of `host` that came along in the additional section, sparing a lookup of the
+    // targets of NS, MX or SRV records
+    pub fn glue_for(&self, host: &str) -> Vec<IpAddr> {
+        self.resources
+            .iter()
+            .filter_map(|record| match record {
+                DnsRecord::A { domain, addr, .. } if domain.eq_ignore_ascii_case(host) => {
+                    Some(IpAddr::V4(*addr))
+                }
+                DnsRecord::AAAA { domain, addr, .. } if domain.eq_ignore_ascii_case(host) => {
+                    Some(IpAddr::V6(*addr))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    // The addresses of the name servers that came with glue, in the order they appear
+    pub fn get_all_resolved_ns(&self, qname: &str) -> Vec<Ipv4Addr> {
+        self.delegations_for(qname)
+            .flat_map(|(_, host)| self.glue_for(host))
+            .filter_map(|addr| match addr {
+                IpAddr::V4(addr) => Some(addr),
+                IpAddr::V6(_) => None,
+            })
+            .collect()
+    }
+
+    pub fn get_all_resolved_ns_v6(&self, qname: &str) -> Vec<Ipv6Addr> {
+        self.delegations_for(qname)
+            .flat_map(|(_, host)| self.glue_for(host))
+            .filter_map(|addr| match addr {
+                IpAddr::V4(_) => None,
+                IpAddr::V6(addr) => Some(addr),
             })
-            .cloned()
-            .next()
+            .collect()
+    }
+
+    // The address of a random one of the name servers that came with glue
+    pub fn get_resolved_ns(&self, qname: &str) -> Option<Ipv4Addr> {
+        self.get_all_resolved_ns(qname)
+            .choose(&mut rand::thread_rng())
+            .copied()
+    }
+
+    pub fn get_resolved_ns_v6(&self, qname: &str) -> Option<Ipv6Addr> {
+        self.get_all_resolved_ns_v6(qname)
+            .choose(&mut rand::thread_rng())
+            .copied()
     }
 
     pub fn get_unresolved_ns<'a>(&'a self, qname: &'a str) -> Option<&'a str> {
-        self.get_ns_for(qname).map(|(_, host)| host).next()
+        self.delegations_for(qname).map(|(_, host)| host).next()
+    }
+
+    pub fn get_all_unresolved_ns<'a>(&'a self, qname: &'a str) -> Vec<&'a str> {
+        self.delegations_for(qname).map(|(_, host)| host).collect()
     }
+
+    // What a referral for `qname` amounts to, for showing it to a human. Should the authority
+    // section hold NS records of several zones `qname` is in, the closest one is taken.
+    pub fn delegation_info(&self, qname: &str) -> Option<DelegationInfo> {
+        let zone = self
+            .delegations_for(qname)
+            .map(|(zone, _)| zone)
+            .max_by_key(|zone| zone.len())?;
+        let nameservers = self
+            .delegations_for(qname)
+            .filter(|(domain, _)| domain.eq_ignore_ascii_case(zone))
+            .map(|(_, host)| host.to_string())
+            .collect::<Vec<_>>();
+        let glued = nameservers
+            .iter()
+            .flat_map(|host| {
+                self.glue_for(host)
+                    .into_iter()
+                    .map(move |addr| (host.clone(), addr))
+            })
+            .collect();
+
+        Some(DelegationInfo {
+            zone: zone.to_string(),
+            nameservers,
+            glued,
+        })
+    }
+}
+
+// A referral, see `DnsPacket::delegation_info`. Name servers that are missing from `glued` have to
+// be resolved before they can be asked, which is where lame delegations tend to hide.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DelegationInfo {
+    pub zone: String,
+    pub nameservers: Vec<String>,
+    // The addresses of the name servers that came along in the additional section
+    pub glued: Vec<(String, IpAddr)>,
+}
+
+fn dedup_section(records: &mut Vec<DnsRecord>) {
+    let mut seen = HashSet::new();
+    records.retain(|record| seen.insert(record.clone()));
 }
 
 #[cfg(test)]
@@ -355,13 +1515,714 @@ mod test {
     use super::*;
     use pretty_assertions::assert_eq;
 
+    #[test]
+    fn query_type_display_works() {
+        assert_eq!(QueryType::A.to_string(), "A");
+        assert_eq!(QueryType::AAAA.to_string(), "AAAA");
+        assert_eq!(QueryType::MX.to_string(), "MX");
+        assert_eq!(QueryType::UNKNOWN(65).to_string(), "TYPE65");
+    }
+
+    #[test]
+    fn query_type_from_str_works() {
+        assert_eq!("AAAA".parse(), Ok(QueryType::AAAA));
+        assert_eq!("TYPE65".parse(), Ok(QueryType::UNKNOWN(65)));
+        assert_eq!("TYPE1".parse(), Ok(QueryType::A));
+        assert!("BOGUS".parse::<QueryType>().is_err());
+    }
+
+    #[test]
+    fn query_type_classification_works() {
+        let types = (0..=300).map(QueryType::from_num).collect::<Vec<_>>();
+
+        let addresses = types
+            .iter()
+            .copied()
+            .filter(|qtype| qtype.is_address())
+            .collect::<Vec<_>>();
+        let name_data = types
+            .iter()
+            .copied()
+            .filter(|qtype| qtype.is_name_data())
+            .collect::<Vec<_>>();
+
+        assert_eq!(addresses, vec![QueryType::A, QueryType::AAAA]);
+        assert_eq!(
+            name_data,
+            vec![
+                QueryType::NS,
+                QueryType::CNAME,
+                QueryType::PTR,
+                QueryType::MX
+            ]
+        );
+    }
+
+    #[test]
+    fn question_builder_defaults_to_in() {
+        let question = DnsQuestion::builder()
+            .name("example.com".to_string())
+            .qtype(QueryType::TXT)
+            .build();
+        let chaos = DnsQuestion::builder()
+            .name("version.bind".to_string())
+            .qtype(QueryType::TXT)
+            .class(DnsClass::CH)
+            .build();
+
+        assert_eq!(question.name, "example.com");
+        assert_eq!(question.qtype, QueryType::TXT);
+        assert_eq!(question.class, DnsClass::IN);
+        assert_eq!(chaos.class, DnsClass::CH);
+    }
+
+    #[test]
+    fn class_names_work() {
+        assert_eq!(DnsClass::CH.to_string(), "CH");
+        assert_eq!(DnsClass::UNKNOWN(42).to_string(), "CLASS42");
+        assert_eq!("IN".parse(), Ok(DnsClass::IN));
+        assert_eq!("CLASS3".parse(), Ok(DnsClass::CH));
+        assert!("BOGUS".parse::<DnsClass>().is_err());
+    }
+
+    #[test]
+    fn response_code_names_work() {
+        assert_eq!(ResponseCode::NXDOMAIN.to_string(), "NXDOMAIN");
+        assert_eq!(ResponseCode::UNKNOWN(11).to_string(), "RCODE11");
+        assert_eq!("NOTZONE".parse(), Ok(ResponseCode::NOTZONE));
+        assert_eq!("RCODE11".parse(), Ok(ResponseCode::UNKNOWN(11)));
+        assert!("BOGUS".parse::<ResponseCode>().is_err());
+    }
+
+    #[test]
+    fn record_display_works() {
+        let a = DnsRecord::A {
+            domain: "example.com".to_string(),
+            addr: Ipv4Addr::new(93, 184, 216, 34),
+            ttl: 300,
+        };
+        let mx = DnsRecord::MX {
+            domain: "example.com".to_string(),
+            priority: 10,
+            host: "mail.example.com".to_string(),
+            ttl: 3600,
+        };
+        let txt = DnsRecord::TXT {
+            domain: "example.com".to_string(),
+            data: vec!["v=spf1 -all".to_string(), "hello".to_string()],
+            ttl: 3600,
+        };
+        let soa = DnsRecord::SOA {
+            domain: "example.com".to_string(),
+            m_name: "ns.icann.org".to_string(),
+            r_name: "noc.dns.icann.org".to_string(),
+            serial: 2024081400,
+            refresh: 7200,
+            retry: 3600,
+            expire: 1209600,
+            minimum: 3600,
+            ttl: 3600,
+        };
+        let unknown = DnsRecord::UNKNOWN {
+            domain: "example.com".to_string(),
+            qtype: 65,
+            data: vec![0x00, 0x01, 0x00],
+            ttl: 3600,
+        };
+
+        assert_eq!(a.to_string(), "example.com.\t300\tIN\tA\t93.184.216.34");
+        assert_eq!(
+            mx.to_string(),
+            "example.com.\t3600\tIN\tMX\t10 mail.example.com."
+        );
+        assert_eq!(
+            txt.to_string(),
+            "example.com.\t3600\tIN\tTXT\t\"v=spf1 -all\" \"hello\""
+        );
+        assert_eq!(
+            soa.to_string(),
+            "example.com.\t3600\tIN\tSOA\tns.icann.org. noc.dns.icann.org. 2024081400 7200 3600 1209600 3600"
+        );
+        assert_eq!(
+            unknown.to_string(),
+            "example.com.\t3600\tIN\tTYPE65\t\\# 3 000100"
+        );
+    }
+
+    #[test]
+    fn dnskey_display_works() {
+        let dnskey = DnsRecord::DNSKEY {
+            domain: "example.com".to_string(),
+            flags: 256,
+            protocol: 3,
+            algorithm: 13,
+            public_key: b"hello".to_vec(),
+            ttl: 3600,
+        };
+
+        assert_eq!(
+            dnskey.to_string(),
+            "example.com.\t3600\tIN\tDNSKEY\t256 3 13 aGVsbG8="
+        );
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"hell"), "aGVsbA==");
+        assert_eq!(base64(b"hel"), "aGVs");
+    }
+
+    #[test]
+    fn loc_display_works() {
+        // cambridge-net.kei.com from RFC 1876
+        let loc = DnsRecord::LOC {
+            domain: "cambridge-net.kei.com".to_string(),
+            version: 0,
+            size: 0x33,
+            horiz_pre: 0x16,
+            vert_pre: 0x13,
+            latitude: 2299997648,
+            longitude: 1891505648,
+            altitude: 9997600,
+            ttl: 3600,
+        };
+
+        assert_eq!(
+            loc.to_string(),
+            "cambridge-net.kei.com.\t3600\tIN\tLOC\t42 21 54.000 N 71 6 18.000 W -24.00m 30m 10000m 10m"
+        );
+        assert!((loc_to_degrees(2299997648) - 42.365).abs() < 1e-9);
+        assert!((loc_to_degrees(1891505648) + 71.105).abs() < 1e-9);
+        assert_eq!(loc_precision_to_cm(0x12), 100);
+    }
+
+    #[test]
+    fn ttl_accessors_work() {
+        let domain = "example.com".to_string();
+        let mut records = vec![
+            DnsRecord::A {
+                domain: domain.clone(),
+                addr: Ipv4Addr::new(1, 2, 3, 4),
+                ttl: 300,
+            },
+            DnsRecord::NS {
+                domain: domain.clone(),
+                host: "ns.example.com".to_string(),
+                ttl: 300,
+            },
+            DnsRecord::CNAME {
+                domain: domain.clone(),
+                host: "www.example.com".to_string(),
+                ttl: 300,
+            },
+            DnsRecord::MX {
+                domain: domain.clone(),
+                priority: 10,
+                host: "mail.example.com".to_string(),
+                ttl: 300,
+            },
+            DnsRecord::AAAA {
+                domain: domain.clone(),
+                addr: Ipv6Addr::LOCALHOST,
+                ttl: 300,
+            },
+            DnsRecord::SRV {
+                domain: domain.clone(),
+                priority: 10,
+                weight: 60,
+                port: 5060,
+                target: "sip.example.com".to_string(),
+                ttl: 300,
+            },
+            DnsRecord::PTR {
+                domain: domain.clone(),
+                host: "host.example.com".to_string(),
+                ttl: 300,
+            },
+            DnsRecord::CAA {
+                domain: domain.clone(),
+                flags: 0,
+                tag: "issue".to_string(),
+                value: "letsencrypt.org".to_string(),
+                ttl: 300,
+            },
+            DnsRecord::TXT {
+                domain: domain.clone(),
+                data: vec!["hello".to_string()],
+                ttl: 300,
+            },
+            DnsRecord::DNAME {
+                domain: domain.clone(),
+                target: "example.org".to_string(),
+                ttl: 300,
+            },
+            DnsRecord::HINFO {
+                domain: domain.clone(),
+                cpu: "RFC8482".to_string(),
+                os: "".to_string(),
+                ttl: 300,
+            },
+            DnsRecord::SOA {
+                domain: domain.clone(),
+                m_name: "ns.example.com".to_string(),
+                r_name: "hostmaster.example.com".to_string(),
+                serial: 1,
+                refresh: 7200,
+                retry: 3600,
+                expire: 1209600,
+                minimum: 300,
+                ttl: 300,
+            },
+            DnsRecord::LOC {
+                domain: domain.clone(),
+                version: 0,
+                size: 0x12,
+                horiz_pre: 0x16,
+                vert_pre: 0x13,
+                latitude: 1 << 31,
+                longitude: 1 << 31,
+                altitude: 10_000_000,
+                ttl: 300,
+            },
+            DnsRecord::NAPTR {
+                domain: domain.clone(),
+                order: 100,
+                preference: 10,
+                flags: "S".to_string(),
+                services: "SIP+D2U".to_string(),
+                regexp: "".to_string(),
+                replacement: "_sip._udp.example.com".to_string(),
+                ttl: 300,
+            },
+            DnsRecord::DNSKEY {
+                domain: domain.clone(),
+                flags: 257,
+                protocol: 3,
+                algorithm: 13,
+                public_key: vec![1, 2, 3, 4],
+                ttl: 300,
+            },
+            DnsRecord::UNKNOWN {
+                domain,
+                qtype: 99,
+                data: vec![],
+                ttl: 300,
+            },
+        ];
+
+        for record in records.iter_mut() {
+            assert_eq!(record.ttl(), 300);
+            record.set_ttl(42);
+            assert_eq!(record.ttl(), 42);
+        }
+
+        let mut opt = DnsRecord::OPT {
+            udp_payload_size: 4096,
+            ext_rcode: 0,
+            version: 0,
+            flags: 0,
+            data: vec![],
+        };
+        opt.set_ttl(42);
+        assert_eq!(opt.ttl(), 0);
+    }
+
+    #[test]
+    fn response_code_round_trip_works() {
+        let codes = vec![
+            (6, ResponseCode::YXDOMAIN),
+            (7, ResponseCode::YXRRSET),
+            (8, ResponseCode::NXRRSET),
+            (9, ResponseCode::NOTAUTH),
+            (10, ResponseCode::NOTZONE),
+            (11, ResponseCode::UNKNOWN(11)),
+            (16, ResponseCode::BADVERS),
+            (4095, ResponseCode::UNKNOWN(4095)),
+        ];
+
+        for (num, code) in codes {
+            assert_eq!(ResponseCode::from_num(num), code);
+            assert_eq!(code.to_num(), num);
+        }
+    }
+
+    #[test]
+    fn flags_carry_extended_response_codes() {
+        let header = DnsHeader::builder()
+            .id(1)
+            .response(true)
+            .rescode(ResponseCode::NOTZONE)
+            .build();
+
+        assert_eq!(header.flags(), 0x800a);
+    }
+
+    #[test]
+    fn rescode_includes_extended_bits() {
+        let header = DnsHeader::builder().id(1).response(true).build();
+        let opt = DnsRecord::OPT {
+            udp_payload_size: 1232,
+            ext_rcode: 1,
+            version: 0,
+            flags: 0,
+            data: vec![],
+        };
+        let plain = DnsPacket::builder().header(header.clone()).build();
+        let badvers = DnsPacket::builder()
+            .header(header)
+            .resources(vec![opt])
+            .build();
+
+        assert_eq!(plain.rescode(), ResponseCode::NOERROR);
+        assert!(plain.is_successful());
+        assert_eq!(badvers.rescode(), ResponseCode::BADVERS);
+        assert!(!badvers.is_successful());
+    }
+
+    #[test]
+    fn get_random_a_picks_from_all_addresses() {
+        let header = DnsHeader::builder().id(10).answers(4).build();
+        let addrs = (1..=4)
+            .map(|host| Ipv4Addr::new(192, 0, 2, host))
+            .collect::<Vec<_>>();
+        let answers = addrs
+            .iter()
+            .map(|addr| DnsRecord::A {
+                domain: "example.com".to_string(),
+                addr: *addr,
+                ttl: 300,
+            })
+            .collect();
+        let pack = DnsPacket::builder().header(header).answers(answers).build();
+
+        let picked = (0..100)
+            .filter_map(|_| pack.get_random_a())
+            .collect::<HashSet<_>>();
+
+        assert_eq!(pack.get_all_a(), addrs);
+        assert!(picked.len() > 1);
+        assert!(picked.iter().all(|addr| addrs.contains(addr)));
+    }
+
+    #[test]
+    fn dedup_records_works() {
+        let a = |host, ttl| DnsRecord::A {
+            domain: "example.com".to_string(),
+            addr: Ipv4Addr::new(192, 0, 2, host),
+            ttl,
+        };
+        let ns = DnsRecord::NS {
+            domain: "example.com".to_string(),
+            host: "ns1.example.com".to_string(),
+            ttl: 3600,
+        };
+        let header = DnsHeader::builder()
+            .id(10)
+            .answers(5)
+            .authoritative_entries(2)
+            .build();
+        let mut pack = DnsPacket::builder()
+            .header(header)
+            .answers(vec![a(2, 300), a(1, 300), a(2, 300), a(1, 60), a(1, 300)])
+            .authorities(vec![ns.clone(), ns.clone()])
+            .build();
+
+        pack.dedup_records();
+
+        assert_eq!(pack.answers, vec![a(2, 300), a(1, 300), a(1, 60)]);
+        assert_eq!(pack.authorities, vec![ns]);
+        assert_eq!(pack.header.answers, 3);
+        assert_eq!(pack.header.authoritative_entries, 1);
+        assert_eq!(pack.header.resource_entries, 0);
+    }
+
+    #[test]
+    fn push_works() {
+        let a = DnsRecord::A {
+            domain: "example.com".to_string(),
+            addr: Ipv4Addr::new(192, 0, 2, 1),
+            ttl: 300,
+        };
+        let ns = DnsRecord::NS {
+            domain: "example.com".to_string(),
+            host: "ns1.example.com".to_string(),
+            ttl: 3600,
+        };
+        let glue = DnsRecord::A {
+            domain: "ns1.example.com".to_string(),
+            addr: Ipv4Addr::new(192, 0, 2, 53),
+            ttl: 3600,
+        };
+        let header = DnsHeader::builder()
+            .id(10)
+            .answers(2)
+            .authoritative_entries(1)
+            .resource_entries(1)
+            .build();
+        let built = DnsPacket::builder()
+            .header(header)
+            .answers(vec![a.clone(), a.clone()])
+            .authorities(vec![ns.clone()])
+            .resources(vec![glue.clone()])
+            .build();
+
+        let mut pushed = DnsPacket::builder()
+            .header(DnsHeader::builder().id(10).build())
+            .build();
+        pushed.push_answer(a.clone());
+        pushed.push_answer(a);
+        pushed.push_authority(ns);
+        pushed.push_resource(glue);
+
+        assert_eq!(pushed, built);
+    }
+
+    #[test]
+    fn merge_answers_works() {
+        let cname = DnsRecord::CNAME {
+            domain: "www.example.com".to_string(),
+            host: "example.com".to_string(),
+            ttl: 300,
+        };
+        let a = DnsRecord::A {
+            domain: "example.com".to_string(),
+            addr: Ipv4Addr::new(192, 0, 2, 1),
+            ttl: 300,
+        };
+        let ns = DnsRecord::NS {
+            domain: "example.com".to_string(),
+            host: "ns1.example.com".to_string(),
+            ttl: 3600,
+        };
+        let opt = |udp_payload_size| DnsRecord::OPT {
+            udp_payload_size,
+            ext_rcode: 0,
+            version: 0,
+            flags: 0,
+            data: vec![],
+        };
+        let mut pack = DnsPacket::query(10, "www.example.com", QueryType::A);
+        pack.answers = vec![cname.clone()];
+        pack.authorities = vec![ns.clone()];
+        pack.resources = vec![opt(1232)];
+        let mut other = DnsPacket::query(11, "example.com", QueryType::A);
+        other.answers = vec![a.clone(), a.clone()];
+        other.authorities = vec![ns.clone()];
+        other.resources = vec![opt(4096)];
+
+        pack.merge_answers(&other);
+
+        assert_eq!(pack.answers, vec![cname, a]);
+        assert_eq!(pack.authorities, vec![ns]);
+        assert_eq!(pack.resources, vec![opt(1232)]);
+        assert_eq!(pack.header.answers, 2);
+        assert_eq!(pack.header.authoritative_entries, 1);
+        assert_eq!(pack.header.resource_entries, 1);
+    }
+
+    #[test]
+    fn min_ttl_works() {
+        let a = |ttl| DnsRecord::A {
+            domain: "example.com".to_string(),
+            addr: Ipv4Addr::new(192, 0, 2, 1),
+            ttl,
+        };
+        let ns = DnsRecord::NS {
+            domain: "example.com".to_string(),
+            host: "ns1.example.com".to_string(),
+            ttl: 120,
+        };
+        let opt = DnsRecord::OPT {
+            udp_payload_size: 1232,
+            ext_rcode: 0,
+            version: 0,
+            flags: 0,
+            data: vec![],
+        };
+        let header = DnsHeader::builder()
+            .id(10)
+            .answers(2)
+            .authoritative_entries(1)
+            .resource_entries(2)
+            .build();
+        let pack = DnsPacket::builder()
+            .header(header)
+            .answers(vec![a(300), a(3600)])
+            .authorities(vec![ns])
+            .resources(vec![a(600), opt.clone()])
+            .build();
+        let only_opt = DnsPacket::builder()
+            .header(DnsHeader::builder().id(11).resource_entries(1).build())
+            .resources(vec![opt])
+            .build();
+
+        assert_eq!(pack.min_ttl(), Some(120));
+        assert_eq!(only_opt.min_ttl(), None);
+        assert_eq!(
+            DnsPacket::builder()
+                .header(DnsHeader::builder().id(12).build())
+                .build()
+                .min_ttl(),
+            None
+        );
+    }
+
+    #[test]
+    fn responses_are_classified() {
+        let a = DnsRecord::A {
+            domain: "www.example.com".to_string(),
+            addr: Ipv4Addr::new(192, 0, 2, 1),
+            ttl: 300,
+        };
+        let ns = DnsRecord::NS {
+            domain: "example.com".to_string(),
+            host: "ns1.example.com".to_string(),
+            ttl: 3600,
+        };
+        let soa = DnsRecord::SOA {
+            domain: "example.com".to_string(),
+            m_name: "ns1.example.com".to_string(),
+            r_name: "hostmaster.example.com".to_string(),
+            serial: 1,
+            refresh: 7200,
+            retry: 3600,
+            expire: 1209600,
+            minimum: 300,
+            ttl: 300,
+        };
+        let response = |rescode, answers: Vec<DnsRecord>, authorities: Vec<DnsRecord>| {
+            let header = DnsHeader::builder()
+                .id(10)
+                .response(true)
+                .rescode(rescode)
+                .answers(answers.len() as u16)
+                .authoritative_entries(authorities.len() as u16)
+                .build();
+            DnsPacket::builder()
+                .header(header)
+                .answers(answers)
+                .authorities(authorities)
+                .build()
+        };
+        let classify = |pack: &DnsPacket| {
+            (
+                pack.is_answer(),
+                pack.is_referral(),
+                pack.is_nxdomain(),
+                pack.is_nodata(),
+            )
+        };
+
+        let answer = response(ResponseCode::NOERROR, vec![a], vec![ns.clone()]);
+        let referral = response(ResponseCode::NOERROR, vec![], vec![ns.clone()]);
+        let nxdomain = response(ResponseCode::NXDOMAIN, vec![], vec![soa.clone()]);
+        // Some servers send the NS records of the zone along with the SOA
+        let nodata = response(ResponseCode::NOERROR, vec![], vec![soa, ns.clone()]);
+        let servfail = response(ResponseCode::SERVFAIL, vec![], vec![ns]);
+
+        assert_eq!(classify(&answer), (true, false, false, false));
+        assert_eq!(classify(&referral), (false, true, false, false));
+        assert_eq!(classify(&nxdomain), (false, false, true, false));
+        assert_eq!(classify(&nodata), (false, false, false, true));
+        assert_eq!(classify(&servfail), (false, false, false, false));
+    }
+
+    #[test]
+    fn error_responses_echo_the_request() {
+        let mut request = DnsPacket::query(1234, "example.com", QueryType::AAAA);
+        request.header.recursion_desired = false;
+
+        let responses = [
+            (DnsPacket::nxdomain(&request), ResponseCode::NXDOMAIN),
+            (DnsPacket::servfail(&request), ResponseCode::SERVFAIL),
+            (DnsPacket::formerr(&request), ResponseCode::FORMERR),
+        ];
+
+        for (response, rescode) in responses {
+            assert_eq!(response.header.id, 1234);
+            assert!(response.is_response());
+            assert!(!response.header.recursion_desired);
+            assert_eq!(response.rescode(), rescode);
+            assert_eq!(response.header.questions, 1);
+            assert_eq!(response.questions, request.questions);
+            assert_eq!(response.all_records().count(), 0);
+        }
+        assert!(DnsPacket::nxdomain(&request).is_nxdomain());
+    }
+
+    #[test]
+    fn questions_iter_works() {
+        let mut pack = DnsPacket::query(10, "example.com", QueryType::A);
+        pack.questions.push(
+            DnsQuestion::builder()
+                .name("example.org".to_string())
+                .qtype(QueryType::MX)
+                .build(),
+        );
+        pack.header.questions = 2;
+
+        let questions = pack.questions_iter().collect::<Vec<_>>();
+
+        assert_eq!(
+            questions,
+            vec![
+                ("example.com", QueryType::A),
+                ("example.org", QueryType::MX)
+            ]
+        );
+        assert_eq!(pack.qname(), Some("example.com".to_string()));
+    }
+
+    #[test]
+    fn glue_for_works() {
+        let header = DnsHeader::builder()
+            .id(10)
+            .answers(1)
+            .resource_entries(3)
+            .build();
+        let mx = DnsRecord::MX {
+            domain: "example.com".to_string(),
+            priority: 10,
+            host: "mail.example.com".to_string(),
+            ttl: 300,
+        };
+        let glue = vec![
+            DnsRecord::A {
+                domain: "MAIL.example.com".to_string(),
+                addr: Ipv4Addr::new(192, 0, 2, 25),
+                ttl: 300,
+            },
+            DnsRecord::A {
+                domain: "other.example.com".to_string(),
+                addr: Ipv4Addr::new(192, 0, 2, 26),
+                ttl: 300,
+            },
+            DnsRecord::AAAA {
+                domain: "mail.example.com".to_string(),
+                addr: "2001:db8::25".parse().unwrap(),
+                ttl: 300,
+            },
+        ];
+        let pack = DnsPacket::builder()
+            .header(header)
+            .answers(vec![mx])
+            .resources(glue)
+            .build();
+
+        assert_eq!(
+            pack.glue_for("mail.example.com"),
+            vec![
+                IpAddr::from(Ipv4Addr::new(192, 0, 2, 25)),
+                "2001:db8::25".parse::<IpAddr>().unwrap(),
+            ]
+        );
+        assert!(pack.glue_for("example.com").is_empty());
+    }
+
     #[test]
     fn get_resolved_ns_works() {
         let header = DnsHeader::builder().id(10).build();
-        let question = DnsQuestion {
-            name: "google.com".to_string(),
-            qtype: QueryType::A,
-        };
+        let question = DnsQuestion::builder()
+            .name("google.com".to_string())
+            .qtype(QueryType::A)
+            .build();
         let authoritative_records = vec![DnsRecord::NS {
             domain: "com".to_string(),
             host: "a.gtld-servers.net".to_string(),
@@ -385,4 +2246,124 @@ mod test {
 
         assert_eq!(Some(Ipv4Addr::new(192, 5, 6, 30)), result);
     }
+
+    #[test]
+    fn get_resolved_ns_ignores_case() {
+        let header = DnsHeader::builder().id(10).build();
+        let authoritative_records = vec![DnsRecord::NS {
+            domain: "Example.COM".to_string(),
+            host: "NS1.example.com".to_string(),
+            ttl: 172800,
+        }];
+        let resource_records = vec![
+            DnsRecord::A {
+                domain: "ns1.EXAMPLE.com".to_string(),
+                addr: Ipv4Addr::new(192, 0, 2, 53),
+                ttl: 172800,
+            },
+            DnsRecord::AAAA {
+                domain: "Ns1.Example.Com".to_string(),
+                addr: "2001:db8::53".parse().unwrap(),
+                ttl: 172800,
+            },
+        ];
+
+        let pack = DnsPacket::builder()
+            .header(header)
+            .authorities(authoritative_records)
+            .resources(resource_records)
+            .build();
+
+        assert_eq!(
+            pack.get_resolved_ns("www.example.com"),
+            Some(Ipv4Addr::new(192, 0, 2, 53))
+        );
+        assert_eq!(
+            pack.get_resolved_ns_v6("WWW.example.com"),
+            Some("2001:db8::53".parse().unwrap())
+        );
+        assert_eq!(
+            pack.get_all_unresolved_ns("www.EXAMPLE.com"),
+            vec!["NS1.example.com"]
+        );
+    }
+
+    #[test]
+    fn get_all_unresolved_ns_works() {
+        let header = DnsHeader::builder().id(10).build();
+        let authoritative_records = vec![
+            DnsRecord::NS {
+                domain: "com".to_string(),
+                host: "a.gtld-servers.net".to_string(),
+                ttl: 172800,
+            },
+            DnsRecord::NS {
+                domain: "org".to_string(),
+                host: "a0.org.afilias-nst.info".to_string(),
+                ttl: 172800,
+            },
+            DnsRecord::NS {
+                domain: "com".to_string(),
+                host: "b.gtld-servers.net".to_string(),
+                ttl: 172800,
+            },
+        ];
+
+        let pack = DnsPacket::builder()
+            .header(header)
+            .authorities(authoritative_records)
+            .build();
+
+        let result = pack.get_all_unresolved_ns("google.com");
+
+        assert_eq!(result, vec!["a.gtld-servers.net", "b.gtld-servers.net"]);
+    }
+
+    #[test]
+    fn get_resolved_ns_v6_works() {
+        let header = DnsHeader::builder().id(10).build();
+        let authoritative_records = vec![DnsRecord::NS {
+            domain: "com".to_string(),
+            host: "a.gtld-servers.net".to_string(),
+            ttl: 172800,
+        }];
+
+        let resource_records = vec![DnsRecord::AAAA {
+            domain: "a.gtld-servers.net".to_string(),
+            addr: "2001:503:a83e::2:30".parse::<Ipv6Addr>().unwrap(),
+            ttl: 172800,
+        }];
+
+        let pack = DnsPacket::builder()
+            .header(header)
+            .authorities(authoritative_records)
+            .resources(resource_records)
+            .build();
+
+        assert_eq!(pack.get_resolved_ns("google.com"), None);
+        assert_eq!(
+            pack.get_resolved_ns_v6("google.com"),
+            Some("2001:503:a83e::2:30".parse::<Ipv6Addr>().unwrap())
+        );
+    }
+
+    #[test]
+    fn packet_status_helpers_work() {
+        let query = DnsPacket::query(10, "google.com", QueryType::A);
+
+        let header = DnsHeader::builder().id(10).response(true).build();
+        let answer = DnsPacket::builder().header(header).build();
+
+        let header = DnsHeader::builder()
+            .id(10)
+            .response(true)
+            .rescode(ResponseCode::SERVFAIL)
+            .build();
+        let failure = DnsPacket::builder().header(header).build();
+
+        assert!(query.is_query() && !query.is_response());
+        assert!(answer.is_response() && !answer.is_query());
+        assert!(answer.is_successful());
+        assert!(!failure.is_successful());
+    }
 }
@@ -1,11 +1,27 @@
 // Ignore: util function to intersperse delimiter to a vector of strings
-// Extra hacky
 pub fn isperse(input: Vec<String>) -> String {
-    let string = input
-        .into_iter()
-        .fold("".to_string(), |acc, x| format!("{}.{}", acc, x));
+    input.join(".")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn isperse_works_on_empty_input() {
+        assert_eq!(isperse(vec![]), "");
+    }
+
+    #[test]
+    fn isperse_works_on_single_element() {
+        assert_eq!(isperse(vec!["com".to_string()]), "com");
+    }
+
+    #[test]
+    fn isperse_works_on_multiple_elements() {
+        let input = vec!["www".to_string(), "google".to_string(), "com".to_string()];
 
-    let mut chars = string.chars();
-    chars.next();
-    chars.as_str().to_string()
+        assert_eq!(isperse(input), "www.google.com");
+    }
 }
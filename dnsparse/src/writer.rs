@@ -1,25 +1,83 @@
-use log::warn;
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fmt,
+    io::{self, Write},
+};
 
+use crate::name::MAX_NAME_LENGTH;
 use crate::types::{DnsHeader, DnsPacket, DnsQuestion, DnsRecord, QueryType};
 
+// Messages sent over TCP are prefixed with their length in two octets (RFC 1035 section 4.2.2)
+const MAX_TCP_MESSAGE_SIZE: usize = u16::MAX as usize;
+// Compression pointers have 14 bits for the offset of the name they point to
+const MAX_POINTER_OFFSET: usize = 0x3FFF;
+
+// How packets get written, see `write_with_options`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriteOptions {
+    // Names that were written before are replaced by a pointer to them (RFC 1035 section 4.1.4)
+    pub compress: bool,
+}
+
+impl Default for WriteOptions {
+    fn default() -> WriteOptions {
+        WriteOptions { compress: true }
+    }
+}
+
+// What writing past the limit fails with, so that it can be told apart from the packet not being
+// writable at all (see `write_with_limit`)
+#[derive(Debug)]
+struct EndOfBuffer;
+
+impl fmt::Display for EndOfBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "End of buffer")
+    }
+}
+
+impl std::error::Error for EndOfBuffer {}
+
 struct BytePacketBuffer<'a> {
     buf: &'a mut [u8],
     pos: usize,
+    limit: usize,
+    // Where the names written so far (and the names they end in) start, when compressing
+    names: Option<HashMap<String, usize>>,
 }
 
 impl<'a> BytePacketBuffer<'a> {
     /// This gives us a fresh buffer for holding the packet contents, and a
-    /// field for keeping track of where we are.
-    pub fn new(buffer: &'a mut [u8]) -> BytePacketBuffer<'a> {
+    /// field for keeping track of where we are. At most `limit` bytes (512
+    /// unless the packet advertises a bigger payload size via EDNS) are written,
+    /// and never more than what fits into `buffer`.
+    pub fn new(buffer: &'a mut [u8], limit: usize) -> BytePacketBuffer<'a> {
+        let limit = limit.min(buffer.len());
         BytePacketBuffer {
             buf: buffer,
             pos: 0,
+            limit,
+            names: None,
+        }
+    }
+
+    fn with_compression(mut self, compress: bool) -> BytePacketBuffer<'a> {
+        self.names = compress.then(HashMap::new);
+        self
+    }
+
+    // Throws away everything written from `pos` on, names included
+    fn rewind(&mut self, pos: usize) {
+        self.pos = pos;
+        if let Some(names) = &mut self.names {
+            names.retain(|_, start| *start < pos);
         }
     }
 
     fn write(&mut self, val: u8) -> anyhow::Result<()> {
-        if self.pos >= 512 {
-            anyhow::bail!("End of buffer")
+        if self.pos >= self.limit {
+            return Err(EndOfBuffer.into());
         }
         self.buf[self.pos] = val;
         self.pos += 1;
@@ -48,14 +106,59 @@ impl<'a> BytePacketBuffer<'a> {
         Ok(())
     }
 
+    // Names are written out in full, where compression is not allowed (e.g. the targets of SRV
+    // records or the RDATA of any type defined after RFC 1035, see RFC 3597 section 4)
     fn write_qname(&mut self, qname: &str) -> anyhow::Result<()> {
-        for label in qname.split('.') {
-            let len = label.len();
-            if len > 0x3f {
+        self.write_name(qname, false)
+    }
+
+    // The owner names of records and the names within the RDATA of the types of RFC 1035 may
+    // point to a name written before, when compressing
+    fn write_compressed_qname(&mut self, qname: &str) -> anyhow::Result<()> {
+        self.write_name(qname, true)
+    }
+
+    fn write_name(&mut self, qname: &str, compressible: bool) -> anyhow::Result<()> {
+        let qname = ascii_name(qname)?;
+        // Names are fully qualified either way, a trailing dot (or the root on its own) is not an
+        // empty label to write
+        let labels = match qname.strip_suffix('.').unwrap_or(&qname) {
+            "" => vec![],
+            name => name.split('.').collect::<Vec<_>>(),
+        };
+        // Account for the terminating zero up front
+        let mut name_len = 1;
+        for label in &labels {
+            // A zero length octet would end the name right there, leaving the rest to be read as
+            // whatever comes after it
+            if label.is_empty() {
+                anyhow::bail!("Domain name {:?} contains an empty label", qname)
+            }
+            if label.len() > 0x3f {
                 anyhow::bail!("Single label exceeds 63 characters")
             }
 
-            self.write_u8(len as u8)?;
+            name_len += label.len() + 1;
+            if name_len > MAX_NAME_LENGTH {
+                anyhow::bail!("Domain name exceeds {} octets", MAX_NAME_LENGTH)
+            }
+        }
+
+        for (i, label) in labels.iter().enumerate() {
+            if let Some(names) = &mut self.names {
+                let suffix = labels[i..].join(".");
+                match names.get(&suffix) {
+                    Some(&start) if compressible => {
+                        return self.write_u16(0xC000 | start as u16);
+                    }
+                    None if self.pos <= MAX_POINTER_OFFSET => {
+                        names.insert(suffix, self.pos);
+                    }
+                    _ => {}
+                }
+            }
+
+            self.write_u8(label.len() as u8)?;
             for b in label.as_bytes() {
                 self.write_u8(*b)?;
             }
@@ -66,6 +169,20 @@ impl<'a> BytePacketBuffer<'a> {
         Ok(())
     }
 
+    fn write_character_string(&mut self, val: &str) -> anyhow::Result<()> {
+        let len = val.len();
+        if len > 0xff {
+            anyhow::bail!("Character string exceeds 255 characters")
+        }
+
+        self.write_u8(len as u8)?;
+        for b in val.as_bytes() {
+            self.write_u8(*b)?;
+        }
+
+        Ok(())
+    }
+
     fn set(&mut self, pos: usize, val: u8) -> anyhow::Result<()> {
         self.buf[pos] = val;
 
@@ -80,28 +197,149 @@ impl<'a> BytePacketBuffer<'a> {
     }
 }
 
+// Names go on the wire as ASCII, the punycode form of internationalized ones is only known to us
+// with the idna feature
+#[cfg(feature = "idna")]
+fn ascii_name(name: &str) -> anyhow::Result<Cow<'_, str>> {
+    if name.is_ascii() {
+        return Ok(Cow::Borrowed(name));
+    }
+
+    crate::idn::to_ascii(name).map(Cow::Owned)
+}
+
+#[cfg(not(feature = "idna"))]
+fn ascii_name(name: &str) -> anyhow::Result<Cow<'_, str>> {
+    if !name.is_ascii() {
+        anyhow::bail!("Domain name {} is not ASCII (see the idna feature)", name)
+    }
+
+    Ok(Cow::Borrowed(name))
+}
+
+// Records that do not fit into the payload size of the packet (or the buffer) are left out, with
+// the TC bit set to let the client know that it should retry over TCP
 pub fn write(buf: &mut [u8], packet: &DnsPacket) -> anyhow::Result<usize> {
-    let mut buffer = BytePacketBuffer::new(buf);
+    write_with_options(buf, packet, WriteOptions::default())
+}
+
+// Same as `write` but e.g. without name compression, for name servers that can not handle it
+pub fn write_with_options(
+    buf: &mut [u8],
+    packet: &DnsPacket,
+    options: WriteOptions,
+) -> anyhow::Result<usize> {
+    write_with_limit(buf, packet, packet.udp_payload_size(), true, options)
+}
+
+// Same as `write` but for TCP, where the packet can be as big as 65535 bytes regardless of the
+// payload size it advertises and is preceded by its length
+pub fn write_tcp(buf: &mut [u8], packet: &DnsPacket) -> anyhow::Result<usize> {
+    if buf.len() < 2 {
+        anyhow::bail!("End of buffer")
+    }
+
+    let size = write_with_limit(
+        &mut buf[2..],
+        packet,
+        MAX_TCP_MESSAGE_SIZE,
+        false,
+        WriteOptions::default(),
+    )?;
+    let mut buffer = BytePacketBuffer::new(buf, 2);
+    buffer.write_u16(size as u16)?;
+
+    Ok(size + 2)
+}
+
+// `write_tcp` taking care of allocating the buffer
+pub fn write_tcp_vec(packet: &DnsPacket) -> anyhow::Result<Vec<u8>> {
+    let mut buf = vec![0u8; MAX_TCP_MESSAGE_SIZE + 2];
+    let size = write_tcp(&mut buf, packet)?;
+    buf.truncate(size);
+
+    Ok(buf)
+}
+
+// The header and the questions always have to fit. When `truncate` is set, what comes after is
+// written up to the last complete record that fits (RFC 2181 section 9), followed by the OPT
+// record if there is still room for it (RFC 6891 section 7). The header counts are fixed up to
+// match what was written.
+fn write_with_limit(
+    buf: &mut [u8],
+    packet: &DnsPacket,
+    limit: usize,
+    truncate: bool,
+    options: WriteOptions,
+) -> anyhow::Result<usize> {
+    let mut buffer = BytePacketBuffer::new(buf, limit).with_compression(options.compress);
 
     write_header(&packet.header, &mut buffer)?;
 
     for question in &packet.questions {
-        write_question(&question, &mut buffer)?;
+        write_question(question, &mut buffer)?;
     }
 
-    for rec in &packet.answers {
-        write_record(rec, &mut buffer)?;
-    }
-    for rec in &packet.authorities {
-        write_record(rec, &mut buffer)?;
+    let sections = [&packet.answers, &packet.authorities, &packet.resources];
+    let mut written = [0u16; 3];
+    let mut truncated = false;
+    for (section, records) in sections.iter().enumerate() {
+        for rec in records.iter() {
+            if truncated && rec.query_type() != QueryType::OPT {
+                continue;
+            }
+
+            let start_pos = buffer.pos;
+            match write_record(rec, &mut buffer) {
+                Ok(_) => written[section] += 1,
+                Err(error) if truncate && error.is::<EndOfBuffer>() => {
+                    buffer.rewind(start_pos);
+                    truncated = true;
+                }
+                Err(error) => return Err(error),
+            }
+        }
     }
-    for rec in &packet.resources {
-        write_record(rec, &mut buffer)?;
+
+    if truncated {
+        let mut header = packet.header.clone();
+        header.truncated_message = true;
+        buffer.set_u16(2, header.flags())?;
+        buffer.set_u16(6, written[0])?;
+        buffer.set_u16(8, written[1])?;
+        buffer.set_u16(10, written[2])?;
     }
 
     Ok(buffer.pos)
 }
 
+// Same as `write` but takes care of allocating the buffer, which only holds the written bytes
+pub fn write_vec(packet: &DnsPacket) -> anyhow::Result<Vec<u8>> {
+    let mut buf = vec![0u8; packet.udp_payload_size()];
+    let size = write(&mut buf, packet)?;
+    buf.truncate(size);
+
+    Ok(buf)
+}
+
+// Hands the packet over to `sink` (e.g. a file or a TCP stream). Same as `write_tcp` it can be as
+// big as 65535 bytes and nothing is left out, only without the length in front. Packets that can
+// not be written at all are reported as `InvalidInput`.
+pub fn write_io<W: Write>(sink: &mut W, packet: &DnsPacket) -> io::Result<usize> {
+    let mut buf = vec![0u8; MAX_TCP_MESSAGE_SIZE];
+    let size = write_with_limit(
+        &mut buf,
+        packet,
+        MAX_TCP_MESSAGE_SIZE,
+        false,
+        WriteOptions::default(),
+    )
+    .map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))?;
+    sink.write_all(&buf[..size])?;
+
+    Ok(size)
+}
+
 fn write_header(header: &DnsHeader, buffer: &mut BytePacketBuffer) -> anyhow::Result<()> {
     buffer.write_u16(header.id)?;
     buffer.write_u16(header.flags())?;
@@ -114,10 +352,10 @@ fn write_header(header: &DnsHeader, buffer: &mut BytePacketBuffer) -> anyhow::Re
 }
 
 fn write_question(question: &DnsQuestion, buffer: &mut BytePacketBuffer) -> anyhow::Result<()> {
-    buffer.write_qname(&question.name)?;
+    buffer.write_compressed_qname(&question.name)?;
     let typenum = question.qtype.to_num();
     buffer.write_u16(typenum)?;
-    buffer.write_u16(1)?;
+    buffer.write_u16(question.class.to_num())?;
 
     Ok(())
 }
@@ -131,7 +369,7 @@ fn write_record(record: &DnsRecord, buffer: &mut BytePacketBuffer) -> anyhow::Re
             ref addr,
             ttl,
         } => {
-            buffer.write_qname(domain)?;
+            buffer.write_compressed_qname(domain)?;
             buffer.write_u16(QueryType::A.to_num())?;
             buffer.write_u16(1)?;
             buffer.write_u32(ttl)?;
@@ -148,7 +386,7 @@ fn write_record(record: &DnsRecord, buffer: &mut BytePacketBuffer) -> anyhow::Re
             ref host,
             ttl,
         } => {
-            buffer.write_qname(domain)?;
+            buffer.write_compressed_qname(domain)?;
             buffer.write_u16(QueryType::NS.to_num())?;
             buffer.write_u16(1)?;
             buffer.write_u32(ttl)?;
@@ -156,7 +394,7 @@ fn write_record(record: &DnsRecord, buffer: &mut BytePacketBuffer) -> anyhow::Re
             let pos = buffer.pos;
             buffer.write_u16(0)?;
 
-            buffer.write_qname(host)?;
+            buffer.write_compressed_qname(host)?;
 
             let size = buffer.pos - (pos + 2);
             buffer.set_u16(pos, size as u16)?;
@@ -166,7 +404,7 @@ fn write_record(record: &DnsRecord, buffer: &mut BytePacketBuffer) -> anyhow::Re
             ref host,
             ttl,
         } => {
-            buffer.write_qname(domain)?;
+            buffer.write_compressed_qname(domain)?;
             buffer.write_u16(QueryType::CNAME.to_num())?;
             buffer.write_u16(1)?;
             buffer.write_u32(ttl)?;
@@ -174,7 +412,7 @@ fn write_record(record: &DnsRecord, buffer: &mut BytePacketBuffer) -> anyhow::Re
             let pos = buffer.pos;
             buffer.write_u16(0)?;
 
-            buffer.write_qname(host)?;
+            buffer.write_compressed_qname(host)?;
 
             let size = buffer.pos - (pos + 2);
             buffer.set_u16(pos, size as u16)?;
@@ -185,7 +423,7 @@ fn write_record(record: &DnsRecord, buffer: &mut BytePacketBuffer) -> anyhow::Re
             ref host,
             ttl,
         } => {
-            buffer.write_qname(domain)?;
+            buffer.write_compressed_qname(domain)?;
             buffer.write_u16(QueryType::MX.to_num())?;
             buffer.write_u16(1)?;
             buffer.write_u32(ttl)?;
@@ -194,7 +432,7 @@ fn write_record(record: &DnsRecord, buffer: &mut BytePacketBuffer) -> anyhow::Re
             buffer.write_u16(0)?;
 
             buffer.write_u16(priority)?;
-            buffer.write_qname(host)?;
+            buffer.write_compressed_qname(host)?;
 
             let size = buffer.pos - (pos + 2);
             buffer.set_u16(pos, size as u16)?;
@@ -204,7 +442,7 @@ fn write_record(record: &DnsRecord, buffer: &mut BytePacketBuffer) -> anyhow::Re
             ref addr,
             ttl,
         } => {
-            buffer.write_qname(domain)?;
+            buffer.write_compressed_qname(domain)?;
             buffer.write_u16(QueryType::AAAA.to_num())?;
             buffer.write_u16(1)?;
             buffer.write_u32(ttl)?;
@@ -214,8 +452,263 @@ fn write_record(record: &DnsRecord, buffer: &mut BytePacketBuffer) -> anyhow::Re
                 buffer.write_u16(*octet)?;
             }
         }
-        DnsRecord::UNKNOWN { .. } => {
-            warn!("Skipping record: {:?}", record);
+        DnsRecord::SRV {
+            ref domain,
+            priority,
+            weight,
+            port,
+            ref target,
+            ttl,
+        } => {
+            buffer.write_compressed_qname(domain)?;
+            buffer.write_u16(QueryType::SRV.to_num())?;
+            buffer.write_u16(1)?;
+            buffer.write_u32(ttl)?;
+
+            let pos = buffer.pos;
+            buffer.write_u16(0)?;
+
+            buffer.write_u16(priority)?;
+            buffer.write_u16(weight)?;
+            buffer.write_u16(port)?;
+            buffer.write_qname(target)?;
+
+            let size = buffer.pos - (pos + 2);
+            buffer.set_u16(pos, size as u16)?;
+        }
+        DnsRecord::PTR {
+            ref domain,
+            ref host,
+            ttl,
+        } => {
+            buffer.write_compressed_qname(domain)?;
+            buffer.write_u16(QueryType::PTR.to_num())?;
+            buffer.write_u16(1)?;
+            buffer.write_u32(ttl)?;
+
+            let pos = buffer.pos;
+            buffer.write_u16(0)?;
+
+            buffer.write_compressed_qname(host)?;
+
+            let size = buffer.pos - (pos + 2);
+            buffer.set_u16(pos, size as u16)?;
+        }
+        DnsRecord::CAA {
+            ref domain,
+            flags,
+            ref tag,
+            ref value,
+            ttl,
+        } => {
+            buffer.write_compressed_qname(domain)?;
+            buffer.write_u16(QueryType::CAA.to_num())?;
+            buffer.write_u16(1)?;
+            buffer.write_u32(ttl)?;
+            buffer.write_u16((2 + tag.len() + value.len()) as u16)?;
+
+            buffer.write_u8(flags)?;
+            buffer.write_character_string(tag)?;
+            for b in value.as_bytes() {
+                buffer.write_u8(*b)?;
+            }
+        }
+        DnsRecord::TXT {
+            ref domain,
+            ref data,
+            ttl,
+        } => {
+            buffer.write_compressed_qname(domain)?;
+            buffer.write_u16(QueryType::TXT.to_num())?;
+            buffer.write_u16(1)?;
+            buffer.write_u32(ttl)?;
+
+            let pos = buffer.pos;
+            buffer.write_u16(0)?;
+
+            for text in data {
+                buffer.write_character_string(text)?;
+            }
+
+            let size = buffer.pos - (pos + 2);
+            buffer.set_u16(pos, size as u16)?;
+        }
+        DnsRecord::DNAME {
+            ref domain,
+            ref target,
+            ttl,
+        } => {
+            buffer.write_compressed_qname(domain)?;
+            buffer.write_u16(QueryType::DNAME.to_num())?;
+            buffer.write_u16(1)?;
+            buffer.write_u32(ttl)?;
+
+            let pos = buffer.pos;
+            buffer.write_u16(0)?;
+
+            buffer.write_qname(target)?;
+
+            let size = buffer.pos - (pos + 2);
+            buffer.set_u16(pos, size as u16)?;
+        }
+        DnsRecord::HINFO {
+            ref domain,
+            ref cpu,
+            ref os,
+            ttl,
+        } => {
+            buffer.write_compressed_qname(domain)?;
+            buffer.write_u16(QueryType::HINFO.to_num())?;
+            buffer.write_u16(1)?;
+            buffer.write_u32(ttl)?;
+
+            let pos = buffer.pos;
+            buffer.write_u16(0)?;
+
+            buffer.write_character_string(cpu)?;
+            buffer.write_character_string(os)?;
+
+            let size = buffer.pos - (pos + 2);
+            buffer.set_u16(pos, size as u16)?;
+        }
+        DnsRecord::SOA {
+            ref domain,
+            ref m_name,
+            ref r_name,
+            serial,
+            refresh,
+            retry,
+            expire,
+            minimum,
+            ttl,
+        } => {
+            buffer.write_compressed_qname(domain)?;
+            buffer.write_u16(QueryType::SOA.to_num())?;
+            buffer.write_u16(1)?;
+            buffer.write_u32(ttl)?;
+
+            let pos = buffer.pos;
+            buffer.write_u16(0)?;
+
+            buffer.write_compressed_qname(m_name)?;
+            buffer.write_compressed_qname(r_name)?;
+            buffer.write_u32(serial)?;
+            buffer.write_u32(refresh)?;
+            buffer.write_u32(retry)?;
+            buffer.write_u32(expire)?;
+            buffer.write_u32(minimum)?;
+
+            let size = buffer.pos - (pos + 2);
+            buffer.set_u16(pos, size as u16)?;
+        }
+        DnsRecord::LOC {
+            ref domain,
+            version,
+            size,
+            horiz_pre,
+            vert_pre,
+            latitude,
+            longitude,
+            altitude,
+            ttl,
+        } => {
+            buffer.write_compressed_qname(domain)?;
+            buffer.write_u16(QueryType::LOC.to_num())?;
+            buffer.write_u16(1)?;
+            buffer.write_u32(ttl)?;
+            buffer.write_u16(16)?;
+
+            buffer.write_u8(version)?;
+            buffer.write_u8(size)?;
+            buffer.write_u8(horiz_pre)?;
+            buffer.write_u8(vert_pre)?;
+            buffer.write_u32(latitude)?;
+            buffer.write_u32(longitude)?;
+            buffer.write_u32(altitude)?;
+        }
+        DnsRecord::DNSKEY {
+            ref domain,
+            flags,
+            protocol,
+            algorithm,
+            ref public_key,
+            ttl,
+        } => {
+            buffer.write_compressed_qname(domain)?;
+            buffer.write_u16(QueryType::DNSKEY.to_num())?;
+            buffer.write_u16(1)?;
+            buffer.write_u32(ttl)?;
+            buffer.write_u16((4 + public_key.len()) as u16)?;
+
+            buffer.write_u16(flags)?;
+            buffer.write_u8(protocol)?;
+            buffer.write_u8(algorithm)?;
+            for b in public_key {
+                buffer.write_u8(*b)?;
+            }
+        }
+        DnsRecord::NAPTR {
+            ref domain,
+            order,
+            preference,
+            ref flags,
+            ref services,
+            ref regexp,
+            ref replacement,
+            ttl,
+        } => {
+            buffer.write_compressed_qname(domain)?;
+            buffer.write_u16(QueryType::NAPTR.to_num())?;
+            buffer.write_u16(1)?;
+            buffer.write_u32(ttl)?;
+
+            let pos = buffer.pos;
+            buffer.write_u16(0)?;
+
+            buffer.write_u16(order)?;
+            buffer.write_u16(preference)?;
+            buffer.write_character_string(flags)?;
+            buffer.write_character_string(services)?;
+            buffer.write_character_string(regexp)?;
+            buffer.write_qname(replacement)?;
+
+            let size = buffer.pos - (pos + 2);
+            buffer.set_u16(pos, size as u16)?;
+        }
+        DnsRecord::OPT {
+            udp_payload_size,
+            ext_rcode,
+            version,
+            flags,
+            ref data,
+        } => {
+            buffer.write_u8(0)?;
+            buffer.write_u16(QueryType::OPT.to_num())?;
+            buffer.write_u16(udp_payload_size)?;
+            buffer.write_u8(ext_rcode)?;
+            buffer.write_u8(version)?;
+            buffer.write_u16(flags)?;
+            buffer.write_u16(data.len() as u16)?;
+
+            for b in data {
+                buffer.write_u8(*b)?;
+            }
+        }
+        DnsRecord::UNKNOWN {
+            ref domain,
+            qtype,
+            ref data,
+            ttl,
+        } => {
+            buffer.write_compressed_qname(domain)?;
+            buffer.write_u16(qtype)?;
+            buffer.write_u16(1)?;
+            buffer.write_u32(ttl)?;
+            buffer.write_u16(data.len() as u16)?;
+
+            for b in data {
+                buffer.write_u8(*b)?;
+            }
         }
     }
 
@@ -227,7 +720,7 @@ mod test {
     use pretty_assertions::assert_eq;
     use std::net::Ipv4Addr;
 
-    use crate::types::ResponseCode;
+    use crate::types::{DnsClass, QueryFlags, ResponseCode};
 
     use super::*;
 
@@ -276,30 +769,622 @@ mod test {
         }
     }
 
-    #[test]
-    fn writing_works() {
+    fn google_answer_packet() -> DnsPacket {
         let header = google_header_answer();
-        let question = DnsQuestion {
-            name: "google.com".to_string(),
-            qtype: QueryType::A,
-        };
+        let question = DnsQuestion::builder()
+            .name("google.com".to_string())
+            .qtype(QueryType::A)
+            .build();
         let record = DnsRecord::A {
             domain: "google.com".to_string(),
             addr: Ipv4Addr::new(216, 58, 211, 142),
             ttl: 293,
         };
 
-        let dns_packet = DnsPacket {
+        DnsPacket {
             header,
             questions: vec![question],
             answers: vec![record],
             authorities: vec![],
             resources: vec![],
+        }
+    }
+
+    #[test]
+    fn writing_works() {
+        let mut expected = google_answer()[..28].to_vec();
+        // The name of the answer points to the one of the question
+        expected.extend([0xc0, 0x0c]);
+        expected.extend(&google_answer()[40..]);
+
+        let mut vec = vec![0u8; 512];
+        let size = write(&mut vec, &google_answer_packet()).unwrap();
+
+        assert_eq!(&vec[..size], &expected[..]);
+        assert_eq!(
+            DnsPacket::parse(&vec[..size]).unwrap(),
+            google_answer_packet()
+        );
+    }
+
+    #[test]
+    fn query_flags_are_written() {
+        let flags = QueryFlags {
+            checking_disabled: true,
+            authed_data: false,
         };
+        let query = DnsPacket::query_with_flags(1, "google.com", QueryType::A, flags);
+
+        let buf = write_vec(&query).unwrap();
+        let parsed = DnsPacket::parse(&buf).unwrap();
+
+        assert_eq!(buf[3], 0x10);
+        assert!(parsed.header.checking_disabled);
+        assert!(!parsed.header.authed_data);
+    }
+
+    #[test]
+    fn writing_without_compression_works() {
+        let options = WriteOptions { compress: false };
 
         let mut vec = vec![0u8; 512];
-        let size = write(&mut vec, &dns_packet).unwrap();
+        let size = write_with_options(&mut vec, &google_answer_packet(), options).unwrap();
 
         assert_eq!(&vec[..size], google_answer());
     }
+
+    #[test]
+    fn rdlength_covers_compressed_names() {
+        let records = vec![
+            DnsRecord::NS {
+                domain: "example.com".to_string(),
+                host: "ns1.example.com".to_string(),
+                ttl: 3600,
+            },
+            DnsRecord::CNAME {
+                domain: "example.com".to_string(),
+                host: "www.example.com".to_string(),
+                ttl: 3600,
+            },
+            DnsRecord::MX {
+                domain: "example.com".to_string(),
+                priority: 10,
+                host: "mail.example.com".to_string(),
+                ttl: 3600,
+            },
+        ];
+
+        for record in records {
+            let mut dns_packet = DnsPacket::query(43087, "example.com", record.query_type());
+            dns_packet.header.answers = 1;
+            dns_packet.answers = vec![record];
+
+            let bytes = write_vec(&dns_packet).unwrap();
+            let parsed = DnsPacket::parse(&bytes).unwrap();
+
+            // Header (12) and question (17), then the owner name pointing to the question (2),
+            // type, class and ttl (8) and finally the RDLENGTH
+            let rdlength = u16::from_be_bytes([bytes[39], bytes[40]]) as usize;
+            // The host is its first label followed by a pointer to the question
+            assert_eq!(&bytes[bytes.len() - 2..], &[0xc0, 0x0c]);
+            assert_eq!(rdlength, bytes.len() - 41);
+            assert_eq!(parsed, dns_packet);
+        }
+    }
+
+    #[test]
+    fn writing_past_512_bytes_works_with_edns() {
+        let record = DnsRecord::A {
+            domain: "google.com".to_string(),
+            addr: Ipv4Addr::new(216, 58, 211, 142),
+            ttl: 293,
+        };
+        let opt = DnsRecord::OPT {
+            udp_payload_size: 4096,
+            ext_rcode: 0,
+            version: 0,
+            flags: 0,
+            data: vec![],
+        };
+        let header = DnsHeader::builder()
+            .id(43087)
+            .answers(40)
+            .resource_entries(1)
+            .build();
+
+        let mut dns_packet = DnsPacket::builder()
+            .header(header)
+            .answers(vec![record; 40])
+            .build();
+
+        let mut vec = vec![0u8; 4096];
+        let plain = write(&mut vec, &dns_packet).unwrap();
+        let truncated = DnsPacket::parse(&vec[..plain]);
+
+        dns_packet.resources.push(opt);
+        let size = write(&mut vec, &dns_packet).unwrap();
+        let parsed = DnsPacket::parse(&vec[..size]).unwrap();
+
+        // All but the first name (26 bytes) are pointers to it (16 bytes)
+        assert_eq!(plain, 12 + 26 + 29 * 16);
+        assert!(truncated.unwrap().header.truncated_message);
+        assert_eq!(size, 12 + 26 + 39 * 16 + 11);
+        assert!(!parsed.header.truncated_message);
+    }
+
+    #[test]
+    fn writing_past_512_bytes_works_over_tcp() {
+        let record = DnsRecord::A {
+            domain: "google.com".to_string(),
+            addr: Ipv4Addr::new(216, 58, 211, 142),
+            ttl: 293,
+        };
+        let header = DnsHeader::builder().id(43087).answers(25).build();
+        let dns_packet = DnsPacket::builder()
+            .header(header)
+            .answers(vec![record; 25])
+            .build();
+
+        let mut vec = vec![0u8; 4096];
+        let size = write_tcp(&mut vec, &dns_packet).unwrap();
+        let parsed = DnsPacket::parse(&vec[2..size]).unwrap();
+
+        assert_eq!(size, 2 + 12 + 26 + 24 * 16);
+        assert_eq!(&vec[..2], &((size - 2) as u16).to_be_bytes());
+        assert_eq!(parsed, dns_packet);
+        assert_eq!(write_tcp_vec(&dns_packet).unwrap(), &vec[..size]);
+    }
+
+    #[test]
+    fn writing_past_512_bytes_truncates() {
+        let record = DnsRecord::A {
+            domain: "google.com".to_string(),
+            addr: Ipv4Addr::new(216, 58, 211, 142),
+            ttl: 293,
+        };
+        let ns = DnsRecord::NS {
+            domain: "google.com".to_string(),
+            host: "ns1.google.com".to_string(),
+            ttl: 3600,
+        };
+        let opt = DnsRecord::OPT {
+            udp_payload_size: 512,
+            ext_rcode: 0,
+            version: 0,
+            flags: 0,
+            data: vec![],
+        };
+        let mut dns_packet = DnsPacket::query(43087, "mail.google.com", QueryType::A);
+        dns_packet.header.response = true;
+        dns_packet.header.answers = 40;
+        dns_packet.header.authoritative_entries = 1;
+        dns_packet.header.resource_entries = 1;
+        dns_packet.answers = vec![record.clone(); 40];
+        dns_packet.authorities = vec![ns];
+        dns_packet.resources = vec![opt.clone()];
+
+        let mut vec = vec![0u8; 4096];
+        let size = write(&mut vec, &dns_packet).unwrap();
+        let parsed = DnsPacket::parse(&vec[..size]).unwrap();
+
+        // Header (12), question (21) and OPT (11) leave room for 29 of the A records (16 each,
+        // their names pointing into the question)
+        assert_eq!(size, 12 + 21 + 29 * 16 + 11);
+        assert!(parsed.header.truncated_message);
+        assert_eq!(parsed.header.answers, 29);
+        assert_eq!(parsed.header.authoritative_entries, 0);
+        assert_eq!(parsed.header.resource_entries, 1);
+        assert_eq!(parsed.answers, vec![record; 29]);
+        assert_eq!(parsed.authorities, vec![]);
+        assert_eq!(parsed.resources, vec![opt]);
+    }
+
+    #[test]
+    fn estimated_size_covers_written_size() {
+        let soa = DnsRecord::SOA {
+            domain: "google.com".to_string(),
+            m_name: "ns1.google.com".to_string(),
+            r_name: "dns-admin.google.com".to_string(),
+            serial: 1,
+            refresh: 900,
+            retry: 900,
+            expire: 1800,
+            minimum: 60,
+            ttl: 60,
+        };
+        let txt = DnsRecord::TXT {
+            domain: "google.com".to_string(),
+            data: vec!["v=spf1 -all".to_string(), "".to_string()],
+            ttl: 300,
+        };
+        let opt = DnsRecord::OPT {
+            udp_payload_size: 1232,
+            ext_rcode: 0,
+            version: 0,
+            flags: 0,
+            data: vec![],
+        };
+        let mut dns_packet = DnsPacket::query(43087, "google.com", QueryType::TXT);
+        dns_packet.answers = vec![txt];
+        dns_packet.authorities = vec![soa];
+        dns_packet.resources = vec![opt];
+        dns_packet.header.answers = 1;
+        dns_packet.header.authoritative_entries = 1;
+        dns_packet.header.resource_entries = 1;
+
+        let written = write_vec(&dns_packet).unwrap().len();
+        let mut buf = vec![0u8; 512];
+        let uncompressed =
+            write_with_options(&mut buf, &dns_packet, WriteOptions { compress: false }).unwrap();
+
+        assert!(dns_packet.estimated_size() >= written);
+        // Only the root name of the OPT record is counted a byte too long
+        assert_eq!(dns_packet.estimated_size(), uncompressed + 1);
+    }
+
+    #[test]
+    fn writing_past_end_of_buffer_fails() {
+        let dns_packet = DnsPacket::query(43087, "google.com", QueryType::A);
+
+        let mut vec = vec![0u8; 20];
+        let error = write(&mut vec, &dns_packet).unwrap_err();
+
+        assert_eq!(error.to_string(), "End of buffer");
+        assert!(write_tcp(&mut vec, &dns_packet).is_err());
+    }
+
+    #[test]
+    fn writing_overlong_domain_name_fails() {
+        let name = vec!["a".repeat(50); 6].join(".");
+        assert!(name.len() > 300);
+
+        let question = DnsQuestion::builder()
+            .name(name)
+            .qtype(QueryType::A)
+            .build();
+        let header = DnsHeader::builder().id(43087).questions(1).build();
+        let dns_packet = DnsPacket::builder()
+            .header(header)
+            .questions(vec![question])
+            .build();
+
+        let mut vec = vec![0u8; 512];
+        let error = write(&mut vec, &dns_packet).unwrap_err();
+
+        assert_eq!(error.to_string(), "Domain name exceeds 255 octets");
+    }
+
+    #[rustfmt::skip]
+    fn google_query() -> [u8; 28] {
+        [
+            0xa8, 0x4f, // identifier
+            0x01, 0x00, // flags
+            0x00, 0x01, // question count
+            0x00, 0x00, // answer count
+            0x00, 0x00, // authority count
+            0x00, 0x00, // additional count
+            0x06, 0x67, 0x6f, 0x6f, 0x67, 0x6c, 0x65, 0x03, 0x63, 0x6f, 0x6d, 0x00, // google.com
+            0x00, 0x01, // query type
+            0x00, 0x01, // query question
+        ]
+    }
+
+    #[test]
+    fn writing_any_query_works() {
+        let dns_packet = DnsPacket::query(43087, "google.com", QueryType::ANY);
+
+        let bytes = write_vec(&dns_packet).unwrap();
+        let parsed = DnsPacket::parse(&bytes).unwrap();
+
+        assert_eq!(&bytes[24..26], &[0x00, 0xff]);
+        assert_eq!(parsed.qtype(), Some(QueryType::ANY));
+    }
+
+    #[test]
+    fn writing_question_class_works() {
+        let mut dns_packet = DnsPacket::query(43087, "version.bind", QueryType::TXT);
+        dns_packet.questions[0].class = DnsClass::CH;
+
+        let bytes = write_vec(&dns_packet).unwrap();
+        let parsed = DnsPacket::parse(&bytes).unwrap();
+
+        assert_eq!(&bytes[28..30], &[0x00, 0x03]);
+        assert_eq!(parsed.questions, dns_packet.questions);
+    }
+
+    #[cfg(feature = "idna")]
+    #[test]
+    fn estimated_size_covers_internationalized_names() {
+        let mut dns_packet = DnsPacket::query(43087, "münchen.de", QueryType::CNAME);
+        dns_packet.answers = vec![DnsRecord::CNAME {
+            domain: "münchen.de".to_string(),
+            host: "www.münchen.de".to_string(),
+            ttl: 300,
+        }];
+        dns_packet.header.answers = 1;
+
+        let written = write_vec(&dns_packet).unwrap().len();
+        let mut buf = vec![0u8; 512];
+        let uncompressed =
+            write_with_options(&mut buf, &dns_packet, WriteOptions { compress: false }).unwrap();
+
+        assert!(dns_packet.estimated_size() >= written);
+        // Every name is counted the way it is written, e.g. xn--mnchen-3ya.de rather than the 11
+        // bytes münchen.de takes in UTF-8
+        assert_eq!(dns_packet.estimated_size(), uncompressed);
+    }
+
+    #[cfg(not(feature = "idna"))]
+    #[test]
+    fn writing_unicode_names_fails_without_idna() {
+        let dns_packet = DnsPacket::query(43087, "münchen.de", QueryType::A);
+
+        let mut vec = vec![0u8; 512];
+        let error = write(&mut vec, &dns_packet).unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            "Domain name münchen.de is not ASCII (see the idna feature)"
+        );
+    }
+
+    #[test]
+    fn writing_query_works() {
+        let dns_packet = DnsPacket::query(43087, "google.com", QueryType::A);
+
+        let mut vec = vec![0u8; 512];
+        let size = write(&mut vec, &dns_packet).unwrap();
+
+        assert_eq!(&vec[..size], google_query());
+    }
+
+    #[test]
+    fn writing_unknown_records_works() {
+        let record = DnsRecord::UNKNOWN {
+            domain: "example.com".to_string(),
+            qtype: 65280,
+            data: vec![0xde, 0xad, 0xbe, 0xef],
+            ttl: 300,
+        };
+        let header = DnsHeader::builder().id(1).response(true).answers(1).build();
+        let packet = DnsPacket::builder()
+            .header(header)
+            .answers(vec![record])
+            .build();
+
+        let bytes = write_vec(&packet).unwrap();
+        let parsed = DnsPacket::parse(&bytes).unwrap();
+
+        assert_eq!(parsed, packet);
+    }
+
+    #[test]
+    fn writing_to_vec_works() {
+        let dns_packet = DnsPacket::query(43087, "google.com", QueryType::A);
+
+        let mut vec = vec![0u8; 512];
+        let size = write(&mut vec, &dns_packet).unwrap();
+        let result = write_vec(&dns_packet).unwrap();
+
+        assert_eq!(result, &vec[..size]);
+    }
+
+    #[test]
+    fn trailing_dots_are_not_written_as_labels() {
+        let query = DnsPacket::query(1, "www.example.com.", QueryType::A);
+
+        let written = write_vec(&query).unwrap();
+        let parsed = DnsPacket::parse(&written).unwrap();
+
+        assert_eq!(
+            written,
+            write_vec(&DnsPacket::query(1, "www.example.com", QueryType::A)).unwrap()
+        );
+        assert_eq!(parsed.qname(), Some("www.example.com".to_string()));
+    }
+
+    #[test]
+    fn empty_labels_are_not_written() {
+        let mut vec = vec![0u8; 512];
+
+        for name in &["a..b", ".example.com", "example.com.."] {
+            let dns_packet = DnsPacket::query(1, name, QueryType::A);
+            let error = write(&mut vec, &dns_packet).unwrap_err();
+
+            assert_eq!(
+                error.to_string(),
+                format!("Domain name {:?} contains an empty label", name)
+            );
+        }
+    }
+
+    #[test]
+    fn root_queries_round_trip() {
+        let query = DnsPacket::query(1, ".", QueryType::NS);
+
+        let written = write_vec(&query).unwrap();
+        let parsed = DnsPacket::parse(&written).unwrap();
+
+        // The header, then the root name on its own and the type and class
+        assert_eq!(&written[12..], &[0x00, 0x00, 0x02, 0x00, 0x01]);
+        assert_eq!(parsed.qname(), Some(String::new()));
+        assert_eq!(parsed.qtype(), Some(QueryType::NS));
+    }
+
+    #[test]
+    fn writing_to_io_works() {
+        let dns_packet = DnsPacket::query(43087, "google.com", QueryType::A);
+
+        let mut vec = vec![0u8; 512];
+        let size = write(&mut vec, &dns_packet).unwrap();
+        let mut sink = vec![0xff];
+        let written = write_io(&mut sink, &dns_packet).unwrap();
+        let too_long = DnsPacket::query(1, &"x".repeat(64), QueryType::A);
+        let error = write_io(&mut sink, &too_long).unwrap_err();
+
+        assert_eq!(written, size);
+        assert_eq!(sink[0], 0xff);
+        assert_eq!(&sink[1..], &vec[..size]);
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn writing_to_io_does_not_truncate() {
+        let record = DnsRecord::A {
+            domain: "google.com".to_string(),
+            addr: Ipv4Addr::new(216, 58, 211, 142),
+            ttl: 293,
+        };
+        let mut dns_packet = DnsPacket::query(43087, "google.com", QueryType::A);
+        dns_packet.header.response = true;
+        dns_packet.header.answers = 40;
+        dns_packet.answers = vec![record; 40];
+
+        let mut sink = vec![];
+        let written = write_io(&mut sink, &dns_packet).unwrap();
+        let parsed = DnsPacket::parse(&sink).unwrap();
+
+        // Header (12), question (16) and the A records (16 each), well over 512 bytes
+        assert_eq!(written, 12 + 16 + 40 * 16);
+        assert_eq!(sink.len(), written);
+        assert!(!parsed.header.truncated_message);
+        assert_eq!(parsed, dns_packet);
+    }
+}
+
+// Round trips randomly generated packets through the writer and the parser. Should a case fail,
+// proptest shrinks it and stores its seed under `proptest-regressions/`, which gets replayed ahead
+// of any new cases on every run after. Check that file in to keep the case around. The number of
+// cases can be changed through the PROPTEST_CASES environment variable.
+#[cfg(test)]
+mod proptests {
+    use proptest::{collection::vec, prelude::*};
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use crate::types::{DnsHeader, DnsPacket, DnsQuestion, DnsRecord, QueryType, ResponseCode};
+
+    // Kept short so that even the largest packets fit in 512 bytes
+    fn domain() -> impl Strategy<Value = String> {
+        vec("[a-z0-9]{1,6}", 1..=3).prop_map(|labels| labels.join("."))
+    }
+
+    fn question() -> impl Strategy<Value = DnsQuestion> {
+        let qtype = prop_oneof![
+            Just(QueryType::A),
+            Just(QueryType::AAAA),
+            Just(QueryType::NS),
+            Just(QueryType::CNAME),
+            Just(QueryType::MX),
+        ];
+
+        (domain(), qtype)
+            .prop_map(|(name, qtype)| DnsQuestion::builder().name(name).qtype(qtype).build())
+    }
+
+    fn record() -> impl Strategy<Value = DnsRecord> {
+        prop_oneof![
+            (domain(), any::<u32>(), any::<u32>()).prop_map(|(domain, addr, ttl)| DnsRecord::A {
+                domain,
+                addr: Ipv4Addr::from(addr),
+                ttl
+            }),
+            (domain(), any::<u128>(), any::<u32>()).prop_map(|(domain, addr, ttl)| {
+                DnsRecord::AAAA {
+                    domain,
+                    addr: Ipv6Addr::from(addr),
+                    ttl,
+                }
+            }),
+            (domain(), domain(), any::<u32>()).prop_map(|(domain, host, ttl)| DnsRecord::NS {
+                domain,
+                host,
+                ttl
+            }),
+            (domain(), domain(), any::<u32>()).prop_map(|(domain, host, ttl)| DnsRecord::CNAME {
+                domain,
+                host,
+                ttl
+            }),
+            (domain(), any::<u16>(), domain(), any::<u32>()).prop_map(
+                |(domain, priority, host, ttl)| DnsRecord::MX {
+                    domain,
+                    priority,
+                    host,
+                    ttl,
+                }
+            ),
+            // Types in the private use range (RFC 6895), which will never get a variant of their own
+            (
+                domain(),
+                65280u16..=65534,
+                vec(any::<u8>(), 0..16),
+                any::<u32>()
+            )
+                .prop_map(|(domain, qtype, data, ttl)| DnsRecord::UNKNOWN {
+                    domain,
+                    qtype,
+                    data,
+                    ttl,
+                }),
+        ]
+    }
+
+    fn packet() -> impl Strategy<Value = DnsPacket> {
+        (
+            any::<u16>(),
+            any::<[bool; 8]>(),
+            0u8..16,
+            0u16..16,
+            vec(question(), 0..=1),
+            vec(record(), 0..3),
+            vec(record(), 0..2),
+            vec(record(), 0..2),
+        )
+            .prop_map(
+                |(id, flags, opcode, rescode, questions, answers, authorities, resources)| {
+                    let header = DnsHeader::builder()
+                        .id(id)
+                        .response(flags[0])
+                        .opcode(opcode)
+                        .authoritative_answer(flags[1])
+                        .truncated_message(flags[2])
+                        .recursion_desired(flags[3])
+                        .recursion_available(flags[4])
+                        .z(flags[5])
+                        .authed_data(flags[6])
+                        .checking_disabled(flags[7])
+                        .rescode(ResponseCode::from_num(rescode))
+                        .questions(questions.len() as u16)
+                        .answers(answers.len() as u16)
+                        .authoritative_entries(authorities.len() as u16)
+                        .resource_entries(resources.len() as u16)
+                        .build();
+
+                    DnsPacket::builder()
+                        .header(header)
+                        .questions(questions)
+                        .answers(answers)
+                        .authorities(authorities)
+                        .resources(resources)
+                        .build()
+                },
+            )
+    }
+
+    proptest! {
+        #[test]
+        fn written_packets_parse_back(packet in packet()) {
+            let bytes = super::write_vec(&packet).unwrap();
+            let parsed = DnsPacket::parse(&bytes).unwrap();
+
+            prop_assert_eq!(parsed, packet);
+        }
+
+        #[test]
+        fn estimated_size_is_never_too_small(packet in packet()) {
+            let bytes = super::write_vec(&packet).unwrap();
+
+            prop_assert!(packet.estimated_size() >= bytes.len());
+        }
+    }
 }
@@ -1,47 +1,81 @@
 use log::debug;
-use std::{convert::TryFrom, net::UdpSocket};
+use std::net::{IpAddr, UdpSocket};
 use structopt::StructOpt;
 
-use rdns::{write_packet, DnsHeader, DnsPacket, DnsQuestion, QueryType, MAX_PACKET_SIZE};
+use rdns::{
+    recursive_lookup_traced, reverse_name, reverse_name_v6, validate_qname, write_packet,
+    DnsPacket, QueryFlags, QueryType, MAX_EDNS_PACKET_SIZE, MAX_PACKET_SIZE,
+};
 
 static RESOLVER_SERVER: (&str, u16) = ("8.8.8.8", 53);
 static UDP_RESPONSE_LISTENER: (&str, u16) = ("0.0.0.0", 2053);
 
-fn mk_query(qname: &str, qtype: QueryType) -> DnsPacket {
-    let header = DnsHeader::builder()
-        .id(818) // This should be a random number
-        .questions(1)
-        .recursion_desired(true)
-        .build();
-
-    let question = DnsQuestion {
-        name: qname.to_string(),
-        qtype,
-    };
-
-    DnsPacket::builder()
-        .header(header)
-        .questions(vec![question])
-        .build()
-}
-
 #[derive(Debug, StructOpt)]
 #[structopt(name = "DNS Client", about = "Search DNS records for a given query")]
 struct Opt {
-    #[structopt(short, long, default_value = "google.com")]
+    #[structopt(short, long, default_value = "google.com", parse(try_from_str = parse_query))]
     query: String,
+    /// Look up the names of an IPv4 or IPv6 address (a PTR query), instead of the query
+    #[structopt(short = "x", long)]
+    reverse: Option<IpAddr>,
+    /// Resolve the query starting at the root servers and show every name server consulted
+    #[structopt(long)]
+    trace: bool,
+    /// Ask for records even if they fail DNSSEC validation (sets the CD bit)
+    #[structopt(long)]
+    checking_disabled: bool,
+    /// Ask whether the records were validated (sets the AD bit)
+    #[structopt(long)]
+    authed_data: bool,
+}
+
+// Bad names are turned down before anything is sent
+fn parse_query(query: &str) -> Result<String, String> {
+    validate_qname(query)?;
+
+    Ok(query.to_string())
 }
 
 fn main() -> anyhow::Result<()> {
     env_logger::init();
     debug!("parsing args");
 
-    let Opt { query } = StructOpt::from_args();
-    debug!("args {}", query);
+    let Opt {
+        query,
+        reverse,
+        trace,
+        checking_disabled,
+        authed_data,
+    } = StructOpt::from_args();
+    debug!("args {} (trace: {})", query, trace);
+
+    let (query, qtype) = match reverse {
+        Some(IpAddr::V4(ip)) => (reverse_name(ip), QueryType::PTR),
+        Some(IpAddr::V6(ip)) => (reverse_name_v6(ip), QueryType::PTR),
+        None => (query, QueryType::A),
+    };
+
+    if trace {
+        let (response, steps) = recursive_lookup_traced(&query, qtype)?;
+        for step in steps {
+            println!(
+                "{:?} from {} ({:?})",
+                step.outcome, step.server, step.rescode
+            );
+        }
+        print_packet(&response);
+
+        return Ok(());
+    }
 
     let socket = UdpSocket::bind(UDP_RESPONSE_LISTENER)?;
 
-    let request = mk_query(&query, QueryType::A);
+    let flags = QueryFlags {
+        checking_disabled,
+        authed_data,
+    };
+    // The id should be a random number
+    let request = DnsPacket::query_with_flags(818, &query, qtype, flags);
     debug!("Request: {:#?}", request);
 
     let mut req_buffer = vec![0u8; MAX_PACKET_SIZE];
@@ -49,10 +83,10 @@ fn main() -> anyhow::Result<()> {
 
     socket.send_to(&req_buffer[..size], RESOLVER_SERVER)?;
 
-    let mut response_buffer = vec![0u8; MAX_PACKET_SIZE];
+    let mut response_buffer = vec![0u8; MAX_EDNS_PACKET_SIZE];
     let (size, _src) = socket.recv_from(&mut response_buffer)?;
 
-    let response = DnsPacket::try_from(&response_buffer[..size]).map_err(anyhow::Error::msg)?;
+    let response = DnsPacket::parse(&response_buffer[..size])?;
 
     print_packet(&response);
 
@@ -63,15 +97,15 @@ pub fn print_packet(packet: &DnsPacket) {
     println!("{:#?}", packet.header);
 
     for q in &packet.questions {
-        println!("{:#?}", q);
+        println!(";{}.\tIN\t{}", q.name, q.qtype);
     }
     for rec in &packet.answers {
-        println!("{:#?}", rec);
+        println!("{}", rec);
     }
     for rec in &packet.authorities {
-        println!("{:#?}", rec);
+        println!("{}", rec);
     }
     for rec in &packet.resources {
-        println!("{:#?}", rec);
+        println!("{}", rec);
     }
 }
@@ -0,0 +1,485 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::{DnsPacket, DnsRecord, QueryType};
+
+// Upper bound for how long negative responses are cached, whatever their SOA says (RFC 2308
+// section 5 recommends one to three hours)
+pub const MAX_NEGATIVE_TTL: u32 = 3 * 60 * 60;
+// How many responses are cached unless told otherwise, see `Cache::with_max_entries`
+pub const DEFAULT_MAX_ENTRIES: usize = 10_000;
+// The TTL of the records in stale responses, so that they are not held on to downstream for long
+// (RFC 8767 section 4)
+pub const STALE_TTL: u32 = 30;
+
+// Responses of recursive lookups, kept around for as long as their records live. What gets served
+// from here carries the TTLs the records have left, not the ones they came in with, so that caches
+// further downstream do not hold on to them for longer than they should.
+//
+// The cache is meant to be shared by all the requests being served at the same time. It holds on
+// to a limited number of responses, making room for new ones by dropping those that have not been
+// asked for the longest, whether or not they expired.
+//
+// Expired responses can be kept around for a while longer, to be served stale while fresh ones are
+// being looked up (RFC 8767), see `Cache::with_max_stale`.
+#[derive(Debug)]
+pub struct Cache {
+    entries: Mutex<Entries>,
+    max_entries: usize,
+    max_stale: Duration,
+}
+
+impl Default for Cache {
+    fn default() -> Cache {
+        Cache::with_max_entries(DEFAULT_MAX_ENTRIES)
+    }
+}
+
+type Key = (String, QueryType);
+
+// The entries along with the order they were last used in
+#[derive(Debug, Default)]
+struct Entries {
+    by_key: HashMap<Key, CacheEntry>,
+    // Least recently used first
+    by_use: BTreeMap<u64, Key>,
+    // Goes up with every use of an entry
+    clock: u64,
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    response: DnsPacket,
+    inserted: Instant,
+    // Negative responses (NXDOMAIN or NODATA) have no answers to expire with, they are kept for
+    // this many seconds instead
+    negative_ttl: Option<u32>,
+    // When the entry was last used, see `Entries::clock`
+    last_used: u64,
+}
+
+impl CacheEntry {
+    // When the last of the answers runs out, or the negative TTL for negative responses
+    fn expires_at(&self) -> Instant {
+        let ttl = match self.negative_ttl {
+            Some(ttl) => ttl,
+            None => self
+                .response
+                .answers
+                .iter()
+                .map(DnsRecord::ttl)
+                .max()
+                .unwrap_or(0),
+        };
+
+        self.inserted + Duration::from_secs(ttl as u64)
+    }
+}
+
+impl Entries {
+    // Makes room for the entry by evicting the least recently used ones, if need be
+    fn insert(&mut self, key: Key, mut entry: CacheEntry, max_entries: usize) {
+        self.remove(&key);
+        if max_entries == 0 {
+            return;
+        }
+        while self.by_key.len() >= max_entries {
+            match self.by_use.pop_first() {
+                Some((_, oldest)) => self.by_key.remove(&oldest),
+                None => break,
+            };
+        }
+
+        self.clock += 1;
+        entry.last_used = self.clock;
+        self.by_use.insert(self.clock, key.clone());
+        self.by_key.insert(key, entry);
+    }
+
+    // Looks up the entry, making it the most recently used one
+    fn touch(&mut self, key: &Key) -> Option<&CacheEntry> {
+        let entry = self.by_key.get_mut(key)?;
+        self.by_use.remove(&entry.last_used);
+        self.clock += 1;
+        entry.last_used = self.clock;
+        self.by_use.insert(self.clock, key.clone());
+
+        Some(entry)
+    }
+
+    fn remove(&mut self, key: &Key) {
+        if let Some(entry) = self.by_key.remove(key) {
+            self.by_use.remove(&entry.last_used);
+        }
+    }
+}
+
+impl Cache {
+    pub fn new() -> Cache {
+        Cache::default()
+    }
+
+    pub fn with_max_entries(max_entries: usize) -> Cache {
+        Cache {
+            entries: Mutex::default(),
+            max_entries,
+            max_stale: Duration::ZERO,
+        }
+    }
+
+    // Keeps expired responses for this long past their expiry, for `get_allowing_stale`. Those do
+    // count towards the maximum number of entries.
+    pub fn with_max_stale(mut self, max_stale: Duration) -> Cache {
+        self.max_stale = max_stale;
+        self
+    }
+
+    pub fn max_stale(&self) -> Duration {
+        self.max_stale
+    }
+
+    pub fn insert(&self, qname: &str, qtype: QueryType, response: DnsPacket) {
+        self.insert_at(qname, qtype, response, Instant::now())
+    }
+
+    // Remembers that the name (or records of the type) does not exist for `soa_minimum` seconds,
+    // see `negative_ttl`
+    pub fn insert_negative(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        response: DnsPacket,
+        soa_minimum: u32,
+    ) {
+        self.insert_negative_at(qname, qtype, response, soa_minimum, Instant::now())
+    }
+
+    pub fn get(&self, qname: &str, qtype: QueryType) -> Option<DnsPacket> {
+        self.get_at(qname, qtype, Instant::now())
+    }
+
+    // Like `get`, falling back to a response that expired no more than `max_stale` ago. The
+    // records of stale responses all get a TTL of `STALE_TTL`. Responses are never kept for longer
+    // than the cache was told to, see `with_max_stale`.
+    pub fn get_allowing_stale(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        max_stale: Duration,
+    ) -> Option<DnsPacket> {
+        self.get_allowing_stale_at(qname, qtype, max_stale, Instant::now())
+    }
+
+    // `insert` and `get` with the clock in the hands of the caller
+    pub(crate) fn insert_at(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        response: DnsPacket,
+        now: Instant,
+    ) {
+        let entry = CacheEntry {
+            response,
+            inserted: now,
+            negative_ttl: None,
+            last_used: 0,
+        };
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(key(qname, qtype), entry, self.max_entries);
+    }
+
+    pub(crate) fn insert_negative_at(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        mut response: DnsPacket,
+        soa_minimum: u32,
+        now: Instant,
+    ) {
+        let negative_ttl = soa_minimum.min(MAX_NEGATIVE_TTL);
+        // The SOA is served with what is left of the negative TTL (RFC 2308 section 3)
+        for record in &mut response.authorities {
+            if record.query_type() == QueryType::SOA {
+                record.set_ttl(record.ttl().min(negative_ttl));
+            }
+        }
+
+        let entry = CacheEntry {
+            response,
+            inserted: now,
+            negative_ttl: Some(negative_ttl),
+            last_used: 0,
+        };
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(key(qname, qtype), entry, self.max_entries);
+    }
+
+    pub(crate) fn get_at(&self, qname: &str, qtype: QueryType, now: Instant) -> Option<DnsPacket> {
+        let key = key(qname, qtype);
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.touch(&key)?;
+
+        let elapsed = now.saturating_duration_since(entry.inserted);
+        let mut response = entry.response.clone();
+        age_records(&mut response.answers, elapsed);
+        age_records(&mut response.authorities, elapsed);
+        age_records(&mut response.resources, elapsed);
+
+        let expired = match entry.negative_ttl {
+            Some(ttl) => elapsed.as_secs() >= ttl as u64,
+            None => response.answers.is_empty(),
+        };
+        if expired {
+            if now.saturating_duration_since(entry.expires_at()) >= self.max_stale {
+                entries.remove(&key);
+            }
+            return None;
+        }
+
+        response.header.answers = response.answers.len() as u16;
+        response.header.authoritative_entries = response.authorities.len() as u16;
+        response.header.resource_entries = response.resources.len() as u16;
+
+        Some(response)
+    }
+
+    pub(crate) fn get_allowing_stale_at(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        max_stale: Duration,
+        now: Instant,
+    ) -> Option<DnsPacket> {
+        if let Some(response) = self.get_at(qname, qtype, now) {
+            return Some(response);
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.touch(&key(qname, qtype))?;
+        if now.saturating_duration_since(entry.expires_at()) >= max_stale {
+            return None;
+        }
+
+        let mut response = entry.response.clone();
+        let records = response
+            .answers
+            .iter_mut()
+            .chain(response.authorities.iter_mut())
+            .chain(response.resources.iter_mut());
+        for record in records {
+            if record.query_type() != QueryType::OPT {
+                record.set_ttl(STALE_TTL);
+            }
+        }
+
+        Some(response)
+    }
+}
+
+// How long a negative response can be cached for: the smaller of the minimum and the TTL of the
+// SOA it came with (RFC 2308 section 5). Those without a SOA are not to be cached at all.
+pub fn negative_ttl(response: &DnsPacket) -> Option<u32> {
+    if !response.is_nxdomain() && !response.is_nodata() {
+        return None;
+    }
+
+    response.authorities.iter().find_map(|record| match record {
+        DnsRecord::SOA { minimum, ttl, .. } => Some((*minimum).min(*ttl)),
+        _ => None,
+    })
+}
+
+fn key(qname: &str, qtype: QueryType) -> Key {
+    (qname.to_ascii_lowercase(), qtype)
+}
+
+// Takes the time spent in the cache off the TTL of the records, dropping the ones that expired
+fn age_records(records: &mut Vec<DnsRecord>, elapsed: Duration) {
+    let elapsed = elapsed.as_secs().min(u32::MAX as u64) as u32;
+
+    records.retain_mut(|record| {
+        // OPT records do not have a TTL and are not cached to begin with
+        if record.query_type() == QueryType::OPT {
+            return true;
+        }
+        match record.ttl().checked_sub(elapsed) {
+            Some(ttl) if ttl > 0 => {
+                record.set_ttl(ttl);
+                true
+            }
+            _ => false,
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{DnsHeader, ResponseCode};
+    use pretty_assertions::assert_eq;
+    use std::net::Ipv4Addr;
+
+    fn a_response(ttl: u32) -> DnsPacket {
+        let header = DnsHeader::builder().id(1).response(true).answers(1).build();
+        let record = DnsRecord::A {
+            domain: "example.com".to_string(),
+            addr: Ipv4Addr::new(1, 2, 3, 4),
+            ttl,
+        };
+
+        DnsPacket::builder()
+            .header(header)
+            .answers(vec![record])
+            .build()
+    }
+
+    fn nxdomain_response(minimum: u32) -> DnsPacket {
+        let header = DnsHeader::builder()
+            .id(1)
+            .response(true)
+            .rescode(ResponseCode::NXDOMAIN)
+            .authoritative_entries(1)
+            .build();
+        let soa = DnsRecord::SOA {
+            domain: "example.com".to_string(),
+            m_name: "ns.example.com".to_string(),
+            r_name: "hostmaster.example.com".to_string(),
+            serial: 1,
+            refresh: 7200,
+            retry: 3600,
+            expire: 1209600,
+            minimum,
+            ttl: 3600,
+        };
+
+        DnsPacket::builder()
+            .header(header)
+            .authorities(vec![soa])
+            .build()
+    }
+
+    #[test]
+    fn served_ttl_is_decremented() {
+        let cache = Cache::new();
+        let start = Instant::now();
+        cache.insert_at("example.com", QueryType::A, a_response(10), start);
+
+        let response = cache
+            .get_at("EXAMPLE.com", QueryType::A, start + Duration::from_secs(4))
+            .unwrap();
+
+        assert_eq!(response.answers[0].ttl(), 6);
+    }
+
+    #[test]
+    fn expired_records_are_dropped() {
+        let cache = Cache::new();
+        let start = Instant::now();
+        cache.insert_at("example.com", QueryType::A, a_response(10), start);
+
+        let expired = cache.get_at("example.com", QueryType::A, start + Duration::from_secs(10));
+        let missing = cache.get_at("example.com", QueryType::AAAA, start);
+
+        assert_eq!(expired, None);
+        assert_eq!(missing, None);
+        assert!(cache.entries.lock().unwrap().by_key.is_empty());
+    }
+
+    #[test]
+    fn stale_responses_are_served_within_max_stale() {
+        let cache = Cache::new().with_max_stale(Duration::from_secs(60));
+        let start = Instant::now();
+        cache.insert_at("example.com", QueryType::A, a_response(10), start);
+        let expired = start + Duration::from_secs(30);
+        let max_stale = Duration::from_secs(60);
+
+        let fresh = cache.get_at("example.com", QueryType::A, expired);
+        let stale = cache
+            .get_allowing_stale_at("example.com", QueryType::A, max_stale, expired)
+            .unwrap();
+        let too_stale = cache.get_allowing_stale_at(
+            "example.com",
+            QueryType::A,
+            Duration::from_secs(10),
+            expired,
+        );
+        let gone = cache.get_at("example.com", QueryType::A, start + Duration::from_secs(70));
+
+        assert_eq!(fresh, None);
+        assert_eq!(stale.answers, a_response(STALE_TTL).answers);
+        assert_eq!(too_stale, None);
+        assert_eq!(gone, None);
+        assert!(cache.entries.lock().unwrap().by_key.is_empty());
+    }
+
+    #[test]
+    fn least_recently_used_entries_are_evicted() {
+        let cache = Cache::with_max_entries(2);
+        let start = Instant::now();
+        cache.insert_at("a.example.com", QueryType::A, a_response(10), start);
+        cache.insert_at("b.example.com", QueryType::A, a_response(10), start);
+        cache.get_at("a.example.com", QueryType::A, start).unwrap();
+
+        cache.insert_at("c.example.com", QueryType::A, a_response(10), start);
+
+        assert!(cache.get_at("a.example.com", QueryType::A, start).is_some());
+        assert_eq!(cache.get_at("b.example.com", QueryType::A, start), None);
+        assert!(cache.get_at("c.example.com", QueryType::A, start).is_some());
+        assert_eq!(cache.entries.lock().unwrap().by_use.len(), 2);
+    }
+
+    #[test]
+    fn negative_responses_are_cached_for_soa_minimum() {
+        let cache = Cache::new();
+        let start = Instant::now();
+        let response = nxdomain_response(300);
+        let ttl = negative_ttl(&response).unwrap();
+        cache.insert_negative_at("missing.example.com", QueryType::A, response, ttl, start);
+
+        let cached = cache
+            .get_at(
+                "missing.example.com",
+                QueryType::A,
+                start + Duration::from_secs(100),
+            )
+            .unwrap();
+        let expired = cache.get_at(
+            "missing.example.com",
+            QueryType::A,
+            start + Duration::from_secs(300),
+        );
+
+        assert_eq!(ttl, 300);
+        assert_eq!(cached.rescode(), ResponseCode::NXDOMAIN);
+        assert_eq!(cached.authorities[0].ttl(), 200);
+        assert_eq!(expired, None);
+    }
+
+    #[test]
+    fn negative_ttl_is_capped() {
+        let cache = Cache::new();
+        let start = Instant::now();
+        let response = nxdomain_response(u32::MAX);
+        cache.insert_negative_at(
+            "missing.example.com",
+            QueryType::A,
+            response,
+            u32::MAX,
+            start,
+        );
+
+        let later = start + Duration::from_secs(MAX_NEGATIVE_TTL as u64);
+
+        assert_eq!(
+            cache.get_at("missing.example.com", QueryType::A, later),
+            None
+        );
+    }
+}
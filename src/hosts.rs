@@ -0,0 +1,141 @@
+use std::{collections::HashMap, fs, net::IpAddr, path::Path};
+
+use anyhow::Context;
+
+use crate::DnsRecord;
+
+// Hosts entries are pinned by whoever runs the server and can change at any moment, so clients
+// should not hold on to them for long
+pub const HOSTS_TTL: u32 = 60;
+
+// The good old hosts file (see hosts(5)), mapping names to addresses:
+//
+//     # comments start with a hash
+//     127.0.0.1   localhost
+//     192.0.2.10  printer.lan printer
+//     2001:db8::1 printer.lan
+//
+// A name can show up on several lines, all of its addresses are kept.
+pub fn load_hosts<P: AsRef<Path>>(path: P) -> anyhow::Result<HashMap<String, Vec<IpAddr>>> {
+    let path = path.as_ref();
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("Unable to read hosts file {}", path.display()))?;
+
+    parse_hosts(&contents)
+}
+
+pub fn parse_hosts(contents: &str) -> anyhow::Result<HashMap<String, Vec<IpAddr>>> {
+    let mut hosts: HashMap<String, Vec<IpAddr>> = HashMap::new();
+
+    for (idx, line) in contents.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let mut fields = line.split_whitespace();
+        let addr = fields
+            .next()
+            .unwrap_or_default()
+            .parse::<IpAddr>()
+            .with_context(|| format!("Invalid address on line {}", idx + 1))?;
+        for name in fields {
+            let name = name.trim_end_matches('.').to_ascii_lowercase();
+            hosts.entry(name).or_default().push(addr);
+        }
+    }
+
+    Ok(hosts)
+}
+
+// A and AAAA records for the entries, so they can be answered just like the ones of a zone
+pub fn hosts_records(hosts: &HashMap<String, Vec<IpAddr>>) -> Vec<DnsRecord> {
+    let mut records = hosts
+        .iter()
+        .flat_map(|(name, addrs)| {
+            addrs.iter().map(move |addr| match *addr {
+                IpAddr::V4(addr) => DnsRecord::A {
+                    domain: name.clone(),
+                    addr,
+                    ttl: HOSTS_TTL,
+                },
+                IpAddr::V6(addr) => DnsRecord::AAAA {
+                    domain: name.clone(),
+                    addr,
+                    ttl: HOSTS_TTL,
+                },
+            })
+        })
+        .collect::<Vec<_>>();
+    // Iteration order of the map is random, keep the records stable
+    records.sort();
+
+    records
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    const HOSTS: &str = "
+# The usual suspects
+127.0.0.1   localhost
+192.0.2.10  printer.lan Printer   # the one on the 2nd floor
+
+2001:db8::1 printer.lan.
+";
+
+    #[test]
+    fn parse_hosts_works() {
+        let hosts = parse_hosts(HOSTS).unwrap();
+
+        assert_eq!(hosts.len(), 3);
+        assert_eq!(
+            hosts["localhost"],
+            vec![IpAddr::from(Ipv4Addr::new(127, 0, 0, 1))]
+        );
+        assert_eq!(
+            hosts["printer.lan"],
+            vec![
+                IpAddr::from(Ipv4Addr::new(192, 0, 2, 10)),
+                IpAddr::from("2001:db8::1".parse::<Ipv6Addr>().unwrap()),
+            ]
+        );
+        assert_eq!(
+            hosts["printer"],
+            vec![IpAddr::from(Ipv4Addr::new(192, 0, 2, 10))]
+        );
+    }
+
+    #[test]
+    fn parse_hosts_reports_line_of_invalid_address() {
+        let error = parse_hosts("127.0.0.1 localhost\nlocalhost 127.0.0.1").unwrap_err();
+
+        assert_eq!(error.to_string(), "Invalid address on line 2");
+    }
+
+    #[test]
+    fn hosts_records_works() {
+        let hosts = parse_hosts("192.0.2.10 printer.lan\n2001:db8::1 printer.lan").unwrap();
+
+        let records = hosts_records(&hosts);
+
+        assert_eq!(
+            records,
+            vec![
+                DnsRecord::A {
+                    domain: "printer.lan".to_string(),
+                    addr: Ipv4Addr::new(192, 0, 2, 10),
+                    ttl: HOSTS_TTL,
+                },
+                DnsRecord::AAAA {
+                    domain: "printer.lan".to_string(),
+                    addr: "2001:db8::1".parse().unwrap(),
+                    ttl: HOSTS_TTL,
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,233 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Condvar, Mutex},
+};
+
+use log::debug;
+
+use crate::{DnsPacket, QueryType};
+
+// Recursive lookups that are currently under way. Clients asking the same question at the same
+// time (which happens a lot for popular names) all wait on the first of those lookups instead of
+// each walking down from the root on their own.
+#[derive(Debug, Default)]
+pub struct InFlight {
+    lookups: Mutex<HashMap<(String, QueryType), Arc<Lookup>>>,
+}
+
+#[derive(Debug, Default)]
+struct Lookup {
+    // anyhow errors can not be cloned, so those waiting only get to see the message
+    result: Mutex<Option<Result<DnsPacket, String>>>,
+    done: Condvar,
+}
+
+impl InFlight {
+    pub fn new() -> InFlight {
+        InFlight::default()
+    }
+
+    // Runs `resolve` unless the same lookup is already under way, in which case its result is
+    // waited for and shared
+    pub fn lookup<F>(&self, qname: &str, qtype: QueryType, resolve: F) -> anyhow::Result<DnsPacket>
+    where
+        F: FnOnce() -> anyhow::Result<DnsPacket>,
+    {
+        match self.begin(qname, qtype) {
+            Ok(publish) => publish.run(resolve),
+            Err(lookup) => {
+                debug!("Waiting on lookup in flight for {} ({:?})", qname, qtype);
+                lookup.wait()
+            }
+        }
+    }
+
+    // Same as `lookup` but does not wait on the lookup already under way, if there is one. Those
+    // who do not need the result (e.g. refreshes of what is in the cache) get None then.
+    pub fn lookup_unless_in_flight<F>(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        resolve: F,
+    ) -> Option<anyhow::Result<DnsPacket>>
+    where
+        F: FnOnce() -> anyhow::Result<DnsPacket>,
+    {
+        self.begin(qname, qtype)
+            .ok()
+            .map(|publish| publish.run(resolve))
+    }
+
+    // Registers the lookup as under way, unless it already is in which case that one is returned
+    fn begin(&self, qname: &str, qtype: QueryType) -> Result<Publish<'_>, Arc<Lookup>> {
+        let key = (qname.to_ascii_lowercase(), qtype);
+        let mut lookups = self.lookups.lock().unwrap();
+        if let Some(lookup) = lookups.get(&key) {
+            return Err(Arc::clone(lookup));
+        }
+
+        let lookup = Arc::new(Lookup::default());
+        lookups.insert(key.clone(), Arc::clone(&lookup));
+
+        Ok(Publish {
+            in_flight: self,
+            key,
+            lookup,
+            result: None,
+        })
+    }
+}
+
+impl Lookup {
+    fn wait(&self) -> anyhow::Result<DnsPacket> {
+        let mut result = self.result.lock().unwrap();
+        while result.is_none() {
+            result = self.done.wait(result).unwrap();
+        }
+
+        result.clone().unwrap().map_err(anyhow::Error::msg)
+    }
+}
+
+// Hands the result of a lookup to those waiting on it once it is dropped, which also happens when
+// resolving panics. They get an error then, rather than waiting forever.
+struct Publish<'a> {
+    in_flight: &'a InFlight,
+    key: (String, QueryType),
+    lookup: Arc<Lookup>,
+    result: Option<Result<DnsPacket, String>>,
+}
+
+impl Publish<'_> {
+    fn run<F>(mut self, resolve: F) -> anyhow::Result<DnsPacket>
+    where
+        F: FnOnce() -> anyhow::Result<DnsPacket>,
+    {
+        let result = resolve();
+        self.result = Some(match &result {
+            Ok(response) => Ok(response.clone()),
+            Err(error) => Err(format!("{:#}", error)),
+        });
+
+        result
+    }
+}
+
+impl Drop for Publish<'_> {
+    fn drop(&mut self) {
+        let result = self
+            .result
+            .take()
+            .unwrap_or_else(|| Err(format!("Lookup of {:?} panicked", self.key)));
+        *self.lookup.result.lock().unwrap() = Some(result);
+        self.in_flight.lookups.lock().unwrap().remove(&self.key);
+        self.lookup.done.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{lookup, write_packet, DnsHeader, DnsRecord, MAX_PACKET_SIZE};
+    use pretty_assertions::assert_eq;
+    use std::{
+        net::{Ipv4Addr, UdpSocket},
+        sync::Barrier,
+        thread,
+        time::Duration,
+    };
+
+    // Answers queries (slowly) with an A record until no more come in, returning how many it got
+    fn counting_stub(stub: UdpSocket) -> thread::JoinHandle<usize> {
+        thread::spawn(move || {
+            let mut buf = vec![0u8; MAX_PACKET_SIZE];
+            let mut count = 0;
+            stub.set_read_timeout(Some(Duration::from_millis(500)))
+                .unwrap();
+            while let Ok((size, src)) = stub.recv_from(&mut buf) {
+                count += 1;
+                let request = DnsPacket::parse(&buf[..size]).unwrap();
+                let question = request.first_question().unwrap().clone();
+                let record = DnsRecord::A {
+                    domain: question.name.clone(),
+                    addr: Ipv4Addr::new(1, 2, 3, 4),
+                    ttl: 300,
+                };
+                let header = DnsHeader::builder()
+                    .id(request.header.id)
+                    .response(true)
+                    .questions(1)
+                    .answers(1)
+                    .build();
+                let response = DnsPacket::builder()
+                    .header(header)
+                    .questions(vec![question])
+                    .answers(vec![record])
+                    .build();
+
+                // Quick enough not to have the query retransmitted
+                thread::sleep(Duration::from_millis(100));
+                let size = write_packet(&mut buf, &response).unwrap();
+                stub.send_to(&buf[..size], src).unwrap();
+            }
+
+            count
+        })
+    }
+
+    #[test]
+    fn concurrent_identical_lookups_are_coalesced() {
+        let stub = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let server = stub.local_addr().unwrap();
+        let handle = counting_stub(stub);
+
+        let in_flight = Arc::new(InFlight::new());
+        let barrier = Arc::new(Barrier::new(5));
+        let lookups = (0..5)
+            .map(|_| {
+                let (in_flight, barrier) = (Arc::clone(&in_flight), Arc::clone(&barrier));
+                thread::spawn(move || {
+                    barrier.wait();
+                    in_flight.lookup("example.com", QueryType::A, || {
+                        lookup("example.com", QueryType::A, server)
+                    })
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for lookup in lookups {
+            let response = lookup.join().unwrap().unwrap();
+            assert_eq!(response.get_random_a(), Some(Ipv4Addr::new(1, 2, 3, 4)));
+        }
+
+        assert_eq!(handle.join().unwrap(), 1);
+        assert!(in_flight.lookups.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn panicking_lookups_do_not_hold_up_others() {
+        let in_flight = Arc::new(InFlight::new());
+        let barrier = Arc::new(Barrier::new(2));
+        let panicking = {
+            let (in_flight, barrier) = (Arc::clone(&in_flight), Arc::clone(&barrier));
+            thread::spawn(move || {
+                in_flight.lookup("example.com", QueryType::A, || {
+                    barrier.wait();
+                    thread::sleep(Duration::from_millis(100));
+                    panic!("resolving failed");
+                })
+            })
+        };
+
+        barrier.wait();
+        let waiting = in_flight.lookup("example.com", QueryType::A, || unreachable!());
+        let panicked = panicking.join();
+        let response = DnsPacket::query(1, "example.com", QueryType::A);
+        let next = in_flight.lookup("example.com", QueryType::A, || Ok(response.clone()));
+
+        assert!(waiting.is_err());
+        assert!(panicked.is_err());
+        assert_eq!(next.unwrap(), response);
+        assert!(in_flight.lookups.lock().unwrap().is_empty());
+    }
+}
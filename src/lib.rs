@@ -1,140 +1,1708 @@
-pub use dnsparse::{write_packet, DnsHeader, DnsPacket, DnsQuestion, QueryType, ResponseCode};
+pub mod cache;
+pub mod hosts;
+pub mod in_flight;
+pub mod metrics;
+#[cfg(feature = "tokio")]
+pub mod non_blocking;
+pub mod resolver;
+pub mod server;
+#[cfg(test)]
+pub(crate) mod stub;
+pub mod tcp;
+pub mod zone;
+
+pub use cache::Cache;
+pub use in_flight::InFlight;
+pub use metrics::{Metrics, MetricsSnapshot};
+pub use resolver::{MailExchange, Resolver, ServiceTarget};
+pub use server::{ReservedBitPolicy, Server};
+pub use tcp::TcpConnection;
+
+#[cfg(feature = "tokio")]
+pub use non_blocking::{lookup_async, recursive_lookup_async, resolve_async};
+
+pub use dnsparse::{
+    read_tcp_message, validate_qname, write_packet, write_packet_io, write_packet_tcp,
+    write_packet_tcp_vec, write_packet_vec, write_packet_with_options, DelegationInfo, DnsClass,
+    DnsHeader, DnsPacket, DnsQuestion, DnsRecord, QueryFlags, QueryType, ResponseCode,
+    WriteOptions, EDNS_DO_FLAG,
+};
 use log::{debug, error, info, warn};
+use rand::{seq::SliceRandom, Rng};
 use std::{
-    convert::TryFrom,
-    net::{Ipv4Addr, UdpSocket},
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    sync::{mpsc, Arc},
+    thread,
+    time::{Duration, Instant},
 };
 
+pub const DNS_PORT: u16 = 53;
 pub const MAX_PACKET_SIZE: usize = 512;
+// Largest message we are willing to receive when the other side speaks EDNS
+pub const MAX_EDNS_PACKET_SIZE: usize = 4096;
+// The UDP payload size we advertise to other name servers. Big enough for most responses, small
+// enough for them not to get fragmented on the way (see https://www.dnsflagday.net/2020/).
+pub const DEFAULT_EDNS_PAYLOAD_SIZE: u16 = 1232;
 pub const ROOT_DNS_SERVER: (Ipv4Addr, u16) = (Ipv4Addr::new(198, 41, 0, 4), 53);
 pub const RECURSIVE_DNS_SERVER: (Ipv4Addr, u16) = (Ipv4Addr::new(8, 8, 8, 8), 53);
+// How long we wait on a name server to respond before giving up on it
+pub const LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+// How long a recursive lookup may take as a whole, however many name servers it has to ask
+pub const RESOLUTION_BUDGET: Duration = Duration::from_secs(10);
+// Queries that go unanswered are sent again, first after this long and then twice as long each
+// time up to the maximum (see `retransmit_interval`)
+const INITIAL_RETRANSMIT_INTERVAL: Duration = Duration::from_millis(250);
+const MAX_RETRANSMIT_INTERVAL: Duration = Duration::from_secs(1);
+// How many name servers without glue records we try to resolve at the same time
+const PARALLEL_NS_LOOKUPS: usize = 3;
+// Bounds on how far a (possibly malicious) chain of delegations can drag us along. Resolving the
+// address of a name server without glue may itself run into name servers without glue, and so on.
+pub(crate) const MAX_NS_RESOLUTION_DEPTH: usize = 16;
+pub(crate) const MAX_HOPS: usize = 30;
+// What `health_check` asks about, a name any server out there should be able to answer quickly
+const HEALTH_CHECK_QNAME: &str = "a.root-servers.net";
 
-pub fn resolve(request: DnsPacket) -> anyhow::Result<DnsPacket> {
-    let base_header_builder = DnsHeader::builder()
-        .id(request.header.id)
-        .recursion_desired(true)
-        .recursion_available(true)
-        .response(true);
-
-    let response = if let Some(question) = request.first_question() {
-        let (qname, qtype) = (request.qname().unwrap(), request.qtype().unwrap());
-        info!("Starting recursive lookup for {} ({:?})", qname, qtype);
-
-        match recursive_lookup(&qname, qtype) {
-            Ok(result) => {
-                let header = base_header_builder
-                    .questions(1)
-                    .answers(result.answers.len() as u16)
-                    .authoritative_entries(result.authorities.len() as u16)
-                    .resource_entries(result.resources.len() as u16)
-                    .build();
-                DnsPacket::builder()
-                    .header(header)
-                    .questions(vec![question.clone()])
-                    .answers(result.answers)
-                    .authorities(result.authorities)
-                    .resources(result.resources)
-                    .build()
-            }
-            Err(error) => {
-                let header = base_header_builder.rescode(ResponseCode::SERVFAIL).build();
-                let response = DnsPacket::builder().header(header).build();
-                error!("Server failure: {:?}", error);
-                response
-            }
+// Answers the request from `zone` when we are authoritative for the name, otherwise resolves it
+// by walking down from the root servers.
+//
+// Only a single question per request is supported. The RFC allows for more but never says how the
+// header fields (e.g. rescode, authoritative_answer) should apply to several questions, so like
+// most servers out there we reject such requests with FORMERR instead of guessing.
+pub fn resolve(request: DnsPacket, zone: &[DnsRecord]) -> anyhow::Result<DnsPacket> {
+    Server::builder()
+        .zone(zone.to_vec())
+        .build()
+        .resolve(request)
+}
+
+// Answers the request from the given records alone, as the authoritative server for them. Names
+// none of the records are at (or below) do not exist, the others have no data of the asked for
+// type unless there is a record of it. Negative responses come with the SOA of the zone if the
+// records hold one, so that they can be cached (RFC 2308 section 3).
+pub fn resolve_from_records(request: &DnsPacket, records: &[DnsRecord]) -> DnsPacket {
+    if let Some(response) = answer_locally(request, records) {
+        return response;
+    }
+
+    // Requests without a question were answered above
+    let question = request.first_question().unwrap();
+    let name = question.name.to_ascii_lowercase();
+    let name_exists = records.iter().any(|record| {
+        let domain = record.domain().to_ascii_lowercase();
+        domain == name || domain.ends_with(&format!(".{}", name))
+    });
+    let soa = zone::find_soa(records, &question.name)
+        .into_iter()
+        .collect::<Vec<_>>();
+
+    let mut header = response_header(request);
+    header.authoritative_answer = true;
+    header.questions = 1;
+    header.authoritative_entries = soa.len() as u16;
+    if !name_exists {
+        header.rescode = ResponseCode::NXDOMAIN;
+    }
+
+    DnsPacket::builder()
+        .header(header)
+        .questions(vec![question.clone()])
+        .authorities(soa)
+        .build()
+}
+
+// The response to the request if we can come up with it without asking anyone else, i.e. the
+// request is malformed or the name is in our zone
+pub(crate) fn answer_locally(request: &DnsPacket, zone: &[DnsRecord]) -> Option<DnsPacket> {
+    let question = match request.questions.as_slice() {
+        [question] => question,
+        [] => {
+            let response = recursing(DnsPacket::formerr(request));
+            error!("Client provided insufficient info: {:#?}", response);
+            return Some(response);
+        }
+        questions => {
+            error!(
+                "Client asked {} questions in a single request",
+                questions.len()
+            );
+            return Some(recursing(DnsPacket::formerr(request)));
         }
-    } else {
-        let header = base_header_builder.rescode(ResponseCode::FORMERR).build();
-        let response = DnsPacket::builder().header(header).build();
-        error!("Client provided insufficient info: {:#?}", response);
-        response
     };
 
-    Ok(response)
+    // Only version 0 exists so far, the OPT record `echo_edns` adds tells the client that this is
+    // the one we speak (RFC 6891 section 6.1.3)
+    if let Some(version @ 1..) = request.edns_version() {
+        warn!("Client asked for EDNS version {}", version);
+        return Some(recursing(DnsPacket::badvers(request)));
+    }
+
+    let zone_records = zone::find_records(zone, &question.name, question.qtype);
+    if zone_records.is_empty() {
+        return None;
+    }
+
+    info!(
+        "Answering {} ({:?}) from zone",
+        question.name, question.qtype
+    );
+    let mut header = response_header(request);
+    header.authoritative_answer = true;
+    header.questions = 1;
+    header.answers = zone_records.len() as u16;
+
+    Some(
+        DnsPacket::builder()
+            .header(header)
+            .questions(vec![question.clone()])
+            .answers(zone_records)
+            .build(),
+    )
 }
 
-pub fn recursive_lookup(qname: &str, qtype: QueryType) -> anyhow::Result<DnsPacket> {
-    let mut ns = ROOT_DNS_SERVER;
-    loop {
+// Clients that do not want us to recurse (RD unset) only get told about the name servers closer to
+// the answer we know of from the zone, or get turned away when there are none
+pub(crate) fn refer_locally(request: &DnsPacket, zone: &[DnsRecord]) -> DnsPacket {
+    let mut header = response_header(request);
+    let question = request.first_question().unwrap();
+
+    let (ns_records, glue) = zone::find_delegation(zone, &question.name);
+    if ns_records.is_empty() {
         info!(
-            "attempting lookup of {} ({:?}) with ns {:?}",
-            qname, qtype, ns
+            "Refusing {} ({:?}), recursion was not desired",
+            question.name, question.qtype
         );
+        header.rescode = ResponseCode::REFUSED;
+        return DnsPacket::builder()
+            .header(header)
+            .questions(vec![question.clone()])
+            .build();
+    }
 
-        let ns_copy = ns;
-        let response = lookup(qname, qtype, ns_copy)?;
+    info!(
+        "Referring {} ({:?}) to name servers from zone",
+        question.name, question.qtype
+    );
+    header.questions = 1;
+    header.authoritative_entries = ns_records.len() as u16;
+    header.resource_entries = glue.len() as u16;
 
-        if response.has_answers() && response.rescode() == ResponseCode::NOERROR {
-            info!("Found entries without any errors {:?}", response);
-            return Ok(response);
+    DnsPacket::builder()
+        .header(header)
+        .questions(vec![question.clone()])
+        .authorities(ns_records)
+        .resources(glue)
+        .build()
+}
+
+// Turns the outcome of a recursive lookup into the response for the client
+pub(crate) fn response_from_lookup(
+    request: &DnsPacket,
+    result: anyhow::Result<DnsPacket>,
+) -> DnsPacket {
+    let mut header = response_header(request);
+
+    match result {
+        Ok(result) => {
+            // Passed on so that clients learn about names that do not exist. Codes that need the
+            // OPT record of the name server we asked (which is hop-by-hop and not passed on) to be
+            // expressed become failures.
+            header.rescode = match result.rescode() {
+                code if code.to_num() > 0x0F => ResponseCode::SERVFAIL,
+                code => code,
+            };
+            // We always ask for DNSSEC records, clients only get them when they asked for them as
+            // well (or for one of their types explicitly), see RFC 3225 section 3
+            let qtype = request.qtype().unwrap();
+            let wanted = |record: &DnsRecord| {
+                let rtype = record.query_type();
+                !rtype.is_dnssec() || request.dnssec_ok() || rtype == qtype
+            };
+            let answers = result
+                .answers
+                .into_iter()
+                .filter(wanted)
+                .collect::<Vec<_>>();
+            let authorities = result
+                .authorities
+                .into_iter()
+                .filter(wanted)
+                .collect::<Vec<_>>();
+            let resources = result
+                .resources
+                .into_iter()
+                .filter(|record| record.query_type() != QueryType::OPT && wanted(record))
+                .collect::<Vec<_>>();
+            header.questions = 1;
+            header.answers = answers.len() as u16;
+            header.authoritative_entries = authorities.len() as u16;
+            header.resource_entries = resources.len() as u16;
+            DnsPacket::builder()
+                .header(header)
+                .questions(request.questions.clone())
+                .answers(answers)
+                .authorities(authorities)
+                .resources(resources)
+                .build()
         }
+        Err(error) => {
+            error!("Server failure: {:?}", error);
+            recursing(DnsPacket::servfail(request))
+        }
+    }
+}
+
+// Clients tell us how large of a response they can take by sending an OPT record along (EDNS),
+// without one we have to stick to the 512 bytes of plain DNS. The OPT record is echoed back with
+// the size we settled on. Should the response still not fit, it is sent without any records and
+// the TC bit set, telling the client that it should retry over TCP.
+pub(crate) fn negotiate_edns(request: &DnsPacket, response: DnsPacket) -> DnsPacket {
+    let response = echo_edns(request, response);
+
+    // The estimate never falls short, there is no need to write the response unless it says that
+    // it might not fit
+    if response.estimated_size() <= response.udp_payload_size() {
+        return response;
+    }
 
-        if response.rescode() == ResponseCode::NXDOMAIN {
+    // The writer leaves out whatever does not fit, which is what the client is going to get
+    let written = write_packet_vec(&response)
+        .and_then(|bytes| DnsPacket::parse(&bytes).map_err(anyhow::Error::from));
+    match written {
+        Ok(written) if written.header.truncated_message => {
             warn!(
-                "Authoritative servers {:?} says name {} ({:?}) does not exist",
-                ns, qname, qtype
+                "Response does not fit in {} bytes, truncated to {} records",
+                response.udp_payload_size(),
+                written.answers.len() + written.authorities.len()
             );
-            return Ok(response);
+            written
+        }
+        Ok(_) => response,
+        Err(error) => {
+            warn!("Unable to truncate response: {:?}", error);
+            response
         }
+    }
+}
+
+// The part of `negotiate_edns` that also applies to TCP, where there is no need to truncate
+pub(crate) fn echo_edns(request: &DnsPacket, mut response: DnsPacket) -> DnsPacket {
+    // OPT records are hop-by-hop, whatever a name server we asked sent us is not for our client
+    response
+        .resources
+        .retain(|record| record.query_type() != QueryType::OPT);
+
+    let has_edns = request
+        .resources
+        .iter()
+        .any(|record| record.query_type() == QueryType::OPT);
+    if has_edns {
+        let udp_payload_size = request.udp_payload_size().min(MAX_EDNS_PACKET_SIZE);
+        debug!("Negotiated EDNS payload size of {}", udp_payload_size);
+        response.resources.push(DnsRecord::OPT {
+            udp_payload_size: udp_payload_size as u16,
+            // The upper 8 bits of codes that do not fit into the header, e.g. BADVERS
+            ext_rcode: (response.header.rescode.to_num() >> 4) as u8,
+            version: 0,
+            // The DO bit is copied back (RFC 3225 section 3)
+            flags: request.dnssec_ok() as u16 * EDNS_DO_FLAG,
+            data: vec![],
+        });
+    }
+    response.header.resource_entries = response.resources.len() as u16;
+
+    response
+}
+
+// Error responses let the client know that we recurse as well, see `response_header`
+fn recursing(mut response: DnsPacket) -> DnsPacket {
+    response.header.recursion_available = true;
+    response
+}
+
+fn response_header(request: &DnsPacket) -> DnsHeader {
+    DnsHeader::builder()
+        .id(request.header.id)
+        .recursion_desired(request.header.recursion_desired)
+        .recursion_available(true)
+        .response(true)
+        .build()
+}
+
+// A single hop of a recursive lookup, i.e. what one name server told us
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceStep {
+    pub server: SocketAddr,
+    pub rescode: ResponseCode,
+    pub outcome: TraceOutcome,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceOutcome {
+    // The server pointed us to other name servers closer to the answer
+    Referral,
+    // The server had the answer
+    Answer,
+    // The server could neither answer nor refer us anywhere (including NXDOMAIN)
+    Failure,
+}
+
+// How lookups the server can not answer itself are made
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ResolverMode {
+    // Walk down from the root servers ourselves
+    #[default]
+    Recursive,
+    // Let another (recursive) server do all the work
+    Forward(SocketAddr),
+}
 
-        if let Some(new_ns) = response.get_resolved_ns(qname) {
-            ns = (new_ns, 53);
-            continue;
+// A single query to `upstream` (usually `RECURSIVE_DNS_SERVER`), asking it to do the recursion on
+// our behalf
+pub fn forward_lookup(
+    qname: &str,
+    qtype: QueryType,
+    upstream: SocketAddr,
+) -> anyhow::Result<DnsPacket> {
+    lookup(qname, qtype, upstream)
+}
+
+// Starts from any of the root servers, see `Resolver` for starting elsewhere
+pub fn recursive_lookup(qname: &str, qtype: QueryType) -> anyhow::Result<DnsPacket> {
+    Resolver::default().recursive_lookup(qname, qtype)
+}
+
+// Same as `recursive_lookup` but also returns the chain of name servers that were consulted on
+// the way to the answer (much like `dig +trace`). Lookups made to resolve the addresses of name
+// servers that came without glue are not part of the trace.
+pub fn recursive_lookup_traced(
+    qname: &str,
+    qtype: QueryType,
+) -> anyhow::Result<(DnsPacket, Vec<TraceStep>)> {
+    Resolver::default().recursive_lookup_traced(qname, qtype)
+}
+
+// What the queries of a recursive lookup are sent with, where they are counted and by when the
+// lookup has to be done (including the name servers it has to resolve along the way)
+#[derive(Debug, Clone)]
+pub(crate) struct LookupOptions {
+    // None for queries without EDNS
+    pub(crate) udp_payload_size: Option<u16>,
+    pub(crate) metrics: Arc<Metrics>,
+    pub(crate) deadline: Instant,
+}
+
+impl Default for LookupOptions {
+    fn default() -> LookupOptions {
+        LookupOptions {
+            udp_payload_size: Some(DEFAULT_EDNS_PAYLOAD_SIZE),
+            metrics: Arc::default(),
+            deadline: Instant::now() + RESOLUTION_BUDGET,
         }
+    }
+}
+
+// Walks down the delegation chain starting at one of the `roots` (tried in order). Name servers we
+// get referred to are always contacted on `ns_port`, which is only ever something other than 53 in
+// tests. `depth` is the number of name server resolutions this lookup is nested in.
+pub(crate) fn trace_lookup(
+    qname: &str,
+    qtype: QueryType,
+    roots: &[SocketAddr],
+    ns_port: u16,
+    depth: usize,
+    trace: &mut Vec<TraceStep>,
+    options: &LookupOptions,
+) -> anyhow::Result<DnsPacket> {
+    check_depth(qname, depth)?;
 
-        let new_ns_name = match response.get_unresolved_ns(qname) {
-            Some(x) => x,
-            None => {
-                warn!("No NS Record exist: {:#?}", response);
-                return Ok(response);
+    let mut servers = roots.to_vec();
+    for hop in 0..MAX_HOPS {
+        let (ns, response) = ask_first(qname, qtype, &servers, hop > 0, options)?;
+
+        let next_servers = match next_step(&response, qname, ns_port) {
+            NextStep::Done => vec![],
+            NextStep::Ask(new_servers) => new_servers,
+            NextStep::ResolveNs(new_ns_names) => {
+                let (roots, options) = (roots.to_vec(), options.clone());
+                let resolved_ns = resolve_first_ns(new_ns_names, move |ns_name| {
+                    let ns_response = trace_lookup(
+                        ns_name,
+                        QueryType::A,
+                        &roots,
+                        ns_port,
+                        depth + 1,
+                        &mut vec![],
+                        &options,
+                    )?;
+                    Ok(ns_response.get_random_a())
+                });
+                resolved_ns
+                    .map(|new_ns| SocketAddr::new(new_ns.into(), ns_port))
+                    .into_iter()
+                    .collect()
             }
         };
 
-        info!("Need to resolve IP for server",);
-        let recursive_response = recursive_lookup(&new_ns_name, QueryType::A)?;
+        let outcome = match next_servers.is_empty() {
+            false => TraceOutcome::Referral,
+            true if response.is_answer() => TraceOutcome::Answer,
+            true => TraceOutcome::Failure,
+        };
+        trace.push(TraceStep {
+            server: ns,
+            rescode: response.rescode(),
+            outcome,
+        });
 
-        if let Some(new_ns) = recursive_response.get_random_a() {
-            ns = (new_ns, 53);
-        } else {
+        if next_servers.is_empty() {
             return Ok(response);
         }
+        servers = next_servers;
     }
+
+    anyhow::bail!("Giving up on {} after {} referrals", qname, MAX_HOPS)
 }
 
-pub fn lookup(qname: &str, qtype: QueryType, server: (Ipv4Addr, u16)) -> anyhow::Result<DnsPacket> {
-    let socket = UdpSocket::bind(("0.0.0.0", 43210))?;
+// Asks the given name servers one after the other until one of them responds. A server that does
+// not respond in time is no different from one sending something we can not parse, either way we
+// move on to the next one. The error of the last one is returned if none of them responded.
+//
+// Servers we were `referred` to are also passed over when they turn out to be lame, see
+// `is_lame`.
+fn ask_first(
+    qname: &str,
+    qtype: QueryType,
+    servers: &[SocketAddr],
+    referred: bool,
+    options: &LookupOptions,
+) -> anyhow::Result<(SocketAddr, DnsPacket)> {
+    let mut last_error = anyhow::anyhow!("No name servers to ask about {}", qname);
+    for &ns in servers {
+        if Instant::now() >= options.deadline {
+            anyhow::bail!("Ran out of time resolving {}", qname);
+        }
+        info!(
+            "attempting lookup of {} ({:?}) with ns {}",
+            qname, qtype, ns
+        );
 
-    let random_id = rand::random();
-    let request = mk_query(random_id, qname, qtype);
-    debug!("Request: {:?}", request);
+        options.metrics.record_upstream_query();
+        let socket = UdpSocket::bind(local_addr_for(ns))?;
+        let deadline = options.deadline.min(Instant::now() + LOOKUP_TIMEOUT);
+        match lookup_until(
+            &socket,
+            qname,
+            qtype,
+            ns,
+            options.udp_payload_size,
+            deadline,
+        ) {
+            Ok(response) if referred && is_lame(&response) => {
+                warn!("Lame delegation: {} does not serve {}", ns, qname);
+                last_error = anyhow::anyhow!("Lame delegation: {} does not serve {}", ns, qname);
+            }
+            Ok(response) => return Ok((ns, response)),
+            Err(error) => {
+                warn!("No usable response from {}: {:?}", ns, error);
+                last_error = error;
+            }
+        }
+    }
 
-    let mut req_buffer = vec![0u8; MAX_PACKET_SIZE];
-    let size = write_packet(&mut req_buffer, &request)?;
+    Err(last_error)
+}
 
-    socket.send_to(&req_buffer[..size], server)?;
+// A name server named in a referral that does not actually serve the zone, refusing to answer or
+// responding with neither records nor a referral of its own, without claiming to be authoritative
+// (RFC 1912 section 2.8)
+pub(crate) fn is_lame(response: &DnsPacket) -> bool {
+    response.rescode() == ResponseCode::REFUSED
+        || (response.is_successful()
+            && !response.header.authoritative_answer
+            && !response.has_answers()
+            && !response.is_referral()
+            && !response.is_nodata())
+}
 
-    let mut response_buffer = vec![0u8; MAX_PACKET_SIZE];
-    let (size, _src) = socket.recv_from(&mut response_buffer)?;
-    let response = DnsPacket::try_from(&response_buffer[..size]).map_err(anyhow::Error::msg)?;
-    debug!("Response: {:?}", response);
+pub(crate) fn check_depth(qname: &str, depth: usize) -> anyhow::Result<()> {
+    if depth > MAX_NS_RESOLUTION_DEPTH {
+        anyhow::bail!(
+            "Giving up on {}, nested more than {} name server resolutions deep",
+            qname,
+            MAX_NS_RESOLUTION_DEPTH
+        );
+    }
+
+    Ok(())
+}
+
+// What to do after hearing back from a name server during a recursive lookup
+pub(crate) enum NextStep {
+    // We are done, the response is what we return
+    Done,
+    // Ask one of the given name servers next (in this order)
+    Ask(Vec<SocketAddr>),
+    // Ask one of these name servers next, once we know their address
+    ResolveNs(Vec<String>),
+}
+
+pub(crate) fn next_step(response: &DnsPacket, qname: &str, ns_port: u16) -> NextStep {
+    if response.is_answer() {
+        info!("Found entries without any errors {:?}", response);
+        return NextStep::Done;
+    }
+
+    if response.is_nxdomain() {
+        warn!("Authoritative server says name {} does not exist", qname);
+        return NextStep::Done;
+    }
+
+    // Whatever else the response holds can not be trusted
+    if let rescode @ (ResponseCode::BADVERS | ResponseCode::UNKNOWN(_)) = response.rescode() {
+        warn!("Name server failed with {} for {}", rescode, qname);
+        return NextStep::Done;
+    }
+
+    // Some servers send the NS records of the zone along, which are not a referral
+    if response.is_nodata() {
+        info!("Authoritative server says {} has no such records", qname);
+        return NextStep::Done;
+    }
+
+    if !response.is_referral() {
+        warn!(
+            "Name server neither answered nor referred us on: {:#?}",
+            response
+        );
+        return NextStep::Done;
+    }
+
+    let new_servers = resolved_ns(response, qname, ns_port);
+    if !new_servers.is_empty() {
+        return NextStep::Ask(new_servers);
+    }
+
+    let new_ns_names = response
+        .get_all_unresolved_ns(qname)
+        .into_iter()
+        .map(str::to_string)
+        .collect::<Vec<_>>();
+    if new_ns_names.is_empty() {
+        warn!("No NS Record exist: {:#?}", response);
+        return NextStep::Done;
+    }
+
+    info!("Need to resolve IP for one of servers {:?}", new_ns_names);
+    NextStep::ResolveNs(new_ns_names)
+}
+
+// Addresses of the name servers from the referral we can continue with, using the glue records the
+// referral came with. They are shuffled to spread the load, with IPv4 ones first: IPv6 is only
+// tried once none of the IPv4 ones responded.
+fn resolved_ns(response: &DnsPacket, qname: &str, ns_port: u16) -> Vec<SocketAddr> {
+    let mut rng = rand::thread_rng();
+    let mut v4 = response.get_all_resolved_ns(qname);
+    let mut v6 = response.get_all_resolved_ns_v6(qname);
+    v4.shuffle(&mut rng);
+    v6.shuffle(&mut rng);
+
+    v4.into_iter()
+        .map(IpAddr::from)
+        .chain(v6.into_iter().map(IpAddr::from))
+        .map(|addr| SocketAddr::new(addr, ns_port))
+        .collect()
+}
+
+// Resolves the given name servers (a few at a time, in parallel) and returns the address of the
+// first one that could be resolved. An unreachable or broken name server just means we move on to
+// the next one.
+fn resolve_first_ns<F>(ns_names: Vec<String>, resolve_ns: F) -> Option<Ipv4Addr>
+where
+    F: Fn(&str) -> anyhow::Result<Option<Ipv4Addr>> + Send + Sync + 'static,
+{
+    let resolve_ns = Arc::new(resolve_ns);
+
+    for batch in ns_names.chunks(PARALLEL_NS_LOOKUPS) {
+        let (sender, receiver) = mpsc::channel();
+        for ns_name in batch {
+            let (sender, ns_name, resolve_ns) =
+                (sender.clone(), ns_name.clone(), Arc::clone(&resolve_ns));
+            thread::spawn(move || {
+                let result = resolve_ns(&ns_name);
+                // Nobody is listening anymore once another name server got resolved
+                let _ = sender.send((ns_name, result));
+            });
+        }
+        drop(sender);
+
+        for (ns_name, result) in receiver {
+            match result {
+                Ok(Some(addr)) => return Some(addr),
+                Ok(None) => warn!("No A record found for name server {}", ns_name),
+                Err(error) => warn!("Unable to resolve name server {}: {:?}", ns_name, error),
+            }
+        }
+    }
+
+    None
+}
+
+pub fn lookup(qname: &str, qtype: QueryType, server: SocketAddr) -> anyhow::Result<DnsPacket> {
+    lookup_with_payload_size(qname, qtype, server, DEFAULT_EDNS_PAYLOAD_SIZE)
+}
+
+// How long `server` takes to respond to a simple query, e.g. to make sure an upstream server is up
+// before relying on it. Any response will do, whatever its response code.
+pub fn health_check(server: SocketAddr) -> anyhow::Result<Duration> {
+    let start = Instant::now();
+    lookup(HEALTH_CHECK_QNAME, QueryType::A, server)?;
+
+    Ok(start.elapsed())
+}
+
+// Same as `lookup` but advertising the given UDP payload size instead of the default one
+pub fn lookup_with_payload_size(
+    qname: &str,
+    qtype: QueryType,
+    server: SocketAddr,
+    udp_payload_size: u16,
+) -> anyhow::Result<DnsPacket> {
+    let socket = UdpSocket::bind(local_addr_for(server))?;
 
-    Ok(response)
+    lookup_on(&socket, qname, qtype, server, udp_payload_size)
 }
 
-fn mk_query(id: u16, qname: &str, qtype: QueryType) -> DnsPacket {
-    let header = DnsHeader::builder()
-        .id(id)
-        .questions(1)
-        .recursion_desired(true)
-        .build();
+// Let the OS pick the port, lookups may very well be running in parallel
+pub(crate) fn local_addr_for(server: SocketAddr) -> SocketAddr {
+    let ip = match server {
+        SocketAddr::V4(_) => IpAddr::from(Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(_) => IpAddr::from(Ipv6Addr::UNSPECIFIED),
+    };
+
+    SocketAddr::new(ip, 0)
+}
 
-    let question = DnsQuestion {
-        name: qname.to_string(),
+// Same as `lookup_with_payload_size` but sends the query from a socket provided by the caller (e.g.
+// one taken out of a pool) instead of binding a new one.
+pub fn lookup_on(
+    socket: &UdpSocket,
+    qname: &str,
+    qtype: QueryType,
+    server: SocketAddr,
+    udp_payload_size: u16,
+) -> anyhow::Result<DnsPacket> {
+    let deadline = Instant::now() + LOOKUP_TIMEOUT;
+    lookup_until(
+        socket,
+        qname,
         qtype,
+        server,
+        Some(udp_payload_size),
+        deadline,
+    )
+}
+
+// Same as `lookup_on` but giving up at `deadline`, which a recursive lookup running out of time
+// may have brought forward. Without a `udp_payload_size` the query goes out without EDNS.
+pub(crate) fn lookup_until(
+    socket: &UdpSocket,
+    qname: &str,
+    qtype: QueryType,
+    server: SocketAddr,
+    udp_payload_size: Option<u16>,
+    deadline: Instant,
+) -> anyhow::Result<DnsPacket> {
+    let (id, qname) = (rand::random(), randomize_case(qname));
+    let request = match udp_payload_size {
+        Some(udp_payload_size) => mk_upstream_query(id, &qname, qtype, udp_payload_size)?,
+        None => mk_minimal_query(id, &qname, qtype)?,
     };
+    debug!("Request: {:?}", request);
 
-    DnsPacket::builder()
-        .header(header)
-        .questions(vec![question])
-        .build()
+    let query = write_packet_vec(&request)?;
+    let mut retransmit_at = Instant::now();
+    let mut attempt = 0;
+    let mut response_buffer = vec![0u8; request.udp_payload_size()];
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            anyhow::bail!("Timed out waiting for a response from {}", server);
+        }
+        if now >= retransmit_at {
+            socket.send_to(&query, server)?;
+            retransmit_at = now + retransmit_interval(attempt);
+            attempt += 1;
+        }
+        socket.set_read_timeout(Some(retransmit_at.min(deadline) - now))?;
+
+        let (size, src) = match socket.recv_from(&mut response_buffer) {
+            Ok(received) => received,
+            Err(error)
+                if matches!(
+                    error.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                continue
+            }
+            Err(error) => return Err(error.into()),
+        };
+        if let Some(response) = accept_response(&response_buffer[..size], src, server, &request)? {
+            return Ok(response);
+        }
+    }
+}
+
+// How long to wait for a response before sending the query again. Up to a quarter of it is taken
+// off at random, so that lookups started at the same time do not keep retransmitting in lockstep.
+pub(crate) fn retransmit_interval(attempt: u32) -> Duration {
+    let interval = INITIAL_RETRANSMIT_INTERVAL
+        .checked_mul(1 << attempt.min(16))
+        .map_or(MAX_RETRANSMIT_INTERVAL, |interval| {
+            interval.min(MAX_RETRANSMIT_INTERVAL)
+        });
+
+    interval.mul_f64(rand::thread_rng().gen_range(0.75, 1.0))
+}
+
+// Anyone can send us a packet, so we only accept one that comes from the server we asked and
+// carries the id we picked. Everything else is dropped (None) and we keep on waiting.
+//
+// On top of that the question has to come back exactly as it was asked, see `randomize_case`.
+pub(crate) fn accept_response(
+    buf: &[u8],
+    src: SocketAddr,
+    server: SocketAddr,
+    request: &DnsPacket,
+) -> anyhow::Result<Option<DnsPacket>> {
+    if src != server {
+        warn!("Ignoring packet from unexpected source {}", src);
+        return Ok(None);
+    }
+
+    let response = DnsPacket::parse(buf)?;
+    if response.header.id != request.header.id {
+        warn!(
+            "Ignoring response with id {} (expected {})",
+            response.header.id, request.header.id
+        );
+        return Ok(None);
+    }
+    if response.qname() != request.qname() {
+        warn!(
+            "Ignoring response for {:?} (expected {:?})",
+            response.qname(),
+            request.qname()
+        );
+        return Ok(None);
+    }
+    debug!("Response: {:?}", response);
+
+    Ok(Some(response))
+}
+
+// DNS 0x20 (draft-vixie-dnsext-dns0x20): Servers copy the question into their response as is and
+// compare names case-insensitively, so randomly flipping the case of the letters we send adds a
+// bit of entropy per letter on top of the 16 bits of the id, which a spoofed response has to guess.
+pub(crate) fn randomize_case(qname: &str) -> String {
+    qname
+        .chars()
+        .map(|c| {
+            if rand::random() {
+                c.to_ascii_uppercase()
+            } else {
+                c.to_ascii_lowercase()
+            }
+        })
+        .collect()
+}
+
+// Reverse lookups are regular PTR queries against a special name. The octets of the address are
+// written in reverse order under the `in-addr.arpa` domain, e.g. 8.8.4.4 -> 4.4.8.8.in-addr.arpa
+pub fn reverse_name(ip: Ipv4Addr) -> String {
+    let [a, b, c, d] = ip.octets();
+    format!("{}.{}.{}.{}.in-addr.arpa", d, c, b, a)
+}
+
+// The same for IPv6, one label per nibble of the address under the `ip6.arpa` domain (RFC 3596
+// section 2.5), e.g. 2001:db8::1 -> 1.0.0.0.[...].8.b.d.0.1.0.0.2.ip6.arpa
+pub fn reverse_name_v6(ip: Ipv6Addr) -> String {
+    let mut labels = ip
+        .octets()
+        .iter()
+        .rev()
+        .flat_map(|octet| [octet & 0x0f, octet >> 4])
+        .map(|nibble| format!("{:x}", nibble))
+        .collect::<Vec<_>>();
+    labels.push("ip6.arpa".to_string());
+
+    labels.join(".")
+}
+
+pub(crate) fn mk_query(id: u16, qname: &str, qtype: QueryType) -> DnsPacket {
+    DnsPacket::query(id, qname, qtype)
+}
+
+// What we send to other name servers. It speaks EDNS, so that responses of up to
+// `udp_payload_size` bytes fit, and always asks for DNSSEC records so that they can be passed on
+// to the clients that want them. Names that can not be asked about are rejected right away.
+pub(crate) fn mk_upstream_query(
+    id: u16,
+    qname: &str,
+    qtype: QueryType,
+    udp_payload_size: u16,
+) -> anyhow::Result<DnsPacket> {
+    validate_qname(qname).map_err(anyhow::Error::msg)?;
+
+    let mut query = mk_query(id, wire_name(qname), qtype);
+    query.push_resource(DnsRecord::OPT {
+        udp_payload_size,
+        ext_rcode: 0,
+        version: 0,
+        flags: EDNS_DO_FLAG,
+        data: vec![],
+    });
+
+    Ok(query)
+}
+
+// What we send to name servers that do not speak EDNS: the question and nothing else
+pub(crate) fn mk_minimal_query(
+    id: u16,
+    qname: &str,
+    qtype: QueryType,
+) -> anyhow::Result<DnsPacket> {
+    validate_qname(qname).map_err(anyhow::Error::msg)?;
+
+    Ok(mk_query(id, wire_name(qname), qtype))
+}
+
+// A trailing dot is not written out, so a name that has one comes back without it. Queries are made
+// with the name as it goes over the wire, for the question in a response to match it exactly.
+fn wire_name(qname: &str) -> &str {
+    qname.strip_suffix('.').unwrap_or(qname)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn a_response(request: &DnsPacket, id: u16, addr: Ipv4Addr) -> DnsPacket {
+        let question = request.first_question().unwrap().clone();
+        let record = DnsRecord::A {
+            domain: question.name.clone(),
+            addr,
+            ttl: 300,
+        };
+        let header = DnsHeader::builder()
+            .id(id)
+            .response(true)
+            .questions(1)
+            .answers(1)
+            .build();
+
+        DnsPacket::builder()
+            .header(header)
+            .questions(vec![question])
+            .answers(vec![record])
+            .build()
+    }
+
+    // Answers `count` queries with whatever `respond` makes of them
+    fn serve<F>(stub: UdpSocket, count: usize, respond: F) -> thread::JoinHandle<()>
+    where
+        F: Fn(&DnsPacket) -> DnsPacket + Send + 'static,
+    {
+        thread::spawn(move || {
+            let mut buf = vec![0u8; MAX_PACKET_SIZE];
+            for _ in 0..count {
+                let (size, src) = stub.recv_from(&mut buf).unwrap();
+                let request = DnsPacket::parse(&buf[..size]).unwrap();
+
+                let response = respond(&request);
+                let size = write_packet(&mut buf, &response).unwrap();
+                stub.send_to(&buf[..size], src).unwrap();
+            }
+        })
+    }
+
+    // Answers `count` queries with an A record pointing to `addr`
+    fn serve_a(stub: UdpSocket, count: usize, addr: Ipv4Addr) -> thread::JoinHandle<()> {
+        serve(stub, count, move |request| {
+            a_response(request, request.header.id, addr)
+        })
+    }
+
+    // Refers every query to `ns_name` (with glue pointing to `ns_addr`)
+    fn referral(request: &DnsPacket, zone: &str, ns_name: &str, ns_addr: Ipv4Addr) -> DnsPacket {
+        let header = DnsHeader::builder()
+            .id(request.header.id)
+            .response(true)
+            .questions(1)
+            .authoritative_entries(1)
+            .resource_entries(1)
+            .build();
+
+        DnsPacket::builder()
+            .header(header)
+            .questions(request.questions.clone())
+            .authorities(vec![DnsRecord::NS {
+                domain: zone.to_string(),
+                host: ns_name.to_string(),
+                ttl: 172800,
+            }])
+            .resources(vec![DnsRecord::A {
+                domain: ns_name.to_string(),
+                addr: ns_addr,
+                ttl: 172800,
+            }])
+            .build()
+    }
+
+    #[test]
+    fn trace_lookup_records_each_hop() {
+        let root = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let ns = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let (root_addr, ns_addr) = (root.local_addr().unwrap(), ns.local_addr().unwrap());
+
+        let root_handle = serve(root, 1, |request| {
+            referral(request, "com", "a.gtld-servers.net", Ipv4Addr::LOCALHOST)
+        });
+        let ns_handle = serve_a(ns, 1, Ipv4Addr::new(1, 2, 3, 4));
+
+        let mut trace = vec![];
+        let response = trace_lookup(
+            "example.com",
+            QueryType::A,
+            &[root_addr],
+            ns_addr.port(),
+            0,
+            &mut trace,
+            &LookupOptions::default(),
+        )
+        .unwrap();
+        root_handle.join().unwrap();
+        ns_handle.join().unwrap();
+
+        assert_eq!(response.get_random_a(), Some(Ipv4Addr::new(1, 2, 3, 4)));
+        assert_eq!(
+            trace,
+            vec![
+                TraceStep {
+                    server: root_addr,
+                    rescode: ResponseCode::NOERROR,
+                    outcome: TraceOutcome::Referral,
+                },
+                TraceStep {
+                    server: ns_addr,
+                    rescode: ResponseCode::NOERROR,
+                    outcome: TraceOutcome::Answer,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn ask_first_skips_lame_servers() {
+        let lame = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let ns = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let (lame_addr, ns_addr) = (lame.local_addr().unwrap(), ns.local_addr().unwrap());
+        let lame_handle = serve(lame, 1, |request| {
+            let mut response = DnsPacket::servfail(request);
+            response.header.rescode = ResponseCode::REFUSED;
+            response
+        });
+        let ns_handle = serve_a(ns, 1, Ipv4Addr::new(1, 2, 3, 4));
+
+        let (server, response) = ask_first(
+            "example.com",
+            QueryType::A,
+            &[lame_addr, ns_addr],
+            true,
+            &LookupOptions::default(),
+        )
+        .unwrap();
+        lame_handle.join().unwrap();
+        ns_handle.join().unwrap();
+
+        assert_eq!(server, ns_addr);
+        assert_eq!(response.get_random_a(), Some(Ipv4Addr::new(1, 2, 3, 4)));
+    }
+
+    #[test]
+    fn lame_responses_are_recognized() {
+        let query = mk_query(1, "example.com", QueryType::A);
+        let mut refused = DnsPacket::servfail(&query);
+        refused.header.rescode = ResponseCode::REFUSED;
+        let mut empty = DnsPacket::servfail(&query);
+        empty.header.rescode = ResponseCode::NOERROR;
+        let mut authoritative = empty.clone();
+        authoritative.header.authoritative_answer = true;
+
+        assert!(is_lame(&refused));
+        assert!(is_lame(&empty));
+        assert!(!is_lame(&authoritative));
+        assert!(!is_lame(&a_response(&query, 1, Ipv4Addr::new(1, 2, 3, 4))));
+    }
+
+    #[test]
+    fn trace_lookup_moves_on_from_garbage_responses() {
+        let garbage = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let root = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let ns = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let (garbage_addr, root_addr, ns_addr) = (
+            garbage.local_addr().unwrap(),
+            root.local_addr().unwrap(),
+            ns.local_addr().unwrap(),
+        );
+
+        let garbage_handle = thread::spawn(move || {
+            let mut buf = vec![0u8; MAX_PACKET_SIZE];
+            let (_, src) = garbage.recv_from(&mut buf).unwrap();
+            garbage.send_to(&[0xde, 0xad, 0xbe, 0xef], src).unwrap();
+        });
+        let root_handle = serve(root, 1, |request| {
+            referral(request, "com", "a.gtld-servers.net", Ipv4Addr::LOCALHOST)
+        });
+        let ns_handle = serve_a(ns, 1, Ipv4Addr::new(1, 2, 3, 4));
+
+        let mut trace = vec![];
+        let response = trace_lookup(
+            "example.com",
+            QueryType::A,
+            &[garbage_addr, root_addr],
+            ns_addr.port(),
+            0,
+            &mut trace,
+            &LookupOptions::default(),
+        )
+        .unwrap();
+        garbage_handle.join().unwrap();
+        root_handle.join().unwrap();
+        ns_handle.join().unwrap();
+
+        assert_eq!(response.get_random_a(), Some(Ipv4Addr::new(1, 2, 3, 4)));
+        assert_eq!(
+            trace.iter().map(|step| step.server).collect::<Vec<_>>(),
+            vec![root_addr, ns_addr]
+        );
+    }
+
+    #[test]
+    fn trace_lookup_stops_at_nodata() {
+        let stub = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let server = stub.local_addr().unwrap();
+        // example.com only has an A record, the NS records that come along with the SOA must not
+        // be taken for a referral (which would have us ask the stub a second time)
+        let handle = serve(stub, 1, |request| {
+            let mut response = referral(
+                request,
+                "example.com",
+                "ns.example.com",
+                Ipv4Addr::LOCALHOST,
+            );
+            response.authorities.push(DnsRecord::SOA {
+                domain: "example.com".to_string(),
+                m_name: "ns.example.com".to_string(),
+                r_name: "hostmaster.example.com".to_string(),
+                serial: 1,
+                refresh: 7200,
+                retry: 3600,
+                expire: 1209600,
+                minimum: 300,
+                ttl: 300,
+            });
+            response.header.authoritative_entries = 2;
+            response
+        });
+
+        let mut trace = vec![];
+        let response = trace_lookup(
+            "example.com",
+            QueryType::AAAA,
+            &[server],
+            server.port(),
+            0,
+            &mut trace,
+            &LookupOptions::default(),
+        )
+        .unwrap();
+        handle.join().unwrap();
+
+        assert!(response.is_nodata());
+        assert_eq!(trace.len(), 1);
+        assert_eq!(trace[0].outcome, TraceOutcome::Failure);
+    }
+
+    #[test]
+    fn trace_lookup_gives_up_on_endless_referrals() {
+        let stub = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let server = stub.local_addr().unwrap();
+        // Keeps on referring us back to itself
+        let handle = serve(stub, MAX_HOPS, |request| {
+            referral(request, "com", "ns.example.com", Ipv4Addr::LOCALHOST)
+        });
+
+        let mut trace = vec![];
+        let error = trace_lookup(
+            "example.com",
+            QueryType::A,
+            &[server],
+            server.port(),
+            0,
+            &mut trace,
+            &LookupOptions::default(),
+        )
+        .unwrap_err();
+        handle.join().unwrap();
+
+        assert_eq!(
+            error.to_string(),
+            "Giving up on example.com after 30 referrals"
+        );
+        assert_eq!(trace.len(), MAX_HOPS);
+    }
+
+    #[test]
+    fn trace_lookup_stays_within_its_budget() {
+        let stub = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let server = stub.local_addr().unwrap();
+        // Keeps on referring us back to itself, taking its time with every hop. Without a budget
+        // all the hops together would take several seconds.
+        stub.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let handle = thread::spawn(move || {
+            let mut buf = vec![0u8; MAX_PACKET_SIZE];
+            while let Ok((size, src)) = stub.recv_from(&mut buf) {
+                let request = DnsPacket::parse(&buf[..size]).unwrap();
+                thread::sleep(Duration::from_millis(150));
+
+                let response = referral(&request, "com", "ns.example.com", Ipv4Addr::LOCALHOST);
+                let size = write_packet(&mut buf, &response).unwrap();
+                stub.send_to(&buf[..size], src).unwrap();
+            }
+        });
+
+        let options = LookupOptions {
+            deadline: Instant::now() + Duration::from_secs(1),
+            ..LookupOptions::default()
+        };
+        let start = Instant::now();
+        let result = trace_lookup(
+            "example.com",
+            QueryType::A,
+            &[server],
+            server.port(),
+            0,
+            &mut vec![],
+            &options,
+        );
+        let elapsed = start.elapsed();
+        handle.join().unwrap();
+
+        assert!(result.is_err());
+        assert!(elapsed < Duration::from_millis(1500), "took {:?}", elapsed);
+    }
+
+    #[test]
+    fn trace_lookup_rejects_deep_nesting() {
+        let root = SocketAddr::from((Ipv4Addr::LOCALHOST, 1));
+        let result = trace_lookup(
+            "example.com",
+            QueryType::A,
+            &[root],
+            DNS_PORT,
+            MAX_NS_RESOLUTION_DEPTH + 1,
+            &mut vec![],
+            &LookupOptions::default(),
+        );
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn concurrent_lookups_do_not_collide() {
+        let stub = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let server = stub.local_addr().unwrap();
+        let handle = serve_a(stub, 2, Ipv4Addr::new(1, 2, 3, 4));
+
+        let lookups = (0..2)
+            .map(|_| thread::spawn(move || lookup("example.com", QueryType::A, server)))
+            .collect::<Vec<_>>();
+
+        for lookup in lookups {
+            let response = lookup.join().unwrap().unwrap();
+            assert_eq!(response.get_random_a(), Some(Ipv4Addr::new(1, 2, 3, 4)));
+        }
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn health_check_works() {
+        let stub = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let server = stub.local_addr().unwrap();
+        let handle = serve_a(stub, 1, Ipv4Addr::new(198, 41, 0, 4));
+
+        let rtt = health_check(server).unwrap();
+        handle.join().unwrap();
+
+        assert!(rtt < LOOKUP_TIMEOUT);
+    }
+
+    #[test]
+    fn lookup_on_uses_given_socket() {
+        let stub = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let server = stub.local_addr().unwrap();
+        let handle = serve_a(stub, 2, Ipv4Addr::new(1, 2, 3, 4));
+
+        let socket = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        for _ in 0..2 {
+            let response = lookup_on(
+                &socket,
+                "example.com",
+                QueryType::A,
+                server,
+                DEFAULT_EDNS_PAYLOAD_SIZE,
+            )
+            .unwrap();
+            assert_eq!(response.get_random_a(), Some(Ipv4Addr::new(1, 2, 3, 4)));
+        }
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn lookup_ignores_responses_with_mismatched_id() {
+        let stub = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let server = stub.local_addr().unwrap();
+
+        let handle = thread::spawn(move || {
+            let mut buf = vec![0u8; MAX_PACKET_SIZE];
+            let (size, src) = stub.recv_from(&mut buf).unwrap();
+            let request = DnsPacket::parse(&buf[..size]).unwrap();
+
+            let bogus_id = request.header.id.wrapping_add(1);
+            let bogus = a_response(&request, bogus_id, Ipv4Addr::new(6, 6, 6, 6));
+            let genuine = a_response(&request, request.header.id, Ipv4Addr::new(1, 2, 3, 4));
+
+            for response in &[bogus, genuine] {
+                let size = write_packet(&mut buf, response).unwrap();
+                stub.send_to(&buf[..size], src).unwrap();
+            }
+        });
+
+        let response = lookup("example.com", QueryType::A, server).unwrap();
+        handle.join().unwrap();
+
+        assert_eq!(response.get_random_a(), Some(Ipv4Addr::new(1, 2, 3, 4)));
+    }
+
+    #[test]
+    fn lookup_retransmits_lost_queries() {
+        let stub = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let server = stub.local_addr().unwrap();
+
+        let handle = thread::spawn(move || {
+            let mut buf = vec![0u8; MAX_EDNS_PACKET_SIZE];
+            let mut arrivals = vec![];
+            // The first two get lost on the way
+            for _ in 0..3 {
+                let (size, src) = stub.recv_from(&mut buf).unwrap();
+                arrivals.push((Instant::now(), DnsPacket::parse(&buf[..size]).unwrap(), src));
+            }
+
+            let (_, request, src) = &arrivals[2];
+            let response = a_response(request, request.header.id, Ipv4Addr::new(1, 2, 3, 4));
+            let size = write_packet(&mut buf, &response).unwrap();
+            stub.send_to(&buf[..size], src).unwrap();
+
+            arrivals
+        });
+
+        let start = Instant::now();
+        let response = lookup("example.com", QueryType::A, server).unwrap();
+        let arrivals = handle.join().unwrap();
+
+        assert_eq!(response.get_random_a(), Some(Ipv4Addr::new(1, 2, 3, 4)));
+        assert!(start.elapsed() < LOOKUP_TIMEOUT);
+        // The same query every time, backing off in between
+        assert!(arrivals
+            .iter()
+            .all(|(_, request, _)| request == &arrivals[0].1));
+        let first_gap = arrivals[1].0 - arrivals[0].0;
+        let second_gap = arrivals[2].0 - arrivals[1].0;
+        // With some leeway for the stub being scheduled late
+        assert!(first_gap >= INITIAL_RETRANSMIT_INTERVAL / 2);
+        assert!(second_gap > first_gap.min(INITIAL_RETRANSMIT_INTERVAL));
+    }
+
+    #[test]
+    fn retransmit_interval_is_capped() {
+        for attempt in 0..40 {
+            let interval = retransmit_interval(attempt);
+
+            assert!(interval <= MAX_RETRANSMIT_INTERVAL);
+            assert!(interval >= INITIAL_RETRANSMIT_INTERVAL.mul_f64(0.75));
+        }
+        assert!(retransmit_interval(10) >= MAX_RETRANSMIT_INTERVAL.mul_f64(0.75));
+    }
+
+    #[test]
+    fn lookup_rejects_invalid_names() {
+        // Nothing listens here, the query must not even be sent
+        let stub = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let server = stub.local_addr().unwrap();
+
+        let error = lookup("192..0.2.1", QueryType::PTR, server).unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            "Domain name \"192..0.2.1\" contains an empty label"
+        );
+    }
+
+    #[test]
+    fn lookup_ignores_responses_with_altered_casing() {
+        let stub = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let server = stub.local_addr().unwrap();
+
+        let handle = thread::spawn(move || {
+            let mut buf = vec![0u8; MAX_PACKET_SIZE];
+            let (size, src) = stub.recv_from(&mut buf).unwrap();
+            let request = DnsPacket::parse(&buf[..size]).unwrap();
+
+            let mut altered = request.clone();
+            let name = &mut altered.questions[0].name;
+            *name = name
+                .chars()
+                .map(|c| {
+                    if c.is_ascii_uppercase() {
+                        c.to_ascii_lowercase()
+                    } else {
+                        c.to_ascii_uppercase()
+                    }
+                })
+                .collect();
+            let bogus = a_response(&altered, request.header.id, Ipv4Addr::new(6, 6, 6, 6));
+            let genuine = a_response(&request, request.header.id, Ipv4Addr::new(1, 2, 3, 4));
+
+            for response in &[bogus, genuine] {
+                let size = write_packet(&mut buf, response).unwrap();
+                stub.send_to(&buf[..size], src).unwrap();
+            }
+        });
+
+        let response = lookup("example.com", QueryType::A, server).unwrap();
+        handle.join().unwrap();
+
+        assert_eq!(response.get_random_a(), Some(Ipv4Addr::new(1, 2, 3, 4)));
+    }
+
+    #[test]
+    fn lookup_accepts_names_with_a_trailing_dot() {
+        let stub = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let server = stub.local_addr().unwrap();
+        let handle = serve_a(stub, 1, Ipv4Addr::new(1, 2, 3, 4));
+
+        let start = Instant::now();
+        let response = lookup("example.com.", QueryType::A, server).unwrap();
+        handle.join().unwrap();
+
+        assert_eq!(response.get_random_a(), Some(Ipv4Addr::new(1, 2, 3, 4)));
+        assert!(start.elapsed() < INITIAL_RETRANSMIT_INTERVAL);
+    }
+
+    #[test]
+    fn randomize_case_keeps_the_name() {
+        let name = randomize_case("www.example.com");
+
+        assert!(name.eq_ignore_ascii_case("www.example.com"));
+    }
+
+    #[test]
+    fn resolve_answers_from_zone() {
+        let zone = zone::parse_zone("example.com. 3600 IN A 93.184.216.34").unwrap();
+        let request = mk_query(1234, "example.com", QueryType::A);
+
+        let response = resolve(request, &zone).unwrap();
+
+        assert!(response.header.authoritative_answer);
+        assert_eq!(response.header.id, 1234);
+        assert_eq!(response.header.answers, 1);
+        assert_eq!(response.answers, zone);
+    }
+
+    fn edns_query(id: u16, qname: &str, udp_payload_size: u16) -> DnsPacket {
+        let mut request = mk_query(id, qname, QueryType::A);
+        request.header.resource_entries = 1;
+        request.resources.push(DnsRecord::OPT {
+            udp_payload_size,
+            ext_rcode: 0,
+            version: 0,
+            flags: 0,
+            data: vec![],
+        });
+
+        request
+    }
+
+    #[test]
+    fn dnssec_records_only_go_to_clients_asking_for_them() {
+        let plain = mk_query(1234, "example.com", QueryType::A);
+        let dnssec_ok =
+            mk_upstream_query(1235, "example.com", QueryType::A, DEFAULT_EDNS_PAYLOAD_SIZE)
+                .unwrap();
+        let mut upstream = a_response(&plain, 1, Ipv4Addr::new(1, 2, 3, 4));
+        let rrsig = DnsRecord::UNKNOWN {
+            domain: "example.com".to_string(),
+            qtype: QueryType::RRSIG.to_num(),
+            data: vec![0xde, 0xad, 0xbe, 0xef],
+            ttl: 300,
+        };
+        upstream.answers.push(rrsig.clone());
+        upstream.header.answers = 2;
+
+        let stripped = response_from_lookup(&plain, Ok(upstream.clone()));
+        let kept = negotiate_edns(&dnssec_ok, response_from_lookup(&dnssec_ok, Ok(upstream)));
+
+        assert!(dnssec_ok.dnssec_ok());
+        assert_eq!(stripped.answers.len(), 1);
+        assert_eq!(stripped.header.answers, 1);
+        assert_eq!(kept.answers[1], rrsig);
+        assert!(kept.dnssec_ok());
+    }
+
+    fn example_records() -> Vec<DnsRecord> {
+        let mut records = zone::parse_zone(
+            "
+example.com.         3600 IN A  93.184.216.34
+www.dev.example.com. 3600 IN A  93.184.216.35
+",
+        )
+        .unwrap();
+        records.push(DnsRecord::SOA {
+            domain: "example.com".to_string(),
+            m_name: "ns1.example.com".to_string(),
+            r_name: "hostmaster.example.com".to_string(),
+            serial: 1,
+            refresh: 7200,
+            retry: 3600,
+            expire: 1209600,
+            minimum: 300,
+            ttl: 3600,
+        });
+
+        records
+    }
+
+    #[test]
+    fn resolve_from_records_answers_known_names() {
+        let records = example_records();
+
+        let response = resolve_from_records(&mk_query(1, "EXAMPLE.com", QueryType::A), &records);
+
+        assert_eq!(response.header.id, 1);
+        assert!(response.header.authoritative_answer);
+        assert_eq!(response.rescode(), ResponseCode::NOERROR);
+        assert_eq!(response.header.answers, 1);
+        assert_eq!(response.answers, vec![records[0].clone()]);
+    }
+
+    #[test]
+    fn resolve_from_records_answers_nodata() {
+        let records = example_records();
+
+        let response = resolve_from_records(&mk_query(2, "example.com", QueryType::MX), &records);
+        // Only exists because of the name below it
+        let empty_non_terminal =
+            resolve_from_records(&mk_query(3, "dev.example.com", QueryType::A), &records);
+
+        for response in [response, empty_non_terminal] {
+            assert!(response.header.authoritative_answer);
+            assert!(response.is_nodata());
+            assert_eq!(response.header.authoritative_entries, 1);
+            assert_eq!(response.authorities, vec![records[2].clone()]);
+        }
+    }
+
+    #[test]
+    fn resolve_from_records_answers_nxdomain() {
+        let records = example_records();
+
+        let response =
+            resolve_from_records(&mk_query(4, "missing.example.com", QueryType::A), &records);
+        let elsewhere = resolve_from_records(&mk_query(5, "example.org", QueryType::A), &records);
+
+        assert!(response.header.authoritative_answer);
+        assert!(response.is_nxdomain());
+        assert_eq!(response.authorities, vec![records[2].clone()]);
+        assert!(elsewhere.is_nxdomain());
+        assert_eq!(elsewhere.authorities, vec![]);
+    }
+
+    #[test]
+    fn resolve_echoes_edns() {
+        let zone = zone::parse_zone("example.com. 3600 IN A 93.184.216.34").unwrap();
+        let request = edns_query(1234, "example.com", 1232);
+
+        let response = resolve(request, &zone).unwrap();
+
+        assert_eq!(response.header.resource_entries, 1);
+        assert_eq!(response.udp_payload_size(), 1232);
+        assert!(!response.header.truncated_message);
+        assert_eq!(response.answers, zone);
+    }
+
+    #[test]
+    fn resolve_rejects_unknown_edns_versions() {
+        let mut request = mk_query(1234, "example.com", QueryType::A);
+        request.header.resource_entries = 1;
+        request.resources.push(DnsRecord::OPT {
+            udp_payload_size: 1232,
+            ext_rcode: 0,
+            version: 1,
+            flags: 0,
+            data: vec![],
+        });
+
+        let response = resolve(request, &[]).unwrap();
+        let written = DnsPacket::parse(&write_packet_vec(&response).unwrap()).unwrap();
+
+        assert_eq!(written.header.id, 1234);
+        assert_eq!(written.rescode(), ResponseCode::BADVERS);
+        assert_eq!(written.edns_version(), Some(0));
+        assert_eq!(written.qname(), Some("example.com".to_string()));
+        assert!(written.answers.is_empty());
+    }
+
+    #[test]
+    fn resolve_truncates_responses_exceeding_payload_size() {
+        let record = r#"example.com. 3600 IN TXT "this is a longish text record""#;
+        let zone = zone::parse_zone(&vec![record; 20].join("\n")).unwrap();
+
+        let plain = resolve(mk_query(1, "example.com", QueryType::TXT), &zone).unwrap();
+        let mut request = edns_query(2, "example.com", 4096);
+        request.questions[0].qtype = QueryType::TXT;
+        let edns = resolve(request, &zone).unwrap();
+
+        // Every record takes up 42 bytes (its name pointing to the question), 11 of them fit next
+        // to the header (12) and question (17)
+        assert!(plain.header.truncated_message);
+        assert_eq!(plain.header.answers, 11);
+        assert_eq!(plain.answers, zone[..11].to_vec());
+        assert!(!edns.header.truncated_message);
+        assert_eq!(edns.answers.len(), 20);
+    }
+
+    #[test]
+    fn resolved_ns_falls_back_to_ipv6_glue() {
+        let header = DnsHeader::builder().id(10).build();
+        let authoritative_records = vec![DnsRecord::NS {
+            domain: "example.com".to_string(),
+            host: "ns1.example.com".to_string(),
+            ttl: 172800,
+        }];
+        let resource_records = vec![DnsRecord::AAAA {
+            domain: "ns1.example.com".to_string(),
+            addr: "2001:db8::53".parse().unwrap(),
+            ttl: 172800,
+        }];
+        let referral = DnsPacket::builder()
+            .header(header)
+            .authorities(authoritative_records)
+            .resources(resource_records)
+            .build();
+
+        let result = resolved_ns(&referral, "www.example.com", DNS_PORT);
+
+        assert_eq!(result, vec!["[2001:db8::53]:53".parse().unwrap()]);
+    }
+
+    #[test]
+    fn resolve_first_ns_skips_unreachable_name_servers() {
+        let start = Instant::now();
+        let ns_names = vec!["black.hole".to_string(), "a.ns.net".to_string()];
+
+        let result = resolve_first_ns(ns_names, |ns_name| match ns_name {
+            "black.hole" => {
+                thread::sleep(Duration::from_secs(2));
+                anyhow::bail!("Timed out")
+            }
+            _ => Ok(Some(Ipv4Addr::new(192, 0, 2, 53))),
+        });
+
+        assert_eq!(result, Some(Ipv4Addr::new(192, 0, 2, 53)));
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn resolve_first_ns_moves_on_to_next_batch() {
+        let ns_names = (0..PARALLEL_NS_LOOKUPS + 1)
+            .map(|idx| format!("ns{}.net", idx))
+            .collect::<Vec<_>>();
+        let last = ns_names.last().unwrap().clone();
+
+        let result = resolve_first_ns(ns_names, move |ns_name| {
+            if ns_name == last {
+                Ok(Some(Ipv4Addr::new(192, 0, 2, 53)))
+            } else {
+                Ok(None)
+            }
+        });
+
+        assert_eq!(result, Some(Ipv4Addr::new(192, 0, 2, 53)));
+    }
+
+    #[test]
+    fn extended_upstream_failures_become_servfail() {
+        let request = mk_query(1234, "example.com", QueryType::A);
+        let mut upstream = a_response(&request, 1, Ipv4Addr::new(1, 2, 3, 4));
+        upstream.resources.push(DnsRecord::OPT {
+            udp_payload_size: 1232,
+            ext_rcode: 1,
+            version: 0,
+            flags: 0,
+            data: vec![],
+        });
+        assert_eq!(upstream.rescode(), ResponseCode::BADVERS);
+
+        let response = response_from_lookup(&request, Ok(upstream));
+
+        assert_eq!(response.rescode(), ResponseCode::SERVFAIL);
+    }
+
+    #[test]
+    fn resolve_rejects_multiple_questions() {
+        let mut request = mk_query(1234, "example.com", QueryType::A);
+        request.questions.push(
+            DnsQuestion::builder()
+                .name("example.org".to_string())
+                .qtype(QueryType::A)
+                .build(),
+        );
+        request.header.questions = 2;
+
+        let response = resolve(request, &[]).unwrap();
+
+        assert_eq!(response.header.id, 1234);
+        assert_eq!(response.rescode(), ResponseCode::FORMERR);
+        assert!(!response.has_answers());
+    }
+
+    #[test]
+    fn reverse_name_works() {
+        let result = reverse_name(Ipv4Addr::new(192, 0, 2, 10));
+
+        assert_eq!(result, "10.2.0.192.in-addr.arpa");
+    }
+
+    #[test]
+    fn reverse_name_v6_works() {
+        let result = reverse_name_v6("2001:db8::567:89ab".parse().unwrap());
+
+        assert_eq!(
+            result,
+            "b.a.9.8.7.6.5.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa"
+        );
+    }
 }
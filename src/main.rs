@@ -1,28 +1,231 @@
-use std::{convert::TryFrom, net::UdpSocket};
+use std::{
+    env,
+    net::{IpAddr, SocketAddr, TcpListener, UdpSocket},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
 
-use log::{debug, info};
-use rdns::{resolve, write_packet, DnsPacket, MAX_PACKET_SIZE};
+use anyhow::Context;
+use log::{error, info};
+use rdns::{
+    hosts::{hosts_records, load_hosts},
+    zone::load_zone,
+    Cache, ReservedBitPolicy, ResolverMode, Server, RECURSIVE_DNS_SERVER,
+};
+use structopt::StructOpt;
 
-static DNS_SERVER: (&str, u16) = ("127.0.0.1", 2053);
+// Path to a zone file with the records this server should answer authoritatively
+static ZONE_FILE_VAR: &str = "RDNS_ZONE_FILE";
+// Path to a hosts file with addresses that should be pinned
+static HOSTS_FILE_VAR: &str = "RDNS_HOSTS_FILE";
+// How long a TCP connection may sit idle before we hang up on it (RFC 7766 section 6.2.3)
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "RDNS", about = "A toy recursive DNS server")]
+struct Opt {
+    /// Address to listen on
+    #[structopt(short, long, default_value = "127.0.0.1")]
+    bind: IpAddr,
+    /// Port to listen on
+    #[structopt(short, long, default_value = "2053")]
+    port: u16,
+    /// Forward queries to a recursive server instead of resolving them from the root servers
+    #[structopt(long)]
+    forward: bool,
+    /// Recursive server to forward queries to (implies --forward) [default: 8.8.8.8:53]
+    #[structopt(long)]
+    upstream: Option<SocketAddr>,
+    /// How many UDP requests are served at the same time
+    #[structopt(long, default_value = "256")]
+    max_workers: usize,
+    /// How many responses are cached at most, the least recently used ones are dropped first
+    #[structopt(long, default_value = "10000")]
+    cache_size: usize,
+    /// For how many seconds past their expiry cached responses are served while being refreshed
+    #[structopt(long, default_value = "0")]
+    max_stale: u64,
+    /// Reject requests with the reserved Z bit set (FORMERR) instead of ignoring it
+    #[structopt(long)]
+    strict_z: bool,
+}
+
+impl Opt {
+    fn addr(&self) -> SocketAddr {
+        SocketAddr::new(self.bind, self.port)
+    }
+
+    fn mode(&self) -> ResolverMode {
+        match self.upstream {
+            Some(upstream) => ResolverMode::Forward(upstream),
+            None if self.forward => ResolverMode::Forward(SocketAddr::from(RECURSIVE_DNS_SERVER)),
+            None => ResolverMode::Recursive,
+        }
+    }
+
+    fn reserved_bit(&self) -> ReservedBitPolicy {
+        match self.strict_z {
+            true => ReservedBitPolicy::Strict,
+            false => ReservedBitPolicy::Lenient,
+        }
+    }
+}
 
 fn main() -> anyhow::Result<()> {
     env_logger::init();
 
-    info!("Starting DNS Server: {:?}", DNS_SERVER);
-    let socket = UdpSocket::bind(DNS_SERVER)?;
+    let opt = Opt::from_args();
+    let addr = opt.addr();
+
+    let mut zone = match env::var(ZONE_FILE_VAR) {
+        Ok(path) => load_zone(path)?,
+        Err(_) => vec![],
+    };
+    info!("Loaded {} records from zone", zone.len());
+
+    if let Ok(path) = env::var(HOSTS_FILE_VAR) {
+        let hosts = load_hosts(path)?;
+        info!("Loaded {} names from hosts file", hosts.len());
+        zone.extend(hosts_records(&hosts));
+    }
+
+    info!("Starting DNS Server: {}", addr);
+    let socket = UdpSocket::bind(addr).with_context(|| format!("Unable to bind to {}", addr))?;
+    let listener =
+        TcpListener::bind(addr).with_context(|| format!("Unable to bind to {} (TCP)", addr))?;
+
+    let server = Arc::new(
+        Server::builder()
+            .zone(zone)
+            .mode(opt.mode())
+            .cache(
+                Cache::with_max_entries(opt.cache_size)
+                    .with_max_stale(Duration::from_secs(opt.max_stale)),
+            )
+            .reserved_bit(opt.reserved_bit())
+            .build(),
+    );
+    {
+        let server = Arc::clone(&server);
+        thread::spawn(move || accept_tcp(server, listener));
+    }
+
+    server.serve_udp(&socket, opt.max_workers)
+}
+
+// Like with UDP, every connection is served on its own thread
+fn accept_tcp(server: Arc<Server>, listener: TcpListener) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(error) => {
+                error!("Unable to accept TCP connection: {:?}", error);
+                continue;
+            }
+        };
 
-    loop {
-        let mut request_buffer = vec![0u8; MAX_PACKET_SIZE];
-        let (size, source) = socket.recv_from(&mut request_buffer)?;
+        let server = Arc::clone(&server);
+        thread::spawn(move || {
+            let peer = stream.peer_addr();
+            let result = stream
+                .set_read_timeout(Some(TCP_IDLE_TIMEOUT))
+                .map_err(anyhow::Error::from)
+                .and_then(|_| server.serve_tcp(stream));
+            if let Err(error) = result {
+                error!(
+                    "Unable to serve TCP connection from {:?}: {:?}",
+                    peer, error
+                );
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use rdns::{write_packet, DnsPacket, QueryType, ResponseCode, MAX_EDNS_PACKET_SIZE};
+
+    #[test]
+    fn opt_defaults_to_localhost() {
+        let opt = Opt::from_iter(&["rdns"]);
+
+        assert_eq!(opt.addr(), "127.0.0.1:2053".parse().unwrap());
+        assert_eq!(opt.max_workers, 256);
+        assert_eq!(opt.cache_size, 10000);
+        assert_eq!(opt.max_stale, 0);
+        assert_eq!(opt.reserved_bit(), ReservedBitPolicy::Lenient);
+    }
+
+    #[test]
+    fn opt_parses_bind_address_and_port() {
+        let opt = Opt::from_iter(&["rdns", "--bind", "0.0.0.0", "--port", "53"]);
+
+        assert_eq!(opt.addr(), "0.0.0.0:53".parse().unwrap());
+    }
 
-        let request = DnsPacket::try_from(&request_buffer[..size]).map_err(anyhow::Error::msg)?;
-        debug!("Request: {:?}, from: {:?}", request, source);
+    #[test]
+    fn opt_selects_resolver_mode() {
+        let recursive = Opt::from_iter(&["rdns"]);
+        let forward = Opt::from_iter(&["rdns", "--forward"]);
+
+        assert_eq!(recursive.mode(), ResolverMode::Recursive);
+        assert_eq!(
+            forward.mode(),
+            ResolverMode::Forward("8.8.8.8:53".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn opt_parses_upstream() {
+        let upstream = Opt::from_iter(&["rdns", "--upstream", "1.1.1.1:53"]);
+        let both = Opt::from_iter(&["rdns", "--forward", "--upstream", "[2606:4700::1111]:53"]);
+
+        assert_eq!(
+            upstream.mode(),
+            ResolverMode::Forward("1.1.1.1:53".parse().unwrap())
+        );
+        assert_eq!(
+            both.mode(),
+            ResolverMode::Forward("[2606:4700::1111]:53".parse().unwrap())
+        );
+        assert!(Opt::from_iter_safe(&["rdns", "--upstream", "1.1.1.1"]).is_err());
+    }
+
+    #[test]
+    fn upstream_is_forwarded_to() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let opt = Opt::from_iter(&[
+            "rdns".to_string(),
+            "--upstream".to_string(),
+            upstream.local_addr().unwrap().to_string(),
+        ]);
+        let server = Server::builder().mode(opt.mode()).build();
+        let handle = thread::spawn(move || {
+            let request = DnsPacket::query(1234, "example.com", QueryType::A);
+            server.resolve(request).unwrap()
+        });
+
+        let mut buf = vec![0u8; MAX_EDNS_PACKET_SIZE];
+        let (size, src) = upstream.recv_from(&mut buf).unwrap();
+        let request = DnsPacket::parse(&buf[..size]).unwrap();
+        let mut response = request.clone();
+        response.header.response = true;
+        response.header.rescode = ResponseCode::REFUSED;
+        let size = write_packet(&mut buf, &response).unwrap();
+        upstream.send_to(&buf[..size], src).unwrap();
+        let response = handle.join().unwrap();
+
+        assert_eq!(request.qname().unwrap().to_ascii_lowercase(), "example.com");
+        assert_eq!(response.rescode(), ResponseCode::REFUSED);
+    }
 
-        let response = resolve(request)?;
-        debug!("Response: {:?}", response);
+    #[test]
+    fn opt_rejects_invalid_address() {
+        let result = Opt::from_iter_safe(&["rdns", "--bind", "localhost"]);
 
-        let mut response_buffer = vec![0u8; MAX_PACKET_SIZE];
-        let size = write_packet(&mut response_buffer, &response)?;
-        socket.send_to(&response_buffer[..size], source)?;
+        assert!(result.is_err());
     }
 }
@@ -0,0 +1,62 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{DnsPacket, ResponseCode};
+
+// Counters of what the server has been up to, for operators that want to keep an eye on it. They
+// only ever go up, it is up to whoever reads them to work out rates.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    queries_total: AtomicU64,
+    cache_hits: AtomicU64,
+    servfail_total: AtomicU64,
+    nxdomain_total: AtomicU64,
+    upstream_queries: AtomicU64,
+}
+
+// The counters at a single point in time
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MetricsSnapshot {
+    // Requests answered, no matter how
+    pub queries_total: u64,
+    // Requests answered from the cache
+    pub cache_hits: u64,
+    // Responses with SERVFAIL and NXDOMAIN respectively
+    pub servfail_total: u64,
+    pub nxdomain_total: u64,
+    // Queries sent to other name servers while looking up answers
+    pub upstream_queries: u64,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            queries_total: self.queries_total.load(Ordering::Relaxed),
+            cache_hits: self.cache_hits.load(Ordering::Relaxed),
+            servfail_total: self.servfail_total.load(Ordering::Relaxed),
+            nxdomain_total: self.nxdomain_total.load(Ordering::Relaxed),
+            upstream_queries: self.upstream_queries.load(Ordering::Relaxed),
+        }
+    }
+
+    // Counts a request along with the response it got
+    pub(crate) fn record_response(&self, response: &DnsPacket) {
+        self.queries_total.fetch_add(1, Ordering::Relaxed);
+        match response.rescode() {
+            ResponseCode::SERVFAIL => self.servfail_total.fetch_add(1, Ordering::Relaxed),
+            ResponseCode::NXDOMAIN => self.nxdomain_total.fetch_add(1, Ordering::Relaxed),
+            _ => 0,
+        };
+    }
+
+    pub(crate) fn record_cache_hit(&self) {
+        self.cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_upstream_query(&self) {
+        self.upstream_queries.fetch_add(1, Ordering::Relaxed);
+    }
+}
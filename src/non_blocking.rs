@@ -0,0 +1,308 @@
+use std::{future::Future, net::SocketAddr, pin::Pin};
+
+use log::{debug, info, warn};
+use tokio::{
+    net::UdpSocket,
+    time::{timeout_at, Instant},
+};
+
+use crate::{
+    accept_response, answer_locally, check_depth, is_lame, local_addr_for, mk_upstream_query,
+    negotiate_edns, next_step, randomize_case, refer_locally, response_from_lookup,
+    retransmit_interval, write_packet_vec, DnsPacket, DnsRecord, NextStep, QueryType, Resolver,
+    DEFAULT_EDNS_PAYLOAD_SIZE, DNS_PORT, LOOKUP_TIMEOUT, MAX_HOPS, RESOLUTION_BUDGET,
+};
+
+// Async counterparts of `resolve`, `recursive_lookup` and `lookup` for when rdns is embedded in a
+// tokio application. They behave exactly like the blocking versions (budget for recursive lookups
+// included), except that name servers without glue records are resolved one after the other
+// instead of on a bunch of threads.
+
+pub async fn resolve_async(request: DnsPacket, zone: &[DnsRecord]) -> anyhow::Result<DnsPacket> {
+    let response = match answer_locally(&request, zone) {
+        Some(response) => response,
+        None if !request.header.recursion_desired => refer_locally(&request, zone),
+        None => {
+            let (qname, qtype) = (request.qname().unwrap(), request.qtype().unwrap());
+            info!("Starting recursive lookup for {} ({:?})", qname, qtype);
+            let result = recursive_lookup_async(&qname, qtype).await;
+
+            response_from_lookup(&request, result)
+        }
+    };
+
+    Ok(negotiate_edns(&request, response))
+}
+
+pub async fn recursive_lookup_async(qname: &str, qtype: QueryType) -> anyhow::Result<DnsPacket> {
+    let roots = Resolver::default().roots()?;
+    let deadline = Instant::now() + RESOLUTION_BUDGET;
+    lookup_from(qname, qtype, &roots, DNS_PORT, 0, deadline).await
+}
+
+// Walks down the delegation chain starting at one of the `roots`, see `trace_lookup`. The future
+// is boxed as it calls itself to resolve the addresses of name servers, which have to be resolved
+// by the same `deadline`.
+fn lookup_from<'a>(
+    qname: &'a str,
+    qtype: QueryType,
+    roots: &'a [SocketAddr],
+    ns_port: u16,
+    depth: usize,
+    deadline: Instant,
+) -> Pin<Box<dyn Future<Output = anyhow::Result<DnsPacket>> + Send + 'a>> {
+    Box::pin(async move {
+        check_depth(qname, depth)?;
+
+        let mut servers = roots.to_vec();
+        for hop in 0..MAX_HOPS {
+            let response = ask_first_async(qname, qtype, &servers, hop > 0, deadline).await?;
+
+            servers = match next_step(&response, qname, ns_port) {
+                NextStep::Done => return Ok(response),
+                NextStep::Ask(new_servers) => new_servers,
+                NextStep::ResolveNs(new_ns_names) => {
+                    match resolve_first_ns(new_ns_names, roots, ns_port, depth, deadline).await {
+                        Some(new_ns) => vec![new_ns],
+                        None => return Ok(response),
+                    }
+                }
+            };
+        }
+
+        anyhow::bail!("Giving up on {} after {} referrals", qname, MAX_HOPS)
+    })
+}
+
+// See `ask_first`
+async fn ask_first_async(
+    qname: &str,
+    qtype: QueryType,
+    servers: &[SocketAddr],
+    referred: bool,
+    deadline: Instant,
+) -> anyhow::Result<DnsPacket> {
+    let mut last_error = anyhow::anyhow!("No name servers to ask about {}", qname);
+    for &ns in servers {
+        if Instant::now() >= deadline {
+            anyhow::bail!("Ran out of time resolving {}", qname);
+        }
+        info!(
+            "attempting lookup of {} ({:?}) with ns {}",
+            qname, qtype, ns
+        );
+
+        let deadline = deadline.min(Instant::now() + LOOKUP_TIMEOUT);
+        match lookup_until_async(qname, qtype, ns, deadline).await {
+            Ok(response) if referred && is_lame(&response) => {
+                warn!("Lame delegation: {} does not serve {}", ns, qname);
+                last_error = anyhow::anyhow!("Lame delegation: {} does not serve {}", ns, qname);
+            }
+            Ok(response) => return Ok(response),
+            Err(error) => {
+                warn!("No usable response from {}: {:?}", ns, error);
+                last_error = error;
+            }
+        }
+    }
+
+    Err(last_error)
+}
+
+async fn resolve_first_ns(
+    ns_names: Vec<String>,
+    roots: &[SocketAddr],
+    ns_port: u16,
+    depth: usize,
+    deadline: Instant,
+) -> Option<SocketAddr> {
+    for ns_name in ns_names {
+        match lookup_from(&ns_name, QueryType::A, roots, ns_port, depth + 1, deadline).await {
+            Ok(ns_response) => {
+                if let Some(addr) = ns_response.get_random_a() {
+                    return Some(SocketAddr::new(addr.into(), ns_port));
+                }
+            }
+            Err(error) => info!("Unable to resolve name server {}: {:?}", ns_name, error),
+        }
+    }
+
+    None
+}
+
+pub async fn lookup_async(
+    qname: &str,
+    qtype: QueryType,
+    server: SocketAddr,
+) -> anyhow::Result<DnsPacket> {
+    lookup_until_async(qname, qtype, server, Instant::now() + LOOKUP_TIMEOUT).await
+}
+
+// See `lookup_until`
+async fn lookup_until_async(
+    qname: &str,
+    qtype: QueryType,
+    server: SocketAddr,
+    deadline: Instant,
+) -> anyhow::Result<DnsPacket> {
+    let socket = UdpSocket::bind(local_addr_for(server)).await?;
+
+    let request = mk_upstream_query(
+        rand::random(),
+        &randomize_case(qname),
+        qtype,
+        DEFAULT_EDNS_PAYLOAD_SIZE,
+    )?;
+    debug!("Request: {:?}", request);
+
+    let query = write_packet_vec(&request)?;
+    let mut retransmit_at = Instant::now();
+    let mut attempt = 0;
+    let mut response_buffer = vec![0u8; request.udp_payload_size()];
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            anyhow::bail!("Timed out waiting for a response from {}", server);
+        }
+        if now >= retransmit_at {
+            socket.send_to(&query, server).await?;
+            retransmit_at = now + retransmit_interval(attempt);
+            attempt += 1;
+        }
+
+        let received = timeout_at(
+            retransmit_at.min(deadline),
+            socket.recv_from(&mut response_buffer),
+        )
+        .await;
+        let (size, src) = match received {
+            Ok(received) => received?,
+            Err(_elapsed) => continue,
+        };
+        if let Some(response) = accept_response(&response_buffer[..size], src, server, &request)? {
+            return Ok(response);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{write_packet, DnsHeader, ResponseCode, MAX_PACKET_SIZE};
+    use pretty_assertions::assert_eq;
+    use std::{net::Ipv4Addr, thread, time::Duration};
+
+    // Answers a single query with an A record pointing to `addr`
+    fn serve_a(stub: std::net::UdpSocket, addr: Ipv4Addr) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            let mut buf = vec![0u8; MAX_PACKET_SIZE];
+            let (size, src) = stub.recv_from(&mut buf).unwrap();
+            let request = DnsPacket::parse(&buf[..size]).unwrap();
+
+            let question = request.first_question().unwrap().clone();
+            let record = DnsRecord::A {
+                domain: question.name.clone(),
+                addr,
+                ttl: 300,
+            };
+            let header = DnsHeader::builder()
+                .id(request.header.id)
+                .response(true)
+                .questions(1)
+                .answers(1)
+                .build();
+            let response = DnsPacket::builder()
+                .header(header)
+                .questions(vec![question])
+                .answers(vec![record])
+                .build();
+
+            let size = write_packet(&mut buf, &response).unwrap();
+            stub.send_to(&buf[..size], src).unwrap();
+        })
+    }
+
+    #[tokio::test]
+    async fn lookup_async_works() {
+        let stub = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server = stub.local_addr().unwrap();
+        let handle = serve_a(stub, Ipv4Addr::new(1, 2, 3, 4));
+
+        let response = lookup_async("example.com", QueryType::A, server)
+            .await
+            .unwrap();
+        handle.join().unwrap();
+
+        assert_eq!(response.get_random_a(), Some(Ipv4Addr::new(1, 2, 3, 4)));
+    }
+
+    #[tokio::test]
+    async fn lookup_from_stops_at_answer() {
+        let stub = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let root = stub.local_addr().unwrap();
+        let handle = serve_a(stub, Ipv4Addr::new(5, 6, 7, 8));
+
+        let deadline = Instant::now() + RESOLUTION_BUDGET;
+        let response = lookup_from(
+            "example.com",
+            QueryType::A,
+            &[root],
+            root.port(),
+            0,
+            deadline,
+        )
+        .await
+        .unwrap();
+        handle.join().unwrap();
+
+        assert_eq!(response.get_random_a(), Some(Ipv4Addr::new(5, 6, 7, 8)));
+    }
+
+    #[tokio::test]
+    async fn lookup_from_stays_within_its_budget() {
+        let stub = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server = stub.local_addr().unwrap();
+        // Keeps on referring us back to itself, taking its time with every hop. Without a budget
+        // all the hops together would take several seconds.
+        stub.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let handle = thread::spawn(move || {
+            let mut buf = vec![0u8; MAX_PACKET_SIZE];
+            while let Ok((size, src)) = stub.recv_from(&mut buf) {
+                let request = DnsPacket::parse(&buf[..size]).unwrap();
+                thread::sleep(Duration::from_millis(150));
+
+                let mut response = DnsPacket::servfail(&request);
+                response.header.rescode = ResponseCode::NOERROR;
+                response.push_authority(DnsRecord::NS {
+                    domain: "com".to_string(),
+                    host: "ns.example.com".to_string(),
+                    ttl: 172800,
+                });
+                response.push_resource(DnsRecord::A {
+                    domain: "ns.example.com".to_string(),
+                    addr: Ipv4Addr::LOCALHOST,
+                    ttl: 172800,
+                });
+                let size = write_packet(&mut buf, &response).unwrap();
+                stub.send_to(&buf[..size], src).unwrap();
+            }
+        });
+
+        let start = Instant::now();
+        let deadline = start + Duration::from_secs(1);
+        let result = lookup_from(
+            "example.com",
+            QueryType::A,
+            &[server],
+            server.port(),
+            0,
+            deadline,
+        )
+        .await;
+        let elapsed = start.elapsed();
+        handle.join().unwrap();
+
+        assert!(result.is_err());
+        assert!(elapsed < Duration::from_millis(1500), "took {:?}", elapsed);
+    }
+}
@@ -0,0 +1,654 @@
+use std::{
+    collections::HashSet,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use rand::seq::SliceRandom;
+
+use log::warn;
+
+use crate::{
+    reverse_name, reverse_name_v6, trace_lookup, DnsPacket, DnsRecord, LookupOptions, Metrics,
+    QueryType, TraceStep, DEFAULT_EDNS_PAYLOAD_SIZE, DNS_PORT, RESOLUTION_BUDGET,
+};
+
+// The IPv4 addresses of the 13 root servers, a.root-servers.net through m.root-servers.net (see
+// https://www.iana.org/domains/root/servers)
+pub const ROOT_HINTS: [Ipv4Addr; 13] = [
+    Ipv4Addr::new(198, 41, 0, 4),
+    Ipv4Addr::new(170, 247, 170, 2),
+    Ipv4Addr::new(192, 33, 4, 12),
+    Ipv4Addr::new(199, 7, 91, 13),
+    Ipv4Addr::new(192, 203, 230, 10),
+    Ipv4Addr::new(192, 5, 5, 241),
+    Ipv4Addr::new(192, 112, 36, 4),
+    Ipv4Addr::new(198, 97, 190, 53),
+    Ipv4Addr::new(192, 36, 148, 17),
+    Ipv4Addr::new(192, 58, 128, 30),
+    Ipv4Addr::new(193, 0, 14, 129),
+    Ipv4Addr::new(199, 7, 83, 42),
+    Ipv4Addr::new(202, 12, 27, 33),
+];
+
+// How many CNAMEs `Resolver::resolve_ips` follows before giving up on a name
+const MAX_CNAME_CHAIN: usize = 8;
+
+// A mail server of a domain (see `Resolver::resolve_mx`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MailExchange {
+    pub priority: u16,
+    pub host: String,
+    pub addrs: Vec<IpAddr>,
+}
+
+// A server offering a service (see `Resolver::resolve_srv`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServiceTarget {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    pub target: String,
+    pub addrs: Vec<IpAddr>,
+}
+
+// Does recursive lookups, walking down the delegation chain from one of the root servers it
+// knows about. Every lookup starts from a randomly picked one, so that the load is spread, moving
+// on to the others should it not respond.
+//
+// Alternate roots come in handy for tests or a split-horizon setup. The UDP payload size it
+// advertises defaults to `DEFAULT_EDNS_PAYLOAD_SIZE` and can be raised on networks known not to
+// fragment, so that fewer responses get truncated. A lookup that takes longer than
+// `RESOLUTION_BUDGET` (or whatever it is set to) fails, however far it got. EDNS can be turned off
+// altogether for name servers that do not cope with it, queries then hold nothing but the question.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Resolver {
+    root_hints: Vec<SocketAddr>,
+    udp_payload_size: u16,
+    edns: bool,
+    resolution_budget: Duration,
+}
+
+impl Default for Resolver {
+    fn default() -> Resolver {
+        let root_hints = ROOT_HINTS
+            .iter()
+            .map(|addr| SocketAddr::new((*addr).into(), DNS_PORT))
+            .collect();
+
+        Resolver::new(root_hints)
+    }
+}
+
+impl Resolver {
+    pub fn new(root_hints: Vec<SocketAddr>) -> Resolver {
+        Resolver {
+            root_hints,
+            udp_payload_size: DEFAULT_EDNS_PAYLOAD_SIZE,
+            edns: true,
+            resolution_budget: RESOLUTION_BUDGET,
+        }
+    }
+
+    pub fn with_udp_payload_size(mut self, udp_payload_size: u16) -> Resolver {
+        self.udp_payload_size = udp_payload_size;
+        self
+    }
+
+    pub fn with_edns(mut self, edns: bool) -> Resolver {
+        self.edns = edns;
+        self
+    }
+
+    pub fn with_resolution_budget(mut self, resolution_budget: Duration) -> Resolver {
+        self.resolution_budget = resolution_budget;
+        self
+    }
+
+    pub fn root_hints(&self) -> &[SocketAddr] {
+        &self.root_hints
+    }
+
+    pub fn udp_payload_size(&self) -> u16 {
+        self.udp_payload_size
+    }
+
+    pub fn edns(&self) -> bool {
+        self.edns
+    }
+
+    pub fn resolution_budget(&self) -> Duration {
+        self.resolution_budget
+    }
+
+    pub fn recursive_lookup(&self, qname: &str, qtype: QueryType) -> anyhow::Result<DnsPacket> {
+        self.recursive_lookup_traced(qname, qtype)
+            .map(|(response, _trace)| response)
+    }
+
+    // See `recursive_lookup_traced` at the crate root
+    pub fn recursive_lookup_traced(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+    ) -> anyhow::Result<(DnsPacket, Vec<TraceStep>)> {
+        let mut trace = vec![];
+        let response = trace_lookup(
+            qname,
+            qtype,
+            &self.roots()?,
+            DNS_PORT,
+            0,
+            &mut trace,
+            &self.lookup_options(Arc::default()),
+        )?;
+
+        Ok((response, trace))
+    }
+
+    // `recursive_lookup` counting the queries it sends in `metrics`
+    pub(crate) fn recursive_lookup_metered(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        metrics: &Arc<Metrics>,
+    ) -> anyhow::Result<DnsPacket> {
+        trace_lookup(
+            qname,
+            qtype,
+            &self.roots()?,
+            DNS_PORT,
+            0,
+            &mut vec![],
+            &self.lookup_options(Arc::clone(metrics)),
+        )
+    }
+
+    fn lookup_options(&self, metrics: Arc<Metrics>) -> LookupOptions {
+        LookupOptions {
+            udp_payload_size: self.edns.then_some(self.udp_payload_size),
+            metrics,
+            deadline: Instant::now() + self.resolution_budget,
+        }
+    }
+
+    // The mail servers of `qname`, most preferred (lowest priority) first, along with their
+    // addresses
+    pub fn resolve_mx(&self, qname: &str) -> anyhow::Result<Vec<MailExchange>> {
+        let response = self.recursive_lookup(qname, QueryType::MX)?;
+
+        let mut exchanges = response
+            .answers
+            .iter()
+            .filter_map(|record| match record {
+                DnsRecord::MX { priority, host, .. } => Some(MailExchange {
+                    priority: *priority,
+                    host: host.clone(),
+                    addrs: self.addrs_of(&response, host),
+                }),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        exchanges.sort_by_key(|exchange| exchange.priority);
+
+        Ok(exchanges)
+    }
+
+    // The servers offering the service `qname` (e.g. _sip._udp.example.com), lowest priority first
+    // along with their addresses. Picking one by weight (RFC 2782) is left to the caller.
+    pub fn resolve_srv(&self, qname: &str) -> anyhow::Result<Vec<ServiceTarget>> {
+        let response = self.recursive_lookup(qname, QueryType::SRV)?;
+
+        let mut targets = response
+            .answers
+            .iter()
+            .filter_map(|record| match record {
+                DnsRecord::SRV {
+                    priority,
+                    weight,
+                    port,
+                    target,
+                    ..
+                } => Some(ServiceTarget {
+                    priority: *priority,
+                    weight: *weight,
+                    port: *port,
+                    target: target.clone(),
+                    addrs: self.addrs_of(&response, target),
+                }),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        targets.sort_by_key(|target| target.priority);
+
+        Ok(targets)
+    }
+
+    // The names an address maps back to, IPv4 and IPv6 alike
+    pub fn resolve_ptr(&self, ip: IpAddr) -> anyhow::Result<Vec<String>> {
+        let qname = match ip {
+            IpAddr::V4(ip) => reverse_name(ip),
+            IpAddr::V6(ip) => reverse_name_v6(ip),
+        };
+        let response = self.recursive_lookup(&qname, QueryType::PTR)?;
+
+        Ok(response
+            .answers
+            .iter()
+            .filter_map(|record| match record {
+                DnsRecord::PTR { host, .. } => Some(host.clone()),
+                _ => None,
+            })
+            .collect())
+    }
+
+    // Addresses of a host named in `response`, taken from the glue it came with when there is any
+    // and looked up otherwise. A host that can not be resolved simply has no addresses.
+    fn addrs_of(&self, response: &DnsPacket, host: &str) -> Vec<IpAddr> {
+        let glue = response.glue_for(host);
+        if !glue.is_empty() {
+            return glue;
+        }
+
+        self.resolve_ips(host).unwrap_or_else(|error| {
+            warn!("Unable to resolve {}: {:?}", host, error);
+            vec![]
+        })
+    }
+
+    // Every address of `qname`, IPv4 ones first, following CNAMEs along the way. The DNS analog of
+    // `to_socket_addrs`.
+    pub fn resolve_ips(&self, qname: &str) -> anyhow::Result<Vec<IpAddr>> {
+        let mut addrs = self.addrs_following_cnames(qname, QueryType::A)?;
+        addrs.extend(self.addrs_following_cnames(qname, QueryType::AAAA)?);
+
+        let mut seen = HashSet::new();
+        addrs.retain(|addr| seen.insert(*addr));
+
+        Ok(addrs)
+    }
+
+    // The A or AAAA records of `qname`, or of the name it is an alias of
+    fn addrs_following_cnames(&self, qname: &str, qtype: QueryType) -> anyhow::Result<Vec<IpAddr>> {
+        debug_assert!(qtype.is_address());
+        let mut name = qname.to_string();
+        for _ in 0..MAX_CNAME_CHAIN {
+            let response = self.recursive_lookup(&name, qtype)?;
+            let addrs = match qtype {
+                QueryType::AAAA => response
+                    .get_all_aaaa()
+                    .into_iter()
+                    .map(IpAddr::from)
+                    .collect(),
+                _ => response
+                    .get_all_a()
+                    .into_iter()
+                    .map(IpAddr::from)
+                    .collect::<Vec<_>>(),
+            };
+            if !addrs.is_empty() {
+                return Ok(addrs);
+            }
+
+            let alias_of = response.answers.iter().find_map(|record| match record {
+                DnsRecord::CNAME { domain, host, .. } if domain.eq_ignore_ascii_case(&name) => {
+                    Some(host.clone())
+                }
+                _ => None,
+            });
+            match alias_of {
+                Some(host) => name = host,
+                None => return Ok(vec![]),
+            }
+        }
+
+        anyhow::bail!("Giving up on {} after {} CNAMEs", qname, MAX_CNAME_CHAIN)
+    }
+
+    // The root hints in random order, tried one after the other should a root server not respond
+    pub(crate) fn roots(&self) -> anyhow::Result<Vec<SocketAddr>> {
+        if self.root_hints.is_empty() {
+            anyhow::bail!("No root servers to start the lookup from");
+        }
+
+        let mut roots = self.root_hints.clone();
+        roots.shuffle(&mut rand::thread_rng());
+
+        Ok(roots)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{stub::StubServer, write_packet, DnsHeader, MAX_PACKET_SIZE};
+    use pretty_assertions::assert_eq;
+    use std::{collections::HashMap, net::UdpSocket, thread};
+
+    #[test]
+    fn default_resolver_starts_from_root_servers() {
+        let resolver = Resolver::default();
+
+        assert_eq!(resolver.root_hints().len(), 13);
+        assert!(resolver
+            .root_hints()
+            .contains(&"198.41.0.4:53".parse().unwrap()));
+    }
+
+    #[test]
+    fn resolver_starts_from_its_root_hints() {
+        let stub = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let root = stub.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let mut buf = vec![0u8; MAX_PACKET_SIZE];
+            let (size, src) = stub.recv_from(&mut buf).unwrap();
+            let request = DnsPacket::parse(&buf[..size]).unwrap();
+
+            let record = DnsRecord::A {
+                domain: request.qname().unwrap(),
+                addr: Ipv4Addr::new(1, 2, 3, 4),
+                ttl: 300,
+            };
+            let header = DnsHeader::builder()
+                .id(request.header.id)
+                .response(true)
+                .questions(1)
+                .answers(1)
+                .build();
+            let response = DnsPacket::builder()
+                .header(header)
+                .questions(request.questions)
+                .answers(vec![record])
+                .build();
+
+            let size = write_packet(&mut buf, &response).unwrap();
+            stub.send_to(&buf[..size], src).unwrap();
+        });
+        let resolver = Resolver::new(vec![root]);
+
+        let (response, trace) = resolver
+            .recursive_lookup_traced("example.com", QueryType::A)
+            .unwrap();
+        handle.join().unwrap();
+
+        assert_eq!(response.get_random_a(), Some(Ipv4Addr::new(1, 2, 3, 4)));
+        assert_eq!(trace.len(), 1);
+        assert_eq!(trace[0].server, root);
+    }
+
+    #[test]
+    fn resolver_advertises_its_udp_payload_size() {
+        let stub = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let root = stub.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let mut buf = vec![0u8; MAX_PACKET_SIZE];
+            let (size, src) = stub.recv_from(&mut buf).unwrap();
+            let request = DnsPacket::parse(&buf[..size]).unwrap();
+
+            let header = DnsHeader::builder()
+                .id(request.header.id)
+                .response(true)
+                .questions(1)
+                .build();
+            let response = DnsPacket::builder()
+                .header(header)
+                .questions(request.questions.clone())
+                .build();
+            let size = write_packet(&mut buf, &response).unwrap();
+            stub.send_to(&buf[..size], src).unwrap();
+
+            request
+        });
+        let resolver = Resolver::new(vec![root]).with_udp_payload_size(1400);
+
+        resolver
+            .recursive_lookup("example.com", QueryType::A)
+            .unwrap();
+        let request = handle.join().unwrap();
+
+        assert_eq!(Resolver::default().udp_payload_size(), 1232);
+        assert!(request
+            .resources
+            .iter()
+            .any(|record| matches!(record, DnsRecord::OPT { .. })));
+        assert_eq!(request.udp_payload_size(), 1400);
+    }
+
+    #[test]
+    fn resolver_without_edns_sends_minimal_queries() {
+        let stub = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let root = stub.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let mut buf = vec![0u8; MAX_PACKET_SIZE];
+            let (size, src) = stub.recv_from(&mut buf).unwrap();
+            let request = DnsPacket::parse(&buf[..size]).unwrap();
+
+            let header = DnsHeader::builder()
+                .id(request.header.id)
+                .response(true)
+                .questions(1)
+                .build();
+            let response = DnsPacket::builder()
+                .header(header)
+                .questions(request.questions.clone())
+                .build();
+            let size = write_packet(&mut buf, &response).unwrap();
+            stub.send_to(&buf[..size], src).unwrap();
+
+            request
+        });
+        let resolver = Resolver::new(vec![root]).with_edns(false);
+
+        resolver
+            .recursive_lookup("example.com", QueryType::A)
+            .unwrap();
+        let request = handle.join().unwrap();
+
+        assert!(Resolver::default().edns());
+        assert_eq!(request.questions.len(), 1);
+        assert!(request.answers.is_empty());
+        assert!(request.authorities.is_empty());
+        assert!(request.resources.is_empty());
+        assert_eq!(request.header.resource_entries, 0);
+    }
+
+    #[test]
+    fn resolve_mx_uses_glue() {
+        let mx = |priority, host: &str| DnsRecord::MX {
+            domain: "example.com".to_string(),
+            priority,
+            host: host.to_string(),
+            ttl: 300,
+        };
+        let glue = |host: &str, addr| DnsRecord::A {
+            domain: host.to_string(),
+            addr,
+            ttl: 300,
+        };
+        let header = DnsHeader::builder()
+            .id(0)
+            .answers(2)
+            .resource_entries(2)
+            .build();
+        let response = DnsPacket::builder()
+            .header(header)
+            .answers(vec![
+                mx(20, "backup.example.com"),
+                mx(10, "mail.example.com"),
+            ])
+            .resources(vec![
+                glue("mail.example.com", Ipv4Addr::new(192, 0, 2, 25)),
+                glue("backup.example.com", Ipv4Addr::new(192, 0, 2, 26)),
+            ])
+            .build();
+        let mut responses = HashMap::new();
+        responses.insert(("example.com".to_string(), QueryType::MX), response);
+        let stub = StubServer::start(responses);
+        let resolver = Resolver::new(vec![stub.addr()]);
+
+        let exchanges = resolver.resolve_mx("example.com").unwrap();
+
+        assert_eq!(
+            exchanges,
+            vec![
+                MailExchange {
+                    priority: 10,
+                    host: "mail.example.com".to_string(),
+                    addrs: vec![Ipv4Addr::new(192, 0, 2, 25).into()],
+                },
+                MailExchange {
+                    priority: 20,
+                    host: "backup.example.com".to_string(),
+                    addrs: vec![Ipv4Addr::new(192, 0, 2, 26).into()],
+                },
+            ]
+        );
+        // Everything came with the MX records
+        assert_eq!(stub.queries(), 1);
+    }
+
+    #[test]
+    fn resolve_ptr_works_for_ipv6() {
+        let qname = "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa";
+        let ptr = DnsRecord::PTR {
+            domain: qname.to_string(),
+            host: "host.example.com".to_string(),
+            ttl: 300,
+        };
+        let response = DnsPacket::builder()
+            .header(DnsHeader::builder().id(0).answers(1).build())
+            .answers(vec![ptr])
+            .build();
+        let mut responses = HashMap::new();
+        responses.insert((qname.to_string(), QueryType::PTR), response);
+        let stub = StubServer::start(responses);
+        let resolver = Resolver::new(vec![stub.addr()]);
+
+        let names = resolver
+            .resolve_ptr("2001:db8::1".parse().unwrap())
+            .unwrap();
+
+        assert_eq!(names, vec!["host.example.com".to_string()]);
+    }
+
+    #[test]
+    fn resolve_ips_follows_cnames_and_merges_families() {
+        let cname = DnsRecord::CNAME {
+            domain: "www.example.com".to_string(),
+            host: "example.com".to_string(),
+            ttl: 300,
+        };
+        let a = |addr| DnsRecord::A {
+            domain: "example.com".to_string(),
+            addr,
+            ttl: 300,
+        };
+        let aaaa = DnsRecord::AAAA {
+            domain: "example.com".to_string(),
+            addr: "2001:db8::1".parse().unwrap(),
+            ttl: 300,
+        };
+        let answer = |answers: Vec<DnsRecord>| {
+            DnsPacket::builder()
+                .header(
+                    DnsHeader::builder()
+                        .id(0)
+                        .answers(answers.len() as u16)
+                        .build(),
+                )
+                .answers(answers)
+                .build()
+        };
+        let mut responses = HashMap::new();
+        for qtype in [QueryType::A, QueryType::AAAA] {
+            responses.insert(
+                ("www.example.com".to_string(), qtype),
+                answer(vec![cname.clone()]),
+            );
+        }
+        responses.insert(
+            ("example.com".to_string(), QueryType::A),
+            answer(vec![
+                a(Ipv4Addr::new(192, 0, 2, 1)),
+                a(Ipv4Addr::new(192, 0, 2, 2)),
+                a(Ipv4Addr::new(192, 0, 2, 1)),
+            ]),
+        );
+        responses.insert(
+            ("example.com".to_string(), QueryType::AAAA),
+            answer(vec![aaaa]),
+        );
+        let stub = StubServer::start(responses);
+        let resolver = Resolver::new(vec![stub.addr()]);
+
+        let addrs = resolver.resolve_ips("www.example.com").unwrap();
+
+        assert_eq!(
+            addrs,
+            vec![
+                IpAddr::from(Ipv4Addr::new(192, 0, 2, 1)),
+                IpAddr::from(Ipv4Addr::new(192, 0, 2, 2)),
+                "2001:db8::1".parse::<IpAddr>().unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn resolve_srv_looks_up_targets_without_glue() {
+        let srv = DnsRecord::SRV {
+            domain: "_sip._udp.example.com".to_string(),
+            priority: 10,
+            weight: 5,
+            port: 5060,
+            target: "sip.example.com".to_string(),
+            ttl: 300,
+        };
+        let a = DnsRecord::A {
+            domain: "sip.example.com".to_string(),
+            addr: Ipv4Addr::new(192, 0, 2, 60),
+            ttl: 300,
+        };
+        let mut responses = HashMap::new();
+        responses.insert(
+            ("_sip._udp.example.com".to_string(), QueryType::SRV),
+            DnsPacket::builder()
+                .header(DnsHeader::builder().id(0).answers(1).build())
+                .answers(vec![srv])
+                .build(),
+        );
+        responses.insert(
+            ("sip.example.com".to_string(), QueryType::A),
+            DnsPacket::builder()
+                .header(DnsHeader::builder().id(0).answers(1).build())
+                .answers(vec![a])
+                .build(),
+        );
+        let stub = StubServer::start(responses);
+        let resolver = Resolver::new(vec![stub.addr()]);
+
+        let targets = resolver.resolve_srv("_sip._udp.example.com").unwrap();
+
+        assert_eq!(
+            targets,
+            vec![ServiceTarget {
+                priority: 10,
+                weight: 5,
+                port: 5060,
+                target: "sip.example.com".to_string(),
+                addrs: vec![Ipv4Addr::new(192, 0, 2, 60).into()],
+            }]
+        );
+        // The SRV records, then A and AAAA of the target
+        assert_eq!(stub.queries(), 3);
+    }
+
+    #[test]
+    fn resolver_without_root_hints_fails() {
+        let resolver = Resolver::new(vec![]);
+
+        assert!(resolver
+            .recursive_lookup("example.com", QueryType::A)
+            .is_err());
+    }
+}
@@ -0,0 +1,841 @@
+use std::{
+    io::{self, Read, Write},
+    net::{SocketAddr, UdpSocket},
+    sync::{Arc, Condvar, Mutex},
+    thread,
+};
+
+use log::{debug, error, info, warn};
+use typed_builder::TypedBuilder;
+
+use crate::{
+    answer_locally, cache::negative_ttl, echo_edns, forward_lookup, negotiate_edns, recursing,
+    refer_locally, response_from_lookup, write_packet, write_packet_tcp_vec, Cache, DnsPacket,
+    DnsRecord, InFlight, Metrics, QueryType, Resolver, ResolverMode, MAX_EDNS_PACKET_SIZE,
+};
+
+// Everything needed to answer requests, shared by all the requests being served at the same time
+// (see `resolve` for how a request gets answered)
+#[derive(Debug, Default, TypedBuilder)]
+pub struct Server {
+    // Records we answer authoritatively for
+    #[builder(default)]
+    zone: Vec<DnsRecord>,
+    #[builder(default)]
+    mode: ResolverMode,
+    // Used for recursive lookups, i.e. unless forwarding
+    #[builder(default)]
+    resolver: Resolver,
+    // Shared with the threads refreshing stale responses in the background
+    #[builder(default, setter(transform = |cache: Cache| Arc::new(cache)))]
+    cache: Arc<Cache>,
+    #[builder(default)]
+    in_flight: Arc<InFlight>,
+    // Shared with the threads resolving name servers, which can outlive the lookup they are for
+    #[builder(default)]
+    metrics: Arc<Metrics>,
+    #[builder(default)]
+    reserved_bit: ReservedBitPolicy,
+}
+
+// What to make of requests with the reserved Z bit of the header set, which is to be zero in
+// queries and responses alike (RFC 1035 section 4.1.1). Our responses never have it set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReservedBitPolicy {
+    // Ignore it, as plenty of clients out there do not bother clearing it
+    #[default]
+    Lenient,
+    // Reject the request with FORMERR
+    Strict,
+}
+
+impl Server {
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    // Answers a request that came in over UDP, truncating responses the client can not take
+    pub fn resolve(&self, request: DnsPacket) -> anyhow::Result<DnsPacket> {
+        let response = self.answer(&request);
+
+        Ok(negotiate_edns(&request, response))
+    }
+
+    // Answers a request that came in over TCP, where responses can be as big as they need to be
+    pub fn resolve_tcp(&self, request: DnsPacket) -> anyhow::Result<DnsPacket> {
+        let response = self.answer(&request);
+
+        Ok(echo_edns(&request, response))
+    }
+
+    // Serves the requests coming in on a TCP connection until the client closes it. Every message
+    // is preceded by its length in two octets (RFC 1035 section 4.2.2) and a client is free to send
+    // several of them, one after the other, on the same connection (RFC 7766).
+    pub fn serve_tcp<S: Read + Write>(&self, mut stream: S) -> anyhow::Result<()> {
+        loop {
+            let mut length = [0u8; 2];
+            match stream.read_exact(&mut length) {
+                Ok(()) => {}
+                Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(error) => return Err(error.into()),
+            }
+
+            let mut request_buffer = vec![0u8; u16::from_be_bytes(length) as usize];
+            stream.read_exact(&mut request_buffer)?;
+
+            let request = DnsPacket::parse(&request_buffer)?;
+            debug!("Request over TCP: {:?}", request);
+
+            let response = self.resolve_tcp(request)?;
+            debug!("Response over TCP: {:?}", response);
+
+            stream.write_all(&write_packet_tcp_vec(&response)?)?;
+        }
+    }
+
+    // Serves the requests coming in on a UDP socket, every one of them on its own thread so that a
+    // slow lookup does not hold up the rest. No more than `max_workers` are served at the same
+    // time, requests coming in meanwhile wait in the receive buffer of the socket.
+    pub fn serve_udp(
+        self: &Arc<Self>,
+        socket: &UdpSocket,
+        max_workers: usize,
+    ) -> anyhow::Result<()> {
+        let workers = Arc::new(Workers::new(max_workers));
+        loop {
+            let mut request_buffer = vec![0u8; MAX_EDNS_PACKET_SIZE];
+            let (size, source) = socket.recv_from(&mut request_buffer)?;
+            request_buffer.truncate(size);
+
+            let worker = Workers::start(&workers);
+            let (server, socket) = (Arc::clone(self), socket.try_clone()?);
+            thread::spawn(move || {
+                if let Err(error) = server.serve_datagram(&socket, &request_buffer, source) {
+                    error!("Unable to serve request from {}: {:?}", source, error);
+                }
+                drop(worker);
+            });
+        }
+    }
+
+    // The response goes back to wherever the request came from
+    fn serve_datagram(
+        &self,
+        socket: &UdpSocket,
+        request_buffer: &[u8],
+        source: SocketAddr,
+    ) -> anyhow::Result<()> {
+        let request = DnsPacket::parse(request_buffer)?;
+        debug!("Request: {:?}, from: {:?}", request, source);
+
+        let response = self.resolve(request)?;
+        debug!("Response: {:?}", response);
+
+        let mut response_buffer = vec![0u8; response.udp_payload_size()];
+        let size = write_packet(&mut response_buffer, &response)?;
+        socket.send_to(&response_buffer[..size], source)?;
+
+        Ok(())
+    }
+
+    fn answer(&self, request: &DnsPacket) -> DnsPacket {
+        let strict = self.reserved_bit == ReservedBitPolicy::Strict;
+        let mut response = if strict && request.header.z {
+            warn!("Client set the reserved Z bit: {:?}", request.header);
+            recursing(DnsPacket::formerr(request))
+        } else {
+            self.respond(request)
+        };
+        // We do not validate DNSSEC signatures, so nothing we serve can claim to be authentic
+        // (RFC 4035 section 3.2.3), whatever the client or the upstream server said
+        response.header.authed_data = false;
+        self.metrics.record_response(&response);
+
+        response
+    }
+
+    // Answers a request from our zone, the cache or a lookup, in that order
+    fn respond(&self, request: &DnsPacket) -> DnsPacket {
+        match answer_locally(request, &self.zone) {
+            Some(response) => response,
+            None if !request.header.recursion_desired => refer_locally(request, &self.zone),
+            None => {
+                let (qname, qtype) = (request.qname().unwrap(), request.qtype().unwrap());
+                let result = match self.cache.get(&qname, qtype) {
+                    Some(cached) => {
+                        info!("Answering {} ({:?}) from cache", qname, qtype);
+                        self.metrics.record_cache_hit();
+                        Ok(cached)
+                    }
+                    None => {
+                        match self
+                            .cache
+                            .get_allowing_stale(&qname, qtype, self.cache.max_stale())
+                        {
+                            Some(stale) => {
+                                info!("Answering {} ({:?}) with a stale response", qname, qtype);
+                                self.metrics.record_cache_hit();
+                                self.refresh(&qname, qtype);
+                                Ok(stale)
+                            }
+                            None => self
+                                .in_flight
+                                .lookup(&qname, qtype, || self.lookup(&qname, qtype)),
+                        }
+                    }
+                };
+
+                response_from_lookup(request, result)
+            }
+        }
+    }
+
+    fn lookup(&self, qname: &str, qtype: QueryType) -> anyhow::Result<DnsPacket> {
+        lookup(
+            self.mode,
+            &self.resolver,
+            &self.cache,
+            &self.metrics,
+            qname,
+            qtype,
+        )
+    }
+
+    // Gets a fresh response into the cache in the background, for a name that was just answered
+    // with a stale one (RFC 8767). Only one refresh of a name is under way at any time, however
+    // many requests for it come in meanwhile.
+    fn refresh(&self, qname: &str, qtype: QueryType) {
+        let (mode, resolver, qname) = (self.mode, self.resolver.clone(), qname.to_string());
+        let (cache, in_flight, metrics) = (
+            Arc::clone(&self.cache),
+            Arc::clone(&self.in_flight),
+            Arc::clone(&self.metrics),
+        );
+
+        thread::spawn(move || {
+            let refreshed = in_flight.lookup_unless_in_flight(&qname, qtype, || {
+                lookup(mode, &resolver, &cache, &metrics, &qname, qtype)
+            });
+            if let Some(Err(error)) = refreshed {
+                warn!("Unable to refresh {} ({:?}): {:?}", qname, qtype, error);
+            }
+        });
+    }
+}
+
+// Successful results are kept in the cache for the next time around, as are names (or types of
+// records) that do not exist
+fn lookup(
+    mode: ResolverMode,
+    resolver: &Resolver,
+    cache: &Cache,
+    metrics: &Arc<Metrics>,
+    qname: &str,
+    qtype: QueryType,
+) -> anyhow::Result<DnsPacket> {
+    let result = match mode {
+        ResolverMode::Recursive => {
+            info!("Starting recursive lookup for {} ({:?})", qname, qtype);
+            resolver.recursive_lookup_metered(qname, qtype, metrics)
+        }
+        ResolverMode::Forward(upstream) => {
+            info!("Forwarding {} ({:?}) to {}", qname, qtype, upstream);
+            metrics.record_upstream_query();
+            forward_lookup(qname, qtype, upstream)
+        }
+    };
+    if let Ok(response) = &result {
+        if response.is_answer() {
+            cache.insert(qname, qtype, response.clone());
+        } else if let Some(ttl) = negative_ttl(response) {
+            cache.insert_negative(qname, qtype, response.clone(), ttl);
+        }
+    }
+
+    result
+}
+
+// Keeps count of the requests being served by `serve_udp`
+#[derive(Debug)]
+struct Workers {
+    busy: Mutex<usize>,
+    finished: Condvar,
+    max: usize,
+}
+
+// One of the requests being served, counted until it is dropped (even when serving it panicked)
+struct Worker(Arc<Workers>);
+
+impl Workers {
+    fn new(max: usize) -> Workers {
+        Workers {
+            busy: Mutex::new(0),
+            finished: Condvar::new(),
+            max: max.max(1),
+        }
+    }
+
+    // Waits for one of the others to finish while there are too many being served
+    fn start(workers: &Arc<Workers>) -> Worker {
+        let mut busy = workers.busy.lock().unwrap();
+        while *busy >= workers.max {
+            busy = workers.finished.wait(busy).unwrap();
+        }
+        *busy += 1;
+
+        Worker(Arc::clone(workers))
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        *self.0.busy.lock().unwrap() -= 1;
+        self.0.finished.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        cache::STALE_TTL,
+        hosts::{hosts_records, parse_hosts},
+        mk_query, read_tcp_message,
+        stub::StubServer,
+        write_packet, write_packet_vec, DnsHeader, MetricsSnapshot, QueryFlags, ResponseCode,
+        MAX_PACKET_SIZE,
+    };
+    use pretty_assertions::assert_eq;
+    use std::{
+        collections::HashMap,
+        net::{Ipv4Addr, TcpListener, TcpStream},
+        time::{Duration, Instant},
+    };
+
+    #[test]
+    fn resolve_answers_from_cache() {
+        let record = DnsRecord::A {
+            domain: "example.com".to_string(),
+            addr: Ipv4Addr::new(1, 2, 3, 4),
+            ttl: 300,
+        };
+        let cached = DnsPacket::builder()
+            .header(DnsHeader::builder().id(1).response(true).answers(1).build())
+            .answers(vec![record])
+            .build();
+        let cache = Cache::new();
+        cache.insert("example.com", QueryType::A, cached.clone());
+        let server = Server::builder().cache(cache).build();
+
+        let response = server
+            .resolve(mk_query(1234, "example.com", QueryType::A))
+            .unwrap();
+
+        assert_eq!(response.header.id, 1234);
+        assert!(!response.header.authoritative_answer);
+        assert_eq!(response.answers, cached.answers);
+    }
+
+    #[test]
+    fn reserved_bit_is_ignored_unless_strict() {
+        let hosts = parse_hosts("192.0.2.10 printer.lan").unwrap();
+        let mut request = mk_query(1234, "printer.lan", QueryType::A);
+        request.header.z = true;
+        let lenient = Server::builder().zone(hosts_records(&hosts)).build();
+        let strict = Server::builder()
+            .zone(hosts_records(&hosts))
+            .reserved_bit(ReservedBitPolicy::Strict)
+            .build();
+
+        let answered = lenient.resolve(request.clone()).unwrap();
+        let rejected = strict.resolve(request).unwrap();
+
+        assert_eq!(answered.rescode(), ResponseCode::NOERROR);
+        assert_eq!(answered.get_random_a(), Some(Ipv4Addr::new(192, 0, 2, 10)));
+        assert!(!answered.header.z);
+        assert_eq!(rejected.header.id, 1234);
+        assert_eq!(rejected.rescode(), ResponseCode::FORMERR);
+        assert!(rejected.answers.is_empty());
+    }
+
+    #[test]
+    fn resolve_answers_pinned_hosts() {
+        let hosts = parse_hosts("192.0.2.10 printer.lan").unwrap();
+        let server = Server::builder().zone(hosts_records(&hosts)).build();
+
+        let response = server
+            .resolve(mk_query(1234, "Printer.lan", QueryType::A))
+            .unwrap();
+
+        assert!(response.header.authoritative_answer);
+        assert_eq!(response.get_random_a(), Some(Ipv4Addr::new(192, 0, 2, 10)));
+    }
+
+    #[test]
+    fn resolve_forwards_in_forward_mode() {
+        let stub = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let forwarder = stub.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let mut buf = vec![0u8; MAX_PACKET_SIZE];
+            let (size, src) = stub.recv_from(&mut buf).unwrap();
+            let request = DnsPacket::parse(&buf[..size]).unwrap();
+            assert!(request.header.recursion_desired);
+
+            let record = DnsRecord::A {
+                domain: request.qname().unwrap(),
+                addr: Ipv4Addr::new(1, 2, 3, 4),
+                ttl: 300,
+            };
+            let header = DnsHeader::builder()
+                .id(request.header.id)
+                .response(true)
+                .questions(1)
+                .answers(1)
+                .build();
+            let response = DnsPacket::builder()
+                .header(header)
+                .questions(request.questions)
+                .answers(vec![record])
+                .build();
+
+            let size = write_packet(&mut buf, &response).unwrap();
+            stub.send_to(&buf[..size], src).unwrap();
+        });
+        let server = Server::builder()
+            .mode(ResolverMode::Forward(forwarder))
+            .build();
+
+        let response = server
+            .resolve(mk_query(1234, "example.com", QueryType::A))
+            .unwrap();
+        handle.join().unwrap();
+
+        assert_eq!(response.header.id, 1234);
+        assert_eq!(response.get_random_a(), Some(Ipv4Addr::new(1, 2, 3, 4)));
+    }
+
+    #[test]
+    fn unparseable_upstream_responses_become_servfail() {
+        let stub = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let forwarder = stub.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let mut buf = vec![0u8; MAX_EDNS_PACKET_SIZE];
+            let (_, src) = stub.recv_from(&mut buf).unwrap();
+            stub.send_to(&[0xde, 0xad, 0xbe, 0xef], src).unwrap();
+        });
+        let server = Arc::new(
+            Server::builder()
+                .mode(ResolverMode::Forward(forwarder))
+                .build(),
+        );
+        let socket = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr = socket.local_addr().unwrap();
+        thread::spawn(move || server.serve_udp(&socket, 1));
+
+        let client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let query = write_packet_vec(&mk_query(4321, "example.com", QueryType::A)).unwrap();
+        client.send_to(&query, addr).unwrap();
+
+        let mut buf = vec![0u8; MAX_EDNS_PACKET_SIZE];
+        let size = client.recv(&mut buf).unwrap();
+        let response = DnsPacket::parse(&buf[..size]).unwrap();
+        handle.join().unwrap();
+
+        assert_eq!(response.header.id, 4321);
+        assert_eq!(response.rescode(), ResponseCode::SERVFAIL);
+        assert_eq!(response.qname(), Some("example.com".to_string()));
+        assert!(response.answers.is_empty());
+    }
+
+    #[test]
+    fn stale_responses_are_served_while_refreshing() {
+        const SLOW: Duration = Duration::from_secs(1);
+
+        let stub = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let forwarder = stub.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let mut buf = vec![0u8; MAX_EDNS_PACKET_SIZE];
+            let (size, src) = stub.recv_from(&mut buf).unwrap();
+            let request = DnsPacket::parse(&buf[..size]).unwrap();
+            thread::sleep(SLOW);
+
+            let mut response = DnsPacket::servfail(&request);
+            response.header.rescode = ResponseCode::NOERROR;
+            response.push_answer(DnsRecord::A {
+                domain: request.qname().unwrap(),
+                addr: Ipv4Addr::new(5, 6, 7, 8),
+                ttl: 300,
+            });
+            let size = write_packet(&mut buf, &response).unwrap();
+            stub.send_to(&buf[..size], src).unwrap();
+        });
+        // Records without any TTL left are stale as soon as they are in the cache
+        let cache = Cache::new().with_max_stale(Duration::from_secs(3600));
+        let mut cached = DnsPacket::query(1, "example.com", QueryType::A);
+        cached.header.response = true;
+        cached.push_answer(DnsRecord::A {
+            domain: "example.com".to_string(),
+            addr: Ipv4Addr::new(1, 2, 3, 4),
+            ttl: 0,
+        });
+        cache.insert("example.com", QueryType::A, cached);
+        let server = Server::builder()
+            .mode(ResolverMode::Forward(forwarder))
+            .cache(cache)
+            .build();
+
+        let start = Instant::now();
+        let first = server
+            .resolve(mk_query(4321, "example.com", QueryType::A))
+            .unwrap();
+        let second = server
+            .resolve(mk_query(4322, "example.com", QueryType::A))
+            .unwrap();
+        let elapsed = start.elapsed();
+        handle.join().unwrap();
+        let mut refreshed = None;
+        while refreshed.is_none() && start.elapsed() < SLOW * 5 {
+            thread::sleep(Duration::from_millis(10));
+            refreshed = server.cache.get("example.com", QueryType::A);
+        }
+
+        assert!(elapsed < SLOW / 2);
+        assert_eq!(first.header.id, 4321);
+        assert_eq!(first.rescode(), ResponseCode::NOERROR);
+        assert_eq!(first.get_random_a(), Some(Ipv4Addr::new(1, 2, 3, 4)));
+        assert_eq!(first.answers[0].ttl(), STALE_TTL);
+        assert_eq!(second.get_random_a(), Some(Ipv4Addr::new(1, 2, 3, 4)));
+        assert_eq!(
+            refreshed.unwrap().get_random_a(),
+            Some(Ipv4Addr::new(5, 6, 7, 8))
+        );
+        // The second request did not set off a refresh of its own
+        assert_eq!(server.metrics().snapshot().upstream_queries, 1);
+    }
+
+    #[test]
+    fn serve_udp_is_not_held_up_by_slow_lookups() {
+        const SLOW: Duration = Duration::from_secs(1);
+
+        let root = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let root_addr = root.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = vec![0u8; MAX_EDNS_PACKET_SIZE];
+            let (size, src) = root.recv_from(&mut buf).unwrap();
+            let request = DnsPacket::parse(&buf[..size]).unwrap();
+            thread::sleep(SLOW);
+
+            let record = DnsRecord::A {
+                domain: request.qname().unwrap(),
+                addr: Ipv4Addr::new(1, 2, 3, 4),
+                ttl: 300,
+            };
+            let header = DnsHeader::builder()
+                .id(request.header.id)
+                .response(true)
+                .questions(1)
+                .answers(1)
+                .build();
+            let response = DnsPacket::builder()
+                .header(header)
+                .questions(request.questions)
+                .answers(vec![record])
+                .build();
+            let size = write_packet(&mut buf, &response).unwrap();
+            root.send_to(&buf[..size], src).unwrap();
+        });
+        let hosts = parse_hosts("192.0.2.10 printer.lan").unwrap();
+        let server = Arc::new(
+            Server::builder()
+                .zone(hosts_records(&hosts))
+                .resolver(Resolver::new(vec![root_addr]))
+                .build(),
+        );
+        let socket = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr = socket.local_addr().unwrap();
+        thread::spawn(move || server.serve_udp(&socket, 4));
+
+        let slow_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let fast_client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let start = Instant::now();
+        let query = write_packet_vec(&mk_query(1, "slow.example.com", QueryType::A)).unwrap();
+        slow_client.send_to(&query, addr).unwrap();
+        for id in 2..5 {
+            let query = write_packet_vec(&mk_query(id, "printer.lan", QueryType::A)).unwrap();
+            fast_client.send_to(&query, addr).unwrap();
+        }
+
+        let mut buf = vec![0u8; MAX_EDNS_PACKET_SIZE];
+        let mut ids = vec![];
+        for _ in 2..5 {
+            let size = fast_client.recv(&mut buf).unwrap();
+            let response = DnsPacket::parse(&buf[..size]).unwrap();
+            assert_eq!(response.get_random_a(), Some(Ipv4Addr::new(192, 0, 2, 10)));
+            ids.push(response.header.id);
+        }
+        assert!(start.elapsed() < SLOW);
+        ids.sort_unstable();
+        assert_eq!(ids, vec![2, 3, 4]);
+
+        let size = slow_client.recv(&mut buf).unwrap();
+        let response = DnsPacket::parse(&buf[..size]).unwrap();
+        assert_eq!(response.header.id, 1);
+        assert_eq!(response.get_random_a(), Some(Ipv4Addr::new(1, 2, 3, 4)));
+        assert!(start.elapsed() >= SLOW);
+    }
+
+    fn read_tcp_response(stream: &mut TcpStream) -> DnsPacket {
+        read_tcp_message(stream).unwrap()
+    }
+
+    #[test]
+    fn serve_tcp_answers_several_queries_per_connection() {
+        // Way more than fits into 512 bytes
+        let hosts = (1..=40)
+            .map(|host| format!("192.0.2.{} printer.lan", host))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let hosts = parse_hosts(&hosts).unwrap();
+        let server = Arc::new(Server::builder().zone(hosts_records(&hosts)).build());
+
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = {
+            let server = Arc::clone(&server);
+            thread::spawn(move || {
+                let (stream, _) = listener.accept().unwrap();
+                server.serve_tcp(stream)
+            })
+        };
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        for id in 1..=2 {
+            let request = write_packet_vec(&mk_query(id, "printer.lan", QueryType::A)).unwrap();
+            let length = (request.len() as u16).to_be_bytes();
+            // Split up so that the server has to put the message back together
+            stream.write_all(&length[..1]).unwrap();
+            stream.flush().unwrap();
+            stream.write_all(&length[1..]).unwrap();
+            stream.write_all(&request[..5]).unwrap();
+            stream.flush().unwrap();
+            stream.write_all(&request[5..]).unwrap();
+
+            let response = read_tcp_response(&mut stream);
+
+            assert_eq!(response.header.id, id);
+            assert!(!response.header.truncated_message);
+            assert_eq!(response.answers.len(), 40);
+        }
+        drop(stream);
+
+        assert!(handle.join().unwrap().is_ok());
+    }
+
+    #[test]
+    fn metrics_are_counted() {
+        let stub = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let forwarder = stub.local_addr().unwrap();
+        // Answers the one name it knows and fails the others
+        let handle = thread::spawn(move || {
+            let mut buf = vec![0u8; MAX_PACKET_SIZE];
+            for _ in 0..3 {
+                let (size, src) = stub.recv_from(&mut buf).unwrap();
+                let request = DnsPacket::parse(&buf[..size]).unwrap();
+                let qname = request.qname().unwrap().to_ascii_lowercase();
+
+                let mut header = DnsHeader::builder()
+                    .id(request.header.id)
+                    .response(true)
+                    .questions(1)
+                    .build();
+                let mut answers = vec![];
+                match qname.as_str() {
+                    "example.com" => answers.push(DnsRecord::A {
+                        domain: qname,
+                        addr: Ipv4Addr::new(1, 2, 3, 4),
+                        ttl: 300,
+                    }),
+                    "missing.example.com" => header.rescode = ResponseCode::NXDOMAIN,
+                    _ => header.rescode = ResponseCode::SERVFAIL,
+                }
+                header.answers = answers.len() as u16;
+                let response = DnsPacket::builder()
+                    .header(header)
+                    .questions(request.questions)
+                    .answers(answers)
+                    .build();
+
+                let size = write_packet(&mut buf, &response).unwrap();
+                stub.send_to(&buf[..size], src).unwrap();
+            }
+        });
+        let hosts = parse_hosts("192.0.2.10 printer.lan").unwrap();
+        let server = Server::builder()
+            .zone(hosts_records(&hosts))
+            .mode(ResolverMode::Forward(forwarder))
+            .build();
+
+        let queries = [
+            "printer.lan",
+            "example.com",
+            "example.com",
+            "missing.example.com",
+            "broken.example.com",
+        ];
+        for (id, qname) in queries.iter().enumerate() {
+            server
+                .resolve(mk_query(id as u16, qname, QueryType::A))
+                .unwrap();
+        }
+        handle.join().unwrap();
+
+        assert_eq!(
+            server.metrics().snapshot(),
+            MetricsSnapshot {
+                queries_total: 5,
+                cache_hits: 1,
+                servfail_total: 1,
+                nxdomain_total: 1,
+                upstream_queries: 3,
+            }
+        );
+    }
+
+    #[test]
+    fn resolve_does_not_recurse_unless_desired() {
+        let zone = crate::zone::parse_zone(
+            "
+lan.               3600 IN NS ns.lan.
+ns.lan.            3600 IN A  192.0.2.53
+",
+        )
+        .unwrap();
+        // Nothing listens here, any attempt to recurse would end in a SERVFAIL
+        let forwarder = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let server = Server::builder()
+            .zone(zone.clone())
+            .mode(ResolverMode::Forward(forwarder.local_addr().unwrap()))
+            .build();
+
+        let mut referred = mk_query(1234, "printer.lan", QueryType::A);
+        referred.header.recursion_desired = false;
+        let mut refused = mk_query(1235, "example.com", QueryType::A);
+        refused.header.recursion_desired = false;
+
+        let referral = server.resolve(referred).unwrap();
+        let refusal = server.resolve(refused).unwrap();
+
+        assert_eq!(referral.rescode(), ResponseCode::NOERROR);
+        assert!(!referral.has_answers());
+        assert_eq!(referral.authorities, vec![zone[0].clone()]);
+        assert_eq!(referral.resources, vec![zone[1].clone()]);
+        assert_eq!(refusal.rescode(), ResponseCode::REFUSED);
+        // RD is copied from the request (RFC 1035 section 4.1.1)
+        assert!(!referral.header.recursion_desired);
+        assert!(!refusal.header.recursion_desired);
+        assert_eq!(server.metrics().snapshot().upstream_queries, 0);
+    }
+
+    #[test]
+    fn nonexistent_names_are_answered_from_cache() {
+        let soa = DnsRecord::SOA {
+            domain: "example.com".to_string(),
+            m_name: "ns.example.com".to_string(),
+            r_name: "hostmaster.example.com".to_string(),
+            serial: 1,
+            refresh: 7200,
+            retry: 3600,
+            expire: 1209600,
+            minimum: 300,
+            ttl: 3600,
+        };
+        let header = DnsHeader::builder()
+            .id(0)
+            .rescode(ResponseCode::NXDOMAIN)
+            .authoritative_entries(1)
+            .build();
+        let nxdomain = DnsPacket::builder()
+            .header(header)
+            .authorities(vec![soa])
+            .build();
+        let mut responses = HashMap::new();
+        responses.insert(("missing.example.com".to_string(), QueryType::A), nxdomain);
+        let stub = StubServer::start(responses);
+        let server = Server::builder()
+            .mode(ResolverMode::Forward(stub.addr()))
+            .build();
+
+        let first = server
+            .resolve(mk_query(1234, "missing.example.com", QueryType::A))
+            .unwrap();
+        let second = server
+            .resolve(mk_query(1235, "missing.example.com", QueryType::A))
+            .unwrap();
+
+        assert_eq!(first.rescode(), ResponseCode::NXDOMAIN);
+        assert_eq!(second.rescode(), ResponseCode::NXDOMAIN);
+        // The SOA is served with no more than the negative TTL left
+        assert_eq!(first.authorities[0].ttl(), 3600);
+        assert_eq!(second.authorities[0].ttl(), 300);
+        assert_eq!(stub.queries(), 1);
+        assert_eq!(server.metrics().snapshot().cache_hits, 1);
+    }
+
+    #[test]
+    fn responses_never_claim_authenticated_data() {
+        let record = DnsRecord::A {
+            domain: "example.com".to_string(),
+            addr: Ipv4Addr::new(1, 2, 3, 4),
+            ttl: 300,
+        };
+        let header = DnsHeader::builder()
+            .id(0)
+            .answers(1)
+            .authed_data(true)
+            .build();
+        let upstream = DnsPacket::builder()
+            .header(header)
+            .answers(vec![record])
+            .build();
+        let mut responses = HashMap::new();
+        responses.insert(("example.com".to_string(), QueryType::A), upstream);
+        let stub = StubServer::start(responses);
+        let hosts = parse_hosts("192.0.2.10 printer.lan").unwrap();
+        let server = Server::builder()
+            .zone(hosts_records(&hosts))
+            .mode(ResolverMode::Forward(stub.addr()))
+            .build();
+        let flags = QueryFlags {
+            checking_disabled: false,
+            authed_data: true,
+        };
+
+        let synthesized = server
+            .resolve(DnsPacket::query_with_flags(
+                1,
+                "printer.lan",
+                QueryType::A,
+                flags,
+            ))
+            .unwrap();
+        let forwarded = server
+            .resolve(DnsPacket::query_with_flags(
+                2,
+                "example.com",
+                QueryType::A,
+                flags,
+            ))
+            .unwrap();
+
+        assert_eq!(
+            synthesized.get_random_a(),
+            Some(Ipv4Addr::new(192, 0, 2, 10))
+        );
+        assert!(!synthesized.header.authed_data);
+        assert_eq!(forwarded.get_random_a(), Some(Ipv4Addr::new(1, 2, 3, 4)));
+        assert!(!forwarded.header.authed_data);
+    }
+}
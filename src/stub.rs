@@ -0,0 +1,137 @@
+use std::{
+    collections::HashMap,
+    net::{SocketAddr, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::{write_packet, DnsHeader, DnsPacket, QueryType, ResponseCode, MAX_EDNS_PACKET_SIZE};
+
+// A name server for tests to point lookups at, answering from a fixed set of responses. The
+// responses are sent back with the id and question of the query they answer, questions it has no
+// response for are REFUSED. It stops serving once dropped.
+pub(crate) struct StubServer {
+    addr: SocketAddr,
+    queries: Arc<AtomicUsize>,
+    running: Arc<AtomicBool>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+impl StubServer {
+    pub(crate) fn start(responses: HashMap<(String, QueryType), DnsPacket>) -> StubServer {
+        let socket = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        // Checks every so often whether it should stop
+        socket
+            .set_read_timeout(Some(Duration::from_millis(20)))
+            .unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        let queries = Arc::new(AtomicUsize::new(0));
+        let running = Arc::new(AtomicBool::new(true));
+        let handle = {
+            let (queries, running) = (Arc::clone(&queries), Arc::clone(&running));
+            thread::spawn(move || serve(socket, responses, &queries, &running))
+        };
+
+        StubServer {
+            addr,
+            queries,
+            running,
+            handle: Some(handle),
+        }
+    }
+
+    pub(crate) fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    // How many queries came in so far
+    pub(crate) fn queries(&self) -> usize {
+        self.queries.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for StubServer {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn serve(
+    socket: UdpSocket,
+    responses: HashMap<(String, QueryType), DnsPacket>,
+    queries: &AtomicUsize,
+    running: &AtomicBool,
+) {
+    let responses = responses
+        .into_iter()
+        .map(|((qname, qtype), response)| ((qname.to_ascii_lowercase(), qtype), response))
+        .collect::<HashMap<_, _>>();
+    let mut buf = vec![0u8; MAX_EDNS_PACKET_SIZE];
+
+    while running.load(Ordering::SeqCst) {
+        let (size, src) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(_) => continue,
+        };
+        queries.fetch_add(1, Ordering::SeqCst);
+        let request = DnsPacket::parse(&buf[..size]).unwrap();
+
+        let key = (
+            request.qname().unwrap().to_ascii_lowercase(),
+            request.qtype().unwrap(),
+        );
+        let mut response = responses.get(&key).cloned().unwrap_or_else(|| {
+            let header = DnsHeader::builder()
+                .id(request.header.id)
+                .rescode(ResponseCode::REFUSED)
+                .build();
+            DnsPacket::builder().header(header).build()
+        });
+        response.header.id = request.header.id;
+        response.header.response = true;
+        response.header.questions = request.questions.len() as u16;
+        response.questions = request.questions;
+
+        let size = write_packet(&mut buf, &response).unwrap();
+        socket.send_to(&buf[..size], src).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{lookup, DnsRecord};
+    use pretty_assertions::assert_eq;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn lookup_gets_canned_response() {
+        let record = DnsRecord::A {
+            domain: "example.com".to_string(),
+            addr: Ipv4Addr::new(1, 2, 3, 4),
+            ttl: 300,
+        };
+        let response = DnsPacket::builder()
+            .header(DnsHeader::builder().id(0).answers(1).build())
+            .answers(vec![record.clone()])
+            .build();
+        let mut responses = HashMap::new();
+        responses.insert(("example.com".to_string(), QueryType::A), response);
+        let stub = StubServer::start(responses);
+
+        let answered = lookup("example.com", QueryType::A, stub.addr()).unwrap();
+        let refused = lookup("example.com", QueryType::AAAA, stub.addr()).unwrap();
+
+        assert_eq!(answered.answers, vec![record]);
+        assert_eq!(refused.rescode(), ResponseCode::REFUSED);
+        assert_eq!(stub.queries(), 2);
+    }
+}
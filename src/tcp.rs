@@ -0,0 +1,199 @@
+use std::{
+    io::{self, Write},
+    net::{SocketAddr, TcpStream},
+};
+
+use log::{debug, warn};
+
+use crate::{
+    mk_upstream_query, read_tcp_message, write_packet_tcp_vec, DnsPacket, QueryType,
+    DEFAULT_EDNS_PAYLOAD_SIZE, LOOKUP_TIMEOUT,
+};
+
+// A TCP connection to a name server that is kept open for any number of queries, sent one after
+// the other (RFC 7766 section 6.2.1). Servers hang up on connections that sat idle for a while, in
+// which case the query is sent again over a new connection.
+#[derive(Debug)]
+pub struct TcpConnection {
+    server: SocketAddr,
+    stream: Option<TcpStream>,
+}
+
+impl TcpConnection {
+    pub fn connect(server: SocketAddr) -> anyhow::Result<TcpConnection> {
+        Ok(TcpConnection {
+            server,
+            stream: Some(open(server)?),
+        })
+    }
+
+    pub fn server(&self) -> SocketAddr {
+        self.server
+    }
+
+    pub fn query(&mut self, qname: &str, qtype: QueryType) -> anyhow::Result<DnsPacket> {
+        let request = mk_upstream_query(rand::random(), qname, qtype, DEFAULT_EDNS_PAYLOAD_SIZE)?;
+        debug!("Request over TCP: {:?}", request);
+        let message = write_packet_tcp_vec(&request)?;
+
+        // A connection that failed us is not used again, the next query gets a new one
+        let reused = self.stream.is_some();
+        let mut stream = match self.stream.take() {
+            Some(stream) => stream,
+            None => open(self.server)?,
+        };
+        let result = match exchange(&mut stream, &message, &request) {
+            Err(error) if reused && is_closed(&error) => {
+                warn!("Connection to {} was closed, reconnecting", self.server);
+                stream = open(self.server)?;
+                exchange(&mut stream, &message, &request)
+            }
+            result => result,
+        };
+        if result.is_ok() {
+            self.stream = Some(stream);
+        }
+
+        result
+    }
+}
+
+fn open(server: SocketAddr) -> anyhow::Result<TcpStream> {
+    let stream = TcpStream::connect_timeout(&server, LOOKUP_TIMEOUT)?;
+    stream.set_read_timeout(Some(LOOKUP_TIMEOUT))?;
+
+    Ok(stream)
+}
+
+// Responses to queries we gave up on earlier may still be on their way, those are skipped
+fn exchange(
+    stream: &mut TcpStream,
+    message: &[u8],
+    request: &DnsPacket,
+) -> anyhow::Result<DnsPacket> {
+    stream.write_all(message)?;
+
+    loop {
+        let response = read_tcp_message(stream)?;
+        if response.header.id != request.header.id || response.qname() != request.qname() {
+            warn!(
+                "Ignoring response with id {} for {:?} (expected {} for {:?})",
+                response.header.id,
+                response.qname(),
+                request.header.id,
+                request.qname()
+            );
+            continue;
+        }
+        debug!("Response over TCP: {:?}", response);
+
+        return Ok(response);
+    }
+}
+
+// Whether the server closed the connection on its end, as opposed to e.g. not responding in time
+fn is_closed(error: &anyhow::Error) -> bool {
+    error.downcast_ref::<io::Error>().is_some_and(|error| {
+        matches!(
+            error.kind(),
+            io::ErrorKind::UnexpectedEof
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::BrokenPipe
+        )
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{DnsHeader, DnsRecord};
+    use pretty_assertions::assert_eq;
+    use std::{
+        net::{Ipv4Addr, TcpListener},
+        thread,
+    };
+
+    fn a_response(request: &DnsPacket, addr: Ipv4Addr) -> DnsPacket {
+        let record = DnsRecord::A {
+            domain: request.qname().unwrap(),
+            addr,
+            ttl: 300,
+        };
+        let header = DnsHeader::builder()
+            .id(request.header.id)
+            .response(true)
+            .questions(1)
+            .answers(1)
+            .build();
+
+        DnsPacket::builder()
+            .header(header)
+            .questions(request.questions.clone())
+            .answers(vec![record])
+            .build()
+    }
+
+    // Accepts a connection for each entry of `queries_per_connection`, answering that many queries
+    // on it before hanging up
+    fn serve(listener: TcpListener, queries_per_connection: Vec<usize>) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            for (host, queries) in queries_per_connection.into_iter().enumerate() {
+                let (mut stream, _) = listener.accept().unwrap();
+                for _ in 0..queries {
+                    let request = read_tcp_message(&mut stream).unwrap();
+                    let response = a_response(&request, Ipv4Addr::new(192, 0, 2, host as u8));
+                    stream
+                        .write_all(&write_packet_tcp_vec(&response).unwrap())
+                        .unwrap();
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn queries_share_the_connection() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = serve(listener, vec![2]);
+
+        let mut connection = TcpConnection::connect(addr).unwrap();
+        let first = connection.query("example.com", QueryType::A).unwrap();
+        let second = connection.query("example.org", QueryType::A).unwrap();
+        handle.join().unwrap();
+
+        assert_eq!(first.qname(), Some("example.com".to_string()));
+        assert_eq!(second.qname(), Some("example.org".to_string()));
+        assert_eq!(first.get_random_a(), Some(Ipv4Addr::new(192, 0, 2, 0)));
+        assert_eq!(second.get_random_a(), Some(Ipv4Addr::new(192, 0, 2, 0)));
+    }
+
+    #[test]
+    fn names_with_a_trailing_dot_get_their_response() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = serve(listener, vec![1]);
+
+        let mut connection = TcpConnection::connect(addr).unwrap();
+        let response = connection.query("example.com.", QueryType::A).unwrap();
+        handle.join().unwrap();
+
+        assert_eq!(response.qname(), Some("example.com".to_string()));
+        assert_eq!(response.get_random_a(), Some(Ipv4Addr::new(192, 0, 2, 0)));
+    }
+
+    #[test]
+    fn closed_connections_are_reopened() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = serve(listener, vec![1, 1]);
+
+        let mut connection = TcpConnection::connect(addr).unwrap();
+        let first = connection.query("example.com", QueryType::A).unwrap();
+        let second = connection.query("example.com", QueryType::A).unwrap();
+        handle.join().unwrap();
+
+        assert_eq!(first.get_random_a(), Some(Ipv4Addr::new(192, 0, 2, 0)));
+        assert_eq!(second.get_random_a(), Some(Ipv4Addr::new(192, 0, 2, 1)));
+    }
+}
@@ -0,0 +1,304 @@
+use std::{fs, path::Path};
+
+use anyhow::Context;
+
+use crate::{DnsRecord, QueryType};
+
+// A (very) simplified take on the master file format described in RFC 1035 section 5. Every
+// non-empty line holds exactly one record with all of its fields spelled out:
+//
+//     <name> <ttl> IN <type> <rdata>
+//
+// e.g.
+//
+//     ; comments start with a semicolon
+//     example.com.      3600  IN  A      93.184.216.34
+//     example.com.      3600  IN  MX     10 mail.example.com.
+//     example.com.      3600  IN  TXT    "v=spf1 -all"
+//
+// Directives ($ORIGIN, $TTL), relative names, `@` and multi-line records are not supported.
+pub fn load_zone<P: AsRef<Path>>(path: P) -> anyhow::Result<Vec<DnsRecord>> {
+    let path = path.as_ref();
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("Unable to read zone file {}", path.display()))?;
+
+    parse_zone(&contents)
+}
+
+pub fn parse_zone(contents: &str) -> anyhow::Result<Vec<DnsRecord>> {
+    contents
+        .lines()
+        .enumerate()
+        .map(|(idx, line)| (idx + 1, strip_comment(line).trim()))
+        .filter(|(_, line)| !line.is_empty())
+        .map(|(line_no, line)| {
+            parse_record(line).with_context(|| format!("Invalid record on line {}", line_no))
+        })
+        .collect()
+}
+
+// Records the zone holds for the given name and type. A CNAME at the name is returned for any
+// type, as it stands in for all the data of the alias. ANY gets every record of the name.
+pub fn find_records(zone: &[DnsRecord], qname: &str, qtype: QueryType) -> Vec<DnsRecord> {
+    zone.iter()
+        .filter(|record| record.domain().eq_ignore_ascii_case(qname))
+        .filter(|record| {
+            qtype == QueryType::ANY
+                || record.query_type() == qtype
+                || record.query_type() == QueryType::CNAME
+        })
+        .cloned()
+        .collect()
+}
+
+// The NS records of the closest zone enclosing `qname` that the zone delegates to, along with
+// whatever addresses of those name servers it knows (glue)
+pub fn find_delegation(zone: &[DnsRecord], qname: &str) -> (Vec<DnsRecord>, Vec<DnsRecord>) {
+    let labels = qname.split('.').collect::<Vec<_>>();
+    for idx in 0..labels.len() {
+        let ns_records = find_records(zone, &labels[idx..].join("."), QueryType::NS)
+            .into_iter()
+            .filter(|record| record.query_type() == QueryType::NS)
+            .collect::<Vec<_>>();
+        if ns_records.is_empty() {
+            continue;
+        }
+
+        let glue = ns_records
+            .iter()
+            .filter_map(|record| match record {
+                DnsRecord::NS { host, .. } => Some(host),
+                _ => None,
+            })
+            .flat_map(|host| {
+                let mut addrs = find_records(zone, host, QueryType::A);
+                addrs.extend(find_records(zone, host, QueryType::AAAA));
+                addrs
+            })
+            .filter(|record| record.query_type() != QueryType::CNAME)
+            .collect();
+
+        return (ns_records, glue);
+    }
+
+    (vec![], vec![])
+}
+
+// The SOA record of the closest zone enclosing `qname`, if the zone has one
+pub fn find_soa(zone: &[DnsRecord], qname: &str) -> Option<DnsRecord> {
+    let labels = qname.split('.').collect::<Vec<_>>();
+    (0..labels.len()).find_map(|idx| {
+        find_records(zone, &labels[idx..].join("."), QueryType::SOA)
+            .into_iter()
+            .find(|record| record.query_type() == QueryType::SOA)
+    })
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut in_quotes = false;
+    for (idx, c) in line.char_indices() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            ';' if !in_quotes => return &line[..idx],
+            _ => {}
+        }
+    }
+
+    line
+}
+
+fn parse_record(line: &str) -> anyhow::Result<DnsRecord> {
+    let mut fields = line.split_whitespace();
+    let mut next = |field: &str| {
+        fields
+            .next()
+            .with_context(|| format!("Missing {} field", field))
+    };
+
+    let domain = normalize_name(next("name")?);
+    let ttl = next("ttl")?.parse::<u32>().context("Invalid ttl")?;
+    let class = next("class")?;
+    if !class.eq_ignore_ascii_case("IN") {
+        anyhow::bail!("Unsupported class {}", class);
+    }
+    let rtype = next("type")?.to_ascii_uppercase();
+
+    let record = match rtype.as_str() {
+        "A" => DnsRecord::A {
+            domain,
+            addr: next("address")?.parse().context("Invalid IPv4 address")?,
+            ttl,
+        },
+        "AAAA" => DnsRecord::AAAA {
+            domain,
+            addr: next("address")?.parse().context("Invalid IPv6 address")?,
+            ttl,
+        },
+        "NS" => DnsRecord::NS {
+            domain,
+            host: normalize_name(next("host")?),
+            ttl,
+        },
+        "CNAME" => DnsRecord::CNAME {
+            domain,
+            host: normalize_name(next("host")?),
+            ttl,
+        },
+        "MX" => DnsRecord::MX {
+            domain,
+            priority: next("priority")?.parse().context("Invalid priority")?,
+            host: normalize_name(next("host")?),
+            ttl,
+        },
+        "TXT" => {
+            // The text may contain whitespace, so it is taken from the line itself
+            let rdata_start = line
+                .find('"')
+                .context("TXT data must be enclosed in quotes")?;
+            DnsRecord::TXT {
+                domain,
+                data: parse_quoted_strings(&line[rdata_start..])?,
+                ttl,
+            }
+        }
+        _ => anyhow::bail!("Unsupported record type {}", rtype),
+    };
+
+    Ok(record)
+}
+
+fn parse_quoted_strings(rdata: &str) -> anyhow::Result<Vec<String>> {
+    let parts = rdata.split('"').collect::<Vec<_>>();
+    if parts.len() % 2 == 0 {
+        anyhow::bail!("Unterminated quote in TXT data");
+    }
+
+    // Every odd part is the inside of a pair of quotes
+    Ok(parts
+        .into_iter()
+        .skip(1)
+        .step_by(2)
+        .map(str::to_string)
+        .collect())
+}
+
+// Names are stored without the trailing dot, the same way the parser hands them to us
+fn normalize_name(name: &str) -> String {
+    name.trim_end_matches('.').to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    const ZONE: &str = r#"
+; example.com zone
+example.com.       3600  IN  A      93.184.216.34
+example.com.       3600  IN  AAAA   2606:2800:220:1:248:1893:25c8:1946
+example.com.       86400 IN  NS     a.iana-servers.net.
+www.example.com.   300   IN  CNAME  example.com.
+example.com.       3600  IN  MX     10 mail.example.com. ; primary mail
+example.com.       3600  IN  TXT    "v=spf1 -all" "a;b"
+"#;
+
+    #[test]
+    fn parse_zone_works() {
+        let expected = vec![
+            DnsRecord::A {
+                domain: "example.com".to_string(),
+                addr: Ipv4Addr::new(93, 184, 216, 34),
+                ttl: 3600,
+            },
+            DnsRecord::AAAA {
+                domain: "example.com".to_string(),
+                addr: "2606:2800:220:1:248:1893:25c8:1946"
+                    .parse::<Ipv6Addr>()
+                    .unwrap(),
+                ttl: 3600,
+            },
+            DnsRecord::NS {
+                domain: "example.com".to_string(),
+                host: "a.iana-servers.net".to_string(),
+                ttl: 86400,
+            },
+            DnsRecord::CNAME {
+                domain: "www.example.com".to_string(),
+                host: "example.com".to_string(),
+                ttl: 300,
+            },
+            DnsRecord::MX {
+                domain: "example.com".to_string(),
+                priority: 10,
+                host: "mail.example.com".to_string(),
+                ttl: 3600,
+            },
+            DnsRecord::TXT {
+                domain: "example.com".to_string(),
+                data: vec!["v=spf1 -all".to_string(), "a;b".to_string()],
+                ttl: 3600,
+            },
+        ];
+
+        let result = parse_zone(ZONE).unwrap();
+
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn parse_zone_reports_line_of_invalid_record() {
+        let error = parse_zone("example.com. 3600 IN A 1.2.3.4\n\nexample.com. 3600 IN A nope")
+            .unwrap_err();
+
+        assert_eq!(error.to_string(), "Invalid record on line 3");
+    }
+
+    #[test]
+    fn load_zone_works() {
+        let path = std::env::temp_dir().join("rdns_load_zone_works.zone");
+        fs::write(&path, ZONE).unwrap();
+
+        let result = load_zone(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(result.len(), 6);
+    }
+
+    #[test]
+    fn find_records_works() {
+        let zone = parse_zone(ZONE).unwrap();
+
+        let mx = find_records(&zone, "EXAMPLE.com", QueryType::MX);
+        let alias = find_records(&zone, "www.example.com", QueryType::A);
+        let missing = find_records(&zone, "example.org", QueryType::A);
+        let any = find_records(&zone, "example.com", QueryType::ANY);
+
+        assert_eq!(mx.len(), 1);
+        assert_eq!(any.len(), 5);
+        assert_eq!(alias[0].query_type(), QueryType::CNAME);
+        assert_eq!(missing, vec![]);
+    }
+
+    #[test]
+    fn find_delegation_works() {
+        let zone = parse_zone(
+            "
+example.com.        3600 IN NS ns1.example.com.
+ns1.example.com.    3600 IN A  192.0.2.53
+dev.example.com.    3600 IN NS ns.dev.example.net.
+",
+        )
+        .unwrap();
+
+        let (ns_records, glue) = find_delegation(&zone, "www.example.com");
+        let (dev_ns_records, dev_glue) = find_delegation(&zone, "Api.DEV.example.com");
+        let (missing_ns_records, _) = find_delegation(&zone, "example.org");
+
+        assert_eq!(ns_records, vec![zone[0].clone()]);
+        assert_eq!(glue, vec![zone[1].clone()]);
+        assert_eq!(dev_ns_records, vec![zone[2].clone()]);
+        assert_eq!(dev_glue, vec![]);
+        assert_eq!(missing_ns_records, vec![]);
+    }
+}